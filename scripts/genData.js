@@ -40,15 +40,17 @@ const connectToAllDbs = async () => {
     port: process.env.REDIS_BUSINESS_INFO_PORT_1,
   });
   const mongo1 = new mongoDriver.MongoClient(
-    `mongodb://${process.env.MONGO_INITDB_ROOT_USERNAME}:${process.env.MONGO_INITDB_ROOT_PASSWORD}@${process.env.MAIN_HOST}:${process.env.MONGO_MAIN_PORT_1}`,
+    `mongodb://${process.env.MONGO_INITDB_ROOT_USERNAME}:${process.env.MONGO_INITDB_ROOT_PASSWORD}@${process.env.MAIN_HOST}:${process.env.MONGO_MAIN_PORT_1}/?directConnection=true`,
   );
   await mongo1.connect();
   const mongoDB = mongo1.db("main").collection("businesses");
+  const counters = mongo1.db("main").collection("counters");
 
   return {
     redisGeo1,
     redisBusiness1,
     mongoDB,
+    counters,
     mongoInstance: mongo1,
   };
 };
@@ -62,6 +64,13 @@ const generateData = async (line, clients, records) => {
   };
   const geoPipe = clients.redisGeo1.multi();
   const redisBusinessPipe = clients.redisBusiness1.multi();
+  // ids come from the counter the api allocates from, a block per city
+  const counter = await clients.counters.findOneAndUpdate(
+    { _id: "businesses" },
+    { $inc: { seq: maxBusinessPerCity } },
+    { upsert: true, returnDocument: "after" },
+  );
+  let nextId = Number(counter.seq) - maxBusinessPerCity + 1;
 
   for (let i = 0; i < maxBusinessPerCity; i++) {
     const { latitude: lat, longitude: lon } = randomGeo(center, 12000);
@@ -75,10 +84,12 @@ const generateData = async (line, clients, records) => {
     const email = faker.en.internet.email();
     const phone = faker.en.phone.number().toString();
     const name = faker.en.company.name();
-    const id = faker.en.seed();
+    const id = nextId++;
+    const now = new Date();
 
     const record = Object.setPrototypeOf(
       {
+        schemaVersion: 1,
         id,
         zipCode,
         name,
@@ -92,13 +103,27 @@ const generateData = async (line, clients, records) => {
         description,
         email,
         phone,
+        createdAt: now,
+        updatedAt: now,
       },
       null,
     );
     geoPipe.call("GEOADD", "world", record.lon, record.lat, record.id);
+    geoPipe.call(
+      "HSET",
+      `suggest:${record.id}`,
+      "name",
+      name,
+      "type",
+      record.type,
+      "location",
+      `${lon},${lat}`,
+    );
     redisBusinessPipe.call(
       "HSET",
       record.id,
+      "schemaVersion",
+      record.schemaVersion,
       "zipCode",
       zipCode,
       "name",
@@ -0,0 +1,20 @@
+use crate::{
+    config::ServerConfig, import, migrate_ids, rebalance, reconcile, response::Result, sync,
+};
+
+// Maintenance commands, run as `api <command> [args]` next to a running deployment with the
+// same environment
+pub async fn run(config: &ServerConfig, command: &str, args: &[String]) -> Result<()> {
+    match command {
+        "rebalance-geo" => rebalance::run(config, args).await,
+        "migrate-ids" => migrate_ids::run(config).await,
+        "sync" => sync::run(config).await,
+        "reconcile" => reconcile::run(config, args).await,
+        "import" => import::run(config, args).await,
+        _ => Err(format!(
+            "Unknown command: {}. Available: rebalance-geo, migrate-ids, sync, reconcile, import",
+            command
+        )
+        .into()),
+    }
+}
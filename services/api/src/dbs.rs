@@ -1,30 +1,73 @@
+use crate::listing::{self, ListQuery};
+use crate::outbox::{self, OutboxEvent, SharedOutboxStats};
 use crate::{config::ServerConfig, response::Result};
-use bson::doc;
-use mongodb::{Client, Collection};
+use bson::{doc, Bson, Document};
+use model::business::{BusinessData, DELETED_AT_FIELD, SCHEMA_VERSION};
+use model::events::BUSINESS_CHANGED_CHANNEL;
+use model::redis_connection::RedisConnection;
+use model::search_cache;
+use model::shards::{ShardMap, SHARD_LIST_KEY, UNSHARDED_KEY};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{
+    FindOneAndUpdateOptions, FindOneOptions, FindOptions, IndexOptions, ReturnDocument,
+    UpdateModifications, UpdateOptions,
+};
+use mongodb::{Client, ClientSession, Collection, IndexModel};
 use redis::AsyncCommands;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
+// Cloning shares the connections, e.g. with the outbox worker
+#[derive(Clone)]
 pub struct DBConnections {
     pub mongo: MongoDb,
     pub redis_business: RedisBusiness,
     pub redis_geo: RedisGeo,
+    pub soft_delete: bool,
+    pub outbox_stats: SharedOutboxStats,
 }
 
 impl DBConnections {
     pub async fn init(config: &ServerConfig) -> Result<DBConnections> {
         let mongo = MongoDb::connect(&config.mongo).await?;
-        let redis_business = RedisBusiness::connect(&config.redis_business).await?;
-        let redis_geo = RedisGeo::connect(&config.redis_geo).await?;
+        mongo.ensure_geo_index().await?;
+        mongo.ensure_id_counter().await?;
+        mongo.ensure_listing_indexes().await?;
+        outbox::ensure_index(&mongo).await?;
+        if let Err(e) = mongo.ensure_unique_ids().await {
+            println!(
+                "Could not add the unique index on business ids, `api migrate-ids` fixes duplicated ones: {:?}",
+                e
+            );
+        }
+        let redis_business =
+            RedisBusiness::connect(&config.redis_business, config.redis_business_cluster).await?;
+        let redis_geo = RedisGeo::connect(
+            &config.redis_geo,
+            &config.geo_shard_nodes,
+            config.geo_shard_precision,
+        )
+        .await?;
 
         Ok(DBConnections {
             mongo,
             redis_business,
             redis_geo,
+            soft_delete: config.soft_delete,
+            outbox_stats: SharedOutboxStats::default(),
         })
     }
 }
 
+// Business ids come from a counter document that every insert increments, rather than from the
+// number of documents, so concurrent inserts and deletes can't hand out the same id twice
+const COUNTERS_COLLECTION: &str = "counters";
+const BUSINESS_COUNTER: &str = "businesses";
+const DUPLICATE_KEY: i32 = 11000;
+
+// Set by the api on create and on every update, the business listing filters and sorts by them
+pub const CREATED_AT_FIELD: &str = "createdAt";
+pub const UPDATED_AT_FIELD: &str = "updatedAt";
+
 #[derive(Clone)]
 pub struct MongoDb {
     client: Client,
@@ -40,209 +83,527 @@ impl MongoDb {
     pub fn get_businesses_collection(&self) -> Collection<BusinessData> {
         self.client.database("main").collection("businesses")
     }
+    pub fn get_outbox_collection(&self) -> Collection<OutboxEvent> {
+        self.client
+            .database("main")
+            .collection(outbox::OUTBOX_COLLECTION)
+    }
+    // Transactions need Mongo to run as a replica set, a single node one will do
+    pub async fn start_transaction(&self) -> Result<ClientSession> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+        Ok(session)
+    }
+    // lbs searches Mongo by `location` when its geo Redis is down. Documents written before the
+    // field existed get it from their lon/lat here
+    pub async fn ensure_geo_index(&self) -> Result<()> {
+        let businesses = self.get_businesses_collection();
+        let index = IndexModel::builder()
+            .keys(doc! {"location": "2dsphere"})
+            .build();
+        businesses.create_index(index, None).await?;
+        let backfill = vec![doc! {"$set": {
+            "location": {"type": "Point", "coordinates": ["$lon", "$lat"]}
+        }}];
+        let result = businesses
+            .update_many(
+                doc! {"location": {"$exists": false}},
+                UpdateModifications::Pipeline(backfill),
+                None,
+            )
+            .await?;
+        if result.modified_count > 0 {
+            println!("Added location to {} businesses", result.modified_count);
+        }
+        Ok(())
+    }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct BusinessData {
-    pub id: Option<u64>,
-    pub zip_code: String,
-    pub name: String,
-    pub stars: u8,
-    pub r#type: String,
-    pub lat: f32,
-    pub lon: f32,
-    pub opens_at: u8,
-    pub closes_at: u8,
-    pub average_price: u8,
-    pub description: String,
-    pub email: String,
-    pub phone: String,
-}
-
-impl BusinessData {
-    pub fn default() -> BusinessData {
-        BusinessData {
-            id: None,
-            zip_code: "0".to_string(),
-            name: "null".to_string(),
-            stars: 0,
-            r#type: "null".to_string(),
-            lat: 0.0,
-            lon: 0.0,
-            opens_at: 0,
-            closes_at: 0,
-            average_price: 0,
-            email: "".to_string(),
-            description: "".to_string(),
-            phone: "".to_string(),
+impl MongoDb {
+    // One index per sortable field of the listing, with id for the tie break and the cursor.
+    // Businesses written before the dates existed get the time of their ObjectId for both
+    pub async fn ensure_listing_indexes(&self) -> Result<()> {
+        let businesses = self.get_businesses_collection();
+        // id has its unique index already
+        let fields = listing::SORT_FIELDS.iter().filter(|field| **field != "id");
+        for field in fields.chain(["zipCode"].iter()) {
+            let mut keys = Document::new();
+            keys.insert(*field, 1);
+            keys.insert("id", 1);
+            let index = IndexModel::builder().keys(keys).build();
+            businesses.create_index(index, None).await?;
+        }
+        let mut missing_created = Document::new();
+        missing_created.insert(CREATED_AT_FIELD, doc! {"$exists": false});
+        let mut missing_updated = Document::new();
+        missing_updated.insert(UPDATED_AT_FIELD, doc! {"$exists": false});
+        let mut dates = Document::new();
+        dates.insert(
+            CREATED_AT_FIELD,
+            doc! {"$ifNull": [format!("${}", CREATED_AT_FIELD), {"$toDate": "$_id"}]},
+        );
+        dates.insert(
+            UPDATED_AT_FIELD,
+            doc! {"$ifNull": [format!("${}", UPDATED_AT_FIELD), {"$toDate": "$_id"}]},
+        );
+        let result = businesses
+            .update_many(
+                doc! {"$or": [missing_created, missing_updated]},
+                UpdateModifications::Pipeline(vec![doc! {"$set": dates}]),
+                None,
+            )
+            .await?;
+        if result.modified_count > 0 {
+            println!("Added dates to {} businesses", result.modified_count);
         }
+        Ok(())
     }
-    async fn get_business_by_id_mongo(mongo: &MongoDb, id: u32) -> Result<Option<BusinessData>> {
-        let businesses = mongo.get_businesses_collection();
-        let business = businesses.find_one(Some(doc! { "id":  id }), None).await?;
-        Ok(business)
-    }
-    async fn create_business_mongo(mongo: &MongoDb, data: &mut BusinessData) -> Result<u64> {
-        let businesses = mongo.get_businesses_collection();
-        let count = businesses.count_documents(None, None).await?;
-        // We simulate auto incrementing id instead of Mongo ObjectId
-        // It's used for simplicity and debugging purposes, it's not a good idea to use it in prod
-        let new_id = count + 1;
-        data.id = Some(new_id);
-
-        businesses.insert_one(data, None).await?;
-        Ok(new_id)
-    }
-    async fn update_business_by_id_mongo(
-        mongo: &MongoDb,
-        id: u32,
-        data: &BusinessData,
-    ) -> Result<()> {
-        let businesses = mongo.get_businesses_collection();
-        let filter = doc! {"id": id};
-        let updater = doc! {"$set": bson::to_bson(&data).unwrap().as_document().unwrap() };
-        businesses
-            .find_one_and_update(filter, updater, None)
+    fn get_counters_collection(&self) -> Collection<Document> {
+        self.client.database("main").collection(COUNTERS_COLLECTION)
+    }
+    // Moves the counter past the highest id in the collection, for data written before it
+    // existed or by the seeding script. Never moves it back
+    pub async fn ensure_id_counter(&self) -> Result<()> {
+        let options = FindOneOptions::builder()
+            .sort(doc! {"id": -1})
+            .projection(doc! {"id": 1})
+            .build();
+        let highest = self
+            .get_businesses_collection()
+            .clone_with_type::<Document>()
+            .find_one(None, options)
+            .await?;
+        let max_id = highest.and_then(|document| id_of(&document)).unwrap_or(0);
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.get_counters_collection()
+            .update_one(
+                doc! {"_id": BUSINESS_COUNTER},
+                doc! {"$max": {"seq": max_id}},
+                options,
+            )
             .await?;
         Ok(())
     }
-    pub async fn update_business_by_id(
-        dbs: &mut DBConnections,
-        id: u32,
-        mut data: BusinessData,
-    ) -> Result<()> {
-        BusinessData::update_business_by_id_mongo(&dbs.mongo, id, &data).await?;
-        data.id = Some(id as u64);
-        BusinessData::cache_business_data(&mut dbs.redis_business, &data).await?;
+    pub fn get_sync_state_collection(&self) -> Collection<Document> {
+        self.client.database("main").collection("sync_state")
+    }
+    // Change events of deleted documents only say which business it was with a pre-image.
+    // Needs Mongo 6 or later
+    pub async fn enable_change_pre_images(&self) -> Result<()> {
+        let command = doc! {
+            "collMod": "businesses",
+            "changeStreamPreAndPostImages": {"enabled": true},
+        };
+        self.client
+            .database("main")
+            .run_command(command, None)
+            .await?;
         Ok(())
     }
-
-    pub async fn create_business(dbs: &mut DBConnections, mut data: BusinessData) -> Result<u64> {
-        let inserted_id = BusinessData::create_business_mongo(&dbs.mongo, &mut data).await?;
-        BusinessData::cache_business_data(&mut dbs.redis_business, &data).await?;
-        dbs.redis_geo
-            .connection
-            .geo_add("world", (data.lon, data.lat, inserted_id))
+    // Fails while ids are duplicated
+    pub async fn ensure_unique_ids(&self) -> Result<()> {
+        let index = IndexModel::builder()
+            .keys(doc! {"id": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        self.get_businesses_collection()
+            .create_index(index, None)
+            .await?;
+        Ok(())
+    }
+    pub async fn next_business_id(&self) -> Result<u64> {
+        self.reserve_business_ids(1).await
+    }
+    // Moves the counter by `count` at once and returns the first id of the block, the rest
+    // follow it
+    pub async fn reserve_business_ids(&self, count: u64) -> Result<u64> {
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let counter = self
+            .get_counters_collection()
+            .find_one_and_update(
+                doc! {"_id": BUSINESS_COUNTER},
+                doc! {"$inc": {"seq": count as i64}},
+                options,
+            )
             .await?;
-        Ok(inserted_id)
+        match counter
+            .as_ref()
+            .and_then(|counter| number(counter.get("seq")?))
+        {
+            Some(last) => Ok(last as u64 + 1 - count),
+            None => Err("Business id counter has no seq".into()),
+        }
     }
+}
 
-    pub async fn get_business_by_id(
-        dbs: &mut DBConnections,
-        id: u32,
-    ) -> Result<Option<BusinessData>> {
-        let cached = BusinessData::get_business_by_id_redis(&mut dbs.redis_business, id).await?;
+// The api writes ids as 64 bit ints, the seeding script leaves them to the driver
+pub fn id_of(document: &Document) -> Option<i64> {
+    number(document.get("id")?).map(|id| id as i64)
+}
 
-        if cached.is_some() {
-            return Ok(cached);
-        }
-        let from_mongo = BusinessData::get_business_by_id_mongo(&dbs.mongo, id).await?;
+// Position of a business as stored, lon before lat. Numbers written by hand or by other
+// drivers can be ints
+pub fn position_of(document: &Document) -> Option<[f64; 2]> {
+    Some([number(document.get("lon")?)?, number(document.get("lat")?)?])
+}
 
-        if from_mongo.is_some() {
-            BusinessData::cache_business_data(
-                &mut dbs.redis_business,
-                &from_mongo.clone().unwrap(),
-            )
-            .await?;
+fn number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Double(n) => Some(*n),
+        _ => None,
+    }
+}
+
+fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == DUPLICATE_KEY
+    )
+}
+
+// GeoJSON point of the business, what the 2dsphere index is built on
+fn location_of(data: &BusinessData) -> Document {
+    doc! {"type": "Point", "coordinates": [data.lon as f64, data.lat as f64]}
+}
+
+// The business with this id, unless it is soft deleted
+fn live_business(id: u32) -> Document {
+    let mut filter = doc! {"id": id};
+    filter.insert(DELETED_AT_FIELD, doc! {"$exists": false});
+    filter
+}
+
+pub async fn get_business_by_id_mongo(mongo: &MongoDb, id: u32) -> Result<Option<BusinessData>> {
+    let businesses = mongo.get_businesses_collection();
+    let business = businesses.find_one(live_business(id), None).await?;
+    Ok(business)
+}
+// Written records always carry the current schema version and hours with a timezone, so that
+// "open now" does not depend on who asks
+fn prepare_for_write(data: &mut BusinessData) {
+    data.schema_version = SCHEMA_VERSION;
+    data.hours = Some(data.opening_hours());
+}
+
+// Writes go to Mongo in one transaction with an outbox event, which is then applied to Redis
+// straight away. When that fails the write still stands, the outbox worker retries the event
+
+// None when the allocated id turned out to be taken, by a document written without going
+// through the counter. The counter is moved past it, so trying again gets a free one
+pub async fn create_business(
+    dbs: &mut DBConnections,
+    mut data: BusinessData,
+) -> Result<Option<u64>> {
+    prepare_for_write(&mut data);
+    let new_id = dbs.mongo.next_business_id().await?;
+    data.id = Some(new_id);
+    let mut document = bson::to_document(&data)?;
+    document.insert("location", location_of(&data));
+    let now = bson::DateTime::now();
+    document.insert(CREATED_AT_FIELD, now);
+    document.insert(UPDATED_AT_FIELD, now);
+
+    let mut session = dbs.mongo.start_transaction().await?;
+    let inserted = dbs
+        .mongo
+        .get_businesses_collection()
+        .clone_with_type::<Document>()
+        .insert_one_with_session(document, None, &mut session)
+        .await;
+    match inserted {
+        Ok(_) => {}
+        Err(e) if is_duplicate_key(&e) => {
+            // the failed write has already aborted the transaction on the server
+            let _ = session.abort_transaction().await;
+            dbs.mongo.ensure_id_counter().await?;
+            return Ok(None);
         }
+        Err(e) => return Err(e.into()),
+    }
+    let event = OutboxEvent::new(new_id, None);
+    outbox::record(&dbs.mongo, &event, &mut session).await?;
+    session.commit_transaction().await?;
+
+    outbox::apply_now(dbs, &event).await;
+    Ok(Some(new_id))
+}
 
-        Ok(from_mongo)
+// Like create_business for a whole batch, in one transaction. Redis is written with one pipeline
+// per store rather than business by business, and the events are only left to the outbox worker
+// when that fails
+pub async fn create_businesses(
+    dbs: &mut DBConnections,
+    mut batch: Vec<BusinessData>,
+) -> Result<Option<Vec<u64>>> {
+    if batch.is_empty() {
+        return Ok(Some(vec![]));
+    }
+    let first_id = dbs.mongo.reserve_business_ids(batch.len() as u64).await?;
+    let now = bson::DateTime::now();
+    let mut documents = vec![];
+    let mut events = vec![];
+    for (i, data) in batch.iter_mut().enumerate() {
+        prepare_for_write(data);
+        let id = first_id + i as u64;
+        data.id = Some(id);
+        let mut document = bson::to_document(data)?;
+        document.insert("location", location_of(data));
+        document.insert(CREATED_AT_FIELD, now);
+        document.insert(UPDATED_AT_FIELD, now);
+        documents.push(document);
+        events.push(OutboxEvent::new(id, None));
     }
 
-    async fn cache_business_data(redis: &mut RedisBusiness, data: &BusinessData) -> Result<()> {
-        let id = data.id;
-        if id.is_none() {
-            return Err("Failed to cache, id does not exist".into());
+    let mut session = dbs.mongo.start_transaction().await?;
+    let inserted = dbs
+        .mongo
+        .get_businesses_collection()
+        .clone_with_type::<Document>()
+        .insert_many_with_session(documents, None, &mut session)
+        .await;
+    match inserted {
+        Ok(_) => {}
+        Err(e) if is_duplicate_key(&e) => {
+            let _ = session.abort_transaction().await;
+            dbs.mongo.ensure_id_counter().await?;
+            return Ok(None);
         }
-        redis
-            .set_hash(
-                id.unwrap().to_string().as_str(),
-                data.clone().into_hashmap()?,
+        Err(e) => return Err(e.into()),
+    }
+    outbox::record_many(&dbs.mongo, &events, &mut session).await?;
+    session.commit_transaction().await?;
+
+    let ids = batch.iter().filter_map(|data| data.id).collect();
+    match write_batch_to_redis(dbs, &batch).await {
+        Ok(()) => outbox::complete(dbs, &events).await,
+        Err(e) => println!(
+            "Failed to sync {} imported businesses to Redis, the outbox worker will retry: {}",
+            batch.len(),
+            e
+        ),
+    }
+    Ok(Some(ids))
+}
+
+// false when there is no such business
+pub async fn update_business_by_id(
+    dbs: &mut DBConnections,
+    id: u32,
+    mut data: BusinessData,
+) -> Result<bool> {
+    prepare_for_write(&mut data);
+    data.id = Some(id as u64);
+    let mut fields = bson::to_document(&data)?;
+    fields.insert("location", location_of(&data));
+    fields.insert(UPDATED_AT_FIELD, bson::DateTime::now());
+
+    let mut session = dbs.mongo.start_transaction().await?;
+    let previous = dbs
+        .mongo
+        .get_businesses_collection()
+        .find_one_and_update_with_session(
+            live_business(id),
+            doc! {"$set": fields},
+            None,
+            &mut session,
+        )
+        .await?;
+    let Some(previous) = previous else {
+        session.abort_transaction().await?;
+        return Ok(false);
+    };
+    let event = OutboxEvent::new(id as u64, Some(&previous));
+    outbox::record(&dbs.mongo, &event, &mut session).await?;
+    session.commit_transaction().await?;
+
+    outbox::apply_now(dbs, &event).await;
+    Ok(true)
+}
+
+// Removes the business from Mongo, or only marks it deleted there with `soft`, and from every
+// Redis store. false when there is no such business. A hard delete also clears a tombstone
+pub async fn delete_business_by_id(dbs: &mut DBConnections, id: u32, soft: bool) -> Result<bool> {
+    let businesses = dbs.mongo.get_businesses_collection();
+    let mut session = dbs.mongo.start_transaction().await?;
+    let deleted = if soft {
+        let mut tombstone = Document::new();
+        tombstone.insert(DELETED_AT_FIELD, bson::DateTime::now());
+        businesses
+            .find_one_and_update_with_session(
+                live_business(id),
+                doc! {"$set": tombstone},
+                None,
+                &mut session,
             )
-            .await?;
-        Ok(())
+            .await?
+    } else {
+        businesses
+            .find_one_and_delete_with_session(doc! {"id": id}, None, &mut session)
+            .await?
+    };
+    let Some(deleted) = deleted else {
+        session.abort_transaction().await?;
+        return Ok(false);
+    };
+    let event = OutboxEvent::new(id as u64, Some(&deleted));
+    outbox::record(&dbs.mongo, &event, &mut session).await?;
+    session.commit_transaction().await?;
+
+    outbox::apply_now(dbs, &event).await;
+    Ok(true)
+}
+
+// Brings a soft deleted business back everywhere. false when there is no tombstone to restore
+pub async fn restore_business_by_id(dbs: &mut DBConnections, id: u32) -> Result<bool> {
+    let mut filter = doc! {"id": id};
+    filter.insert(DELETED_AT_FIELD, doc! {"$exists": true});
+    let mut unset = Document::new();
+    unset.insert(DELETED_AT_FIELD, "");
+
+    let mut session = dbs.mongo.start_transaction().await?;
+    let restored = dbs
+        .mongo
+        .get_businesses_collection()
+        .find_one_and_update_with_session(filter, doc! {"$unset": unset}, None, &mut session)
+        .await?;
+    if restored.is_none() {
+        session.abort_transaction().await?;
+        return Ok(false);
     }
-    async fn get_business_by_id_redis(
-        redis: &mut RedisBusiness,
-        id: u32,
-    ) -> Result<Option<BusinessData>> {
-        let data = redis.get_hash_by_id(id).await?;
-        match BusinessData::from_hashmap(data) {
-            Ok(mut constructed) => {
-                constructed.id = Some(id as u64);
-                Ok(Some(constructed))
-            }
-            Err(e) => {
-                println!("{:?}", e);
-                Ok(None)
-            }
-        }
+    let event = OutboxEvent::new(id as u64, None);
+    outbox::record(&dbs.mongo, &event, &mut session).await?;
+    session.commit_transaction().await?;
+
+    outbox::apply_now(dbs, &event).await;
+    Ok(true)
+}
+
+// A page of the business listing, one more than the limit to tell whether another page follows
+pub async fn list_businesses(mongo: &MongoDb, query: &ListQuery) -> Result<Vec<Document>> {
+    let options = FindOptions::builder()
+        .sort(query.sort())
+        .limit((query.limit + 1) as i64)
+        .projection(doc! {"_id": 0, "location": 0})
+        .build();
+    let mut cursor = mongo
+        .get_businesses_collection()
+        .clone_with_type::<Document>()
+        .find(query.filter(), options)
+        .await?;
+    let mut businesses = vec![];
+    while cursor.advance().await? {
+        businesses.push(cursor.deserialize_current()?);
     }
+    Ok(businesses)
+}
 
-    pub fn from_value(value: &serde_json::Value) -> serde_json::Result<BusinessData> {
-        match serde_json::from_value(value.clone()) {
-            Ok(res) => Ok(res),
-            Err(e) => {
-                println!("{:?}", e);
-                Err(e)
-            }
-        }
+// Puts the business in every Redis store as it is in Mongo
+pub async fn write_to_redis(dbs: &mut DBConnections, data: &BusinessData) -> Result<()> {
+    let id = match data.id {
+        Some(id) => id,
+        None => return Err("Failed to write to Redis, id does not exist".into()),
+    };
+    cache_business_data(&mut dbs.redis_business, data).await?;
+    dbs.redis_geo
+        .move_position(id, data.lon as f64, data.lat as f64)
+        .await?;
+    dbs.redis_geo.index_suggestion(data).await?;
+    dbs.redis_business
+        .invalidate_search_cache(data.lon as f64, data.lat as f64)
+        .await?;
+    dbs.redis_business.publish_change(id).await?;
+    Ok(())
+}
+
+// write_to_redis for new businesses, which have no old position to clear
+async fn write_batch_to_redis(dbs: &mut DBConnections, batch: &[BusinessData]) -> Result<()> {
+    let mut hashes = vec![];
+    let mut positions = vec![];
+    for data in batch {
+        let id = data
+            .id
+            .ok_or("Failed to write to Redis, id does not exist")?;
+        hashes.push((id, data.to_hash()?));
+        positions.push((id, data.lon as f64, data.lat as f64));
     }
+    dbs.redis_business.set_hashes(&hashes).await?;
+    dbs.redis_geo.add_positions(&positions).await?;
+    dbs.redis_geo.index_suggestions(batch).await?;
+    let points: Vec<(f64, f64)> = positions.iter().map(|(_, lon, lat)| (*lon, *lat)).collect();
+    dbs.redis_business.invalidate_search_caches(&points).await?;
+    let ids: Vec<u64> = hashes.iter().map(|(id, _)| *id).collect();
+    dbs.redis_business.publish_changes(&ids).await?;
+    Ok(())
+}
 
-    pub fn from_hashmap(map: HashMap<String, String>) -> Result<BusinessData> {
-        if map.is_empty() {
-            return Err("Hashmap cannot be empty".into());
-        }
-        let mut data = BusinessData::default();
-
-        for (key, value) in map.iter() {
-            let value = value.to_owned();
-            match key.as_str() {
-                "name" => data.name = value,
-                "lon" => data.lon = value.parse::<f32>()?,
-                "lat" => data.lat = value.parse::<f32>()?,
-                "stars" => data.stars = value.parse::<u8>()?,
-                "zipCode" => data.zip_code = value,
-                "type" => data.r#type = value,
-                "averagePrice" => data.average_price = value.parse::<u8>()?,
-                "opensAt" => data.opens_at = value.parse::<u8>()?,
-                "closesAt" => data.closes_at = value.parse::<u8>()?,
-                "email" => data.email = value,
-                "phone" => data.phone = value,
-                _ => continue,
-            }
-        }
+// Takes a deleted business out of every Redis store. Searches around where it was are left to
+// the caller, which knows the position
+pub async fn remove_from_redis(dbs: &mut DBConnections, id: u64) -> Result<()> {
+    dbs.redis_business.delete_hash(id).await?;
+    dbs.redis_geo.remove_positions(id, None).await?;
+    dbs.redis_geo.remove_suggestion(id).await?;
+    dbs.redis_business.publish_change(id).await?;
+    Ok(())
+}
 
-        Ok(data)
+pub async fn get_business_by_id(dbs: &mut DBConnections, id: u32) -> Result<Option<BusinessData>> {
+    let cached = get_business_by_id_redis(&mut dbs.redis_business, id).await?;
+
+    if cached.is_some() {
+        return Ok(cached);
     }
+    let from_mongo = get_business_by_id_mongo(&dbs.mongo, id).await?;
 
-    pub fn into_hashmap(self) -> Result<HashMap<String, String>> {
-        let value = serde_json::to_value(self)?;
-        let mut map: HashMap<String, String> = HashMap::default();
-        for (k, v) in value.as_object().unwrap().into_iter() {
-            if v.is_f64() {
-                map.insert(k.to_owned(), v.as_f64().unwrap().to_string());
-            } else if v.is_u64() {
-                map.insert(k.to_owned(), v.as_u64().unwrap().to_string());
-            } else if v.is_string() {
-                map.insert(k.to_owned(), v.as_str().unwrap().to_string());
-            } else {
-                continue;
-            }
+    if from_mongo.is_some() {
+        cache_business_data(&mut dbs.redis_business, &from_mongo.clone().unwrap()).await?;
+    }
+
+    Ok(from_mongo)
+}
+
+async fn cache_business_data(redis: &mut RedisBusiness, data: &BusinessData) -> Result<()> {
+    let id = data.id;
+    if id.is_none() {
+        return Err("Failed to cache, id does not exist".into());
+    }
+    let hash = data.to_hash()?;
+    redis
+        .set_hash(id.unwrap().to_string().as_str(), hash)
+        .await?;
+    Ok(())
+}
+async fn get_business_by_id_redis(
+    redis: &mut RedisBusiness,
+    id: u32,
+) -> Result<Option<BusinessData>> {
+    let data = redis.get_hash_by_id(id).await?;
+    match BusinessData::from_hash(id as u64, data) {
+        Ok(constructed) => Ok(Some(constructed)),
+        Err(e) => {
+            println!("{:?}", e);
+            Ok(None)
         }
-        Ok(map)
     }
 }
 
+#[derive(Clone)]
 pub struct RedisBusiness {
-    connection: redis::aio::MultiplexedConnection,
+    connection: RedisConnection,
 }
 
 impl RedisBusiness {
-    pub async fn connect(conn_str: &str) -> Result<RedisBusiness> {
-        let client = redis::Client::open(conn_str).unwrap();
-        let connection = client.get_multiplexed_async_connection().await.unwrap();
+    pub async fn connect(conn_str: &str, cluster: bool) -> Result<RedisBusiness> {
+        let connection = if cluster {
+            let seeds: Vec<String> = conn_str.split(',').map(|s| s.trim().to_string()).collect();
+            RedisConnection::connect_cluster(&seeds).await?
+        } else {
+            RedisConnection::connect(conn_str).await?
+        };
 
         Ok(RedisBusiness { connection })
     }
@@ -252,30 +613,209 @@ impl RedisBusiness {
         Ok(data)
     }
 
-    pub async fn set_hash(&mut self, key: &str, map: HashMap<String, String>) -> Result<()> {
-        let mut values: Vec<(&str, &str)> = Vec::with_capacity(map.len());
+    // One HGETALL per id, in a single pipeline. Missing hashes come back empty
+    pub async fn get_hashes(&mut self, ids: &[u64]) -> Result<Vec<HashMap<String, String>>> {
+        let mut pipe = redis::pipe();
+        for id in ids {
+            pipe.hgetall(id);
+        }
+        Ok(pipe.query_async(&mut self.connection).await?)
+    }
 
-        for (key, value) in map.iter() {
-            if key == "id" {
-                continue;
+    // Every key that looks like a business id. None in cluster mode, where SCAN only sees the
+    // node it is sent to
+    pub async fn business_ids(&mut self) -> Result<Option<Vec<u64>>> {
+        let RedisConnection::Single(connection) = &mut self.connection else {
+            return Ok(None);
+        };
+        let mut ids = vec![];
+        let mut keys = connection.scan_match::<_, String>("[0-9]*").await?;
+        while let Some(key) = keys.next_item().await {
+            if let Ok(id) = key.parse() {
+                ids.push(id);
             }
-            values.push((key.as_str(), value.as_str()));
         }
+        Ok(Some(ids))
+    }
+
+    pub async fn delete_hash(&mut self, id: u64) -> Result<()> {
+        let _: () = self.connection.del(id).await?;
+        Ok(())
+    }
+
+    pub async fn set_hash(&mut self, key: &str, values: Vec<(String, String)>) -> Result<()> {
+        let _: () = self.connection.hset_multiple(key, &values).await?;
+        Ok(())
+    }
+
+    pub async fn set_hashes(&mut self, hashes: &[(u64, Vec<(String, String)>)]) -> Result<()> {
+        let mut pipe = redis::pipe();
+        for (id, values) in hashes {
+            pipe.hset_multiple(id, values).ignore();
+        }
+        let _: () = pipe.query_async(&mut self.connection).await?;
+        Ok(())
+    }
+
+    // Lets lbs instances drop their in-process copy of the business
+    pub async fn publish_change(&mut self, id: u64) -> Result<()> {
+        let _: () = self
+            .connection
+            .publish(BUSINESS_CHANGED_CHANNEL, id)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn publish_changes(&mut self, ids: &[u64]) -> Result<()> {
+        let mut pipe = redis::pipe();
+        for id in ids {
+            pipe.publish(BUSINESS_CHANGED_CHANNEL, id).ignore();
+        }
+        let _: () = pipe.query_async(&mut self.connection).await?;
+        Ok(())
+    }
+
+    // Drops lbs cached search results that could contain a business at this point
+    pub async fn invalidate_search_cache(&mut self, lon: f64, lat: f64) -> Result<()> {
+        self.invalidate_search_caches(&[(lon, lat)]).await
+    }
+
+    // Same for many points, each cache index is only cleared once
+    pub async fn invalidate_search_caches(&mut self, points: &[(f64, f64)]) -> Result<()> {
+        let index_keys: Vec<String> = points
+            .iter()
+            .flat_map(|(lon, lat)| search_cache::index_keys_for_point(*lon, *lat))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let mut pipe = redis::pipe();
+        for index in &index_keys {
+            pipe.smembers(index);
+        }
+        let entries: Vec<Vec<String>> = pipe.query_async(&mut self.connection).await?;
 
-        self.connection.hset_multiple(key, &values).await?;
+        let mut stale: Vec<String> = entries.into_iter().flatten().collect();
+        stale.extend(index_keys);
+        // one DEL per key, in a cluster the keys are spread over slots
+        let mut pipe = redis::pipe();
+        for key in &stale {
+            pipe.del(key).ignore();
+        }
+        let _: () = pipe.query_async(&mut self.connection).await?;
         Ok(())
     }
 }
 
+#[derive(Clone)]
 pub struct RedisGeo {
-    connection: redis::aio::MultiplexedConnection,
+    // suggestions live here, positions are in the shard nodes
+    pub connection: redis::aio::MultiplexedConnection,
+    pub nodes: Vec<redis::aio::MultiplexedConnection>,
+    pub shards: ShardMap,
 }
 
 impl RedisGeo {
-    pub async fn connect(conn_str: &str) -> Result<RedisGeo> {
+    pub async fn connect(conn_str: &str, nodes: &[String], precision: usize) -> Result<RedisGeo> {
         let client = redis::Client::open(conn_str).unwrap();
         let connection = client.get_multiplexed_async_connection().await.unwrap();
+        let mut node_connections = vec![];
+        for node in nodes {
+            let client = redis::Client::open(node.as_str())?;
+            node_connections.push(client.get_multiplexed_async_connection().await?);
+        }
+        if node_connections.is_empty() {
+            return Err("At least one geo shard node is required".into());
+        }
+
+        Ok(RedisGeo {
+            connection,
+            shards: ShardMap::new(precision, node_connections.len()),
+            nodes: node_connections,
+        })
+    }
+
+    // Writes the position into the shard of its region
+    pub async fn add_position(&mut self, id: u64, lon: f64, lat: f64) -> Result<()> {
+        self.add_positions(&[(id, lon, lat)]).await
+    }
 
-        Ok(RedisGeo { connection })
+    // add_position for many businesses, one pipeline per node
+    pub async fn add_positions(&mut self, positions: &[(u64, f64, f64)]) -> Result<()> {
+        let mut pipes = vec![redis::pipe(); self.nodes.len()];
+        let mut used = vec![false; self.nodes.len()];
+        for (id, lon, lat) in positions {
+            let key = self.shards.key_for(*lon, *lat);
+            let node = self.shards.node_for(&key);
+            used[node] = true;
+            let pipe = &mut pipes[node];
+            pipe.geo_add(&key, (*lon, *lat, *id)).ignore();
+            if self.shards.is_sharded() {
+                pipe.sadd(SHARD_LIST_KEY, &key).ignore();
+            }
+        }
+        for (node, connection) in self.nodes.iter_mut().enumerate() {
+            if used[node] {
+                let _: () = pipes[node].query_async(connection).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the new position before dropping the old ones, like rebalance-geo, so a search
+    // running meanwhile never misses the business
+    pub async fn move_position(&mut self, id: u64, lon: f64, lat: f64) -> Result<()> {
+        self.add_position(id, lon, lat).await?;
+        let key = self.shards.key_for(lon, lat);
+        self.remove_positions(id, Some(&key)).await
+    }
+
+    // Removes the position from every shard on every node but `keep`. Looking everywhere rather
+    // than at the shard of the last known position also clears entries left behind by earlier
+    // moves, by a precision change or by data seeded into "world"
+    pub async fn remove_positions(&mut self, id: u64, keep: Option<&str>) -> Result<()> {
+        let keep_node = keep.map(|key| self.shards.node_for(key));
+        for (node, connection) in self.nodes.iter_mut().enumerate() {
+            let mut keys: Vec<String> = connection.smembers(SHARD_LIST_KEY).await?;
+            keys.push(UNSHARDED_KEY.to_string());
+            let mut pipe = redis::pipe();
+            for key in &keys {
+                if keep_node == Some(node) && keep == Some(key.as_str()) {
+                    continue;
+                }
+                pipe.zrem(key, id).ignore();
+            }
+            let _: () = pipe.query_async(connection).await?;
+        }
+        Ok(())
+    }
+
+    // Keeps the document behind lbs `/suggest` up to date. The RediSearch index over these hashes
+    // is created by lbs on startup
+    pub async fn index_suggestion(&mut self, data: &BusinessData) -> Result<()> {
+        self.index_suggestions(std::slice::from_ref(data)).await
+    }
+
+    pub async fn index_suggestions(&mut self, batch: &[BusinessData]) -> Result<()> {
+        let mut pipe = redis::pipe();
+        for data in batch {
+            let id = data
+                .id
+                .ok_or("Failed to index suggestion, id does not exist")?;
+            let location = format!("{},{}", data.lon, data.lat);
+            let values = [
+                ("name", data.name.as_str()),
+                ("type", data.r#type.as_str()),
+                ("location", location.as_str()),
+            ];
+            pipe.hset_multiple(format!("suggest:{}", id), &values)
+                .ignore();
+        }
+        let _: () = pipe.query_async(&mut self.connection).await?;
+        Ok(())
+    }
+
+    pub async fn remove_suggestion(&mut self, id: u64) -> Result<()> {
+        let _: () = self.connection.del(format!("suggest:{}", id)).await?;
+        Ok(())
     }
 }
@@ -0,0 +1,431 @@
+use crate::dbs::{self, DBConnections};
+use crate::request::BodyFormat;
+use crate::{config::ServerConfig, response::Result};
+use model::business::BusinessData;
+use model::validation::{self, FieldError};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio::fs::File;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+
+const BATCH_SIZE: usize = 500;
+// Rows listed in the report, the rest are only counted
+const MAX_REPORTED_ROWS: usize = 1000;
+// csv cells are text, these become json numbers when they parse as one
+const NUMBER_COLUMNS: [&str; 8] = [
+    "stars",
+    "averagePrice",
+    "opensAt",
+    "closesAt",
+    "lat",
+    "lon",
+    "id",
+    "schemaVersion",
+];
+// a csv cell with the json of the opening hours
+const JSON_COLUMNS: [&str; 1] = ["hours"];
+
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportReport {
+    pub rows: usize,
+    pub imported: usize,
+    pub failed: usize,
+    pub errors: Vec<RowError>,
+    // more rows failed than `errors` lists
+    pub errors_truncated: bool,
+}
+
+impl ImportReport {
+    fn fail(&mut self, line: usize, errors: Vec<FieldError>) {
+        self.failed += 1;
+        if self.errors.len() < MAX_REPORTED_ROWS {
+            self.errors.push(RowError { line, errors });
+        } else {
+            self.errors_truncated = true;
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RowError {
+    // line of the input the row starts on, counted from 1. The header of a csv is line 1
+    pub line: usize,
+    pub errors: Vec<FieldError>,
+}
+
+// Reads businesses row by row, validates each like a POST body and creates the valid ones in
+// batches, see dbs::create_businesses. Rows that fail are reported and skipped, the others are
+// imported all the same. Ids are always allocated by the api, an `id` column is ignored
+pub async fn import(
+    dbs: &mut DBConnections,
+    reader: impl AsyncBufRead + Unpin,
+    format: BodyFormat,
+) -> Result<ImportReport> {
+    let mut report = ImportReport::default();
+    let mut batch = vec![];
+    let mut rows = Rows::new(reader, format).await?;
+    while let Some((line, row)) = rows.next_row().await? {
+        report.rows += 1;
+        match row.and_then(|row| validation::parse_business(&row)) {
+            Ok(data) => batch.push((line, data)),
+            Err(errors) => report.fail(line, errors),
+        }
+        if batch.len() == BATCH_SIZE {
+            write_batch(dbs, std::mem::take(&mut batch), &mut report).await?;
+        }
+    }
+    write_batch(dbs, batch, &mut report).await?;
+    Ok(report)
+}
+
+// `api import <file> [--format ndjson|csv]`, the format is taken from the file extension otherwise
+pub async fn run(config: &ServerConfig, args: &[String]) -> Result<()> {
+    let mut path = None;
+    let mut format = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = args.next().and_then(|f| parse_format(f)),
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return Err(format!("Unexpected argument: {}", arg).into()),
+        }
+    }
+    let path = path.ok_or("Usage: api import <file> [--format ndjson|csv]")?;
+    let format = format
+        .or_else(|| path.rsplit_once('.').and_then(|(_, ext)| parse_format(ext)))
+        .ok_or("Unknown format, pass --format ndjson or --format csv")?;
+
+    let mut dbs = DBConnections::init(config).await?;
+    let reader = BufReader::new(File::open(&path).await?);
+    let report = import(&mut dbs, reader, format).await?;
+
+    for row in &report.errors {
+        for error in &row.errors {
+            println!("Line {}: {} {}", row.line, error.field, error.message);
+        }
+    }
+    if report.errors_truncated {
+        println!("More rows failed than are listed...");
+    }
+    println!(
+        "Imported {} of {} businesses, {} failed",
+        report.imported, report.rows, report.failed
+    );
+    Ok(())
+}
+
+fn parse_format(name: &str) -> Option<BodyFormat> {
+    match name.to_lowercase().as_str() {
+        "ndjson" | "jsonl" => Some(BodyFormat::Ndjson),
+        "csv" => Some(BodyFormat::Csv),
+        _ => None,
+    }
+}
+
+// A failed write fails every row of the batch, the import carries on with the next one
+async fn write_batch(
+    dbs: &mut DBConnections,
+    batch: Vec<(usize, BusinessData)>,
+    report: &mut ImportReport,
+) -> Result<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let (lines, data): (Vec<usize>, Vec<BusinessData>) = batch.into_iter().unzip();
+    let mut result = dbs::create_businesses(dbs, data.clone()).await;
+    if let Ok(None) = result {
+        // the counter has been moved past the taken ids, the next block is free
+        result = dbs::create_businesses(dbs, data).await;
+    }
+    let error = match result {
+        Ok(Some(ids)) => {
+            report.imported += ids.len();
+            return Ok(());
+        }
+        Ok(None) => "ids given to the batch were already taken".to_string(),
+        Err(e) => e.to_string(),
+    };
+    println!("Failed to import a batch of businesses: {}", error);
+    for line in lines {
+        report.fail(
+            line,
+            vec![FieldError::new(
+                "",
+                format!("could not be written: {}", error),
+            )],
+        );
+    }
+    Ok(())
+}
+
+// Turns the input into one json object per business, with the line each one starts on
+struct Rows<R: AsyncBufRead + Unpin> {
+    reader: R,
+    format: BodyFormat,
+    line: usize,
+    header: Vec<String>,
+}
+
+impl<R: AsyncBufRead + Unpin> Rows<R> {
+    async fn new(reader: R, format: BodyFormat) -> Result<Rows<R>> {
+        let mut rows = Rows {
+            reader,
+            format,
+            line: 0,
+            header: vec![],
+        };
+        if format == BodyFormat::Csv {
+            rows.header = match rows.next_record().await? {
+                // spreadsheets tend to start the file with a byte order mark
+                Some((_, header, _)) => header
+                    .iter()
+                    .map(|name| name.trim_start_matches('\u{feff}').trim().to_string())
+                    .collect(),
+                None => vec![],
+            };
+        }
+        Ok(rows)
+    }
+
+    // Blank lines are skipped. None at the end of the input
+    #[allow(clippy::type_complexity)]
+    async fn next_row(
+        &mut self,
+    ) -> Result<Option<(usize, std::result::Result<Value, Vec<FieldError>>)>> {
+        match self.format {
+            BodyFormat::Ndjson => loop {
+                let Some(text) = self.next_line().await? else {
+                    return Ok(None);
+                };
+                if text.trim().is_empty() {
+                    continue;
+                }
+                let row = serde_json::from_str::<Value>(&text)
+                    .map_err(|e| vec![FieldError::new("", format!("is not valid json: {}", e))]);
+                return Ok(Some((self.line, row)));
+            },
+            BodyFormat::Csv => loop {
+                let Some((line, cells, closed)) = self.next_record().await? else {
+                    return Ok(None);
+                };
+                if !closed {
+                    let error = FieldError::new("", "has a quote that is never closed");
+                    return Ok(Some((line, Err(vec![error]))));
+                }
+                if cells.iter().all(|cell| cell.trim().is_empty()) {
+                    continue;
+                }
+                return Ok(Some((line, self.to_json(cells))));
+            },
+        }
+    }
+
+    fn to_json(&self, cells: Vec<String>) -> std::result::Result<Value, Vec<FieldError>> {
+        if cells.len() != self.header.len() {
+            return Err(vec![FieldError::new(
+                "",
+                format!(
+                    "has {} columns, the header has {}",
+                    cells.len(),
+                    self.header.len()
+                ),
+            )]);
+        }
+        let mut row = Map::new();
+        for (name, cell) in self.header.iter().zip(cells) {
+            // an empty cell is a missing field
+            if cell.is_empty() {
+                continue;
+            }
+            let value = if NUMBER_COLUMNS.contains(&name.as_str()) {
+                serde_json::from_str::<serde_json::Number>(cell.trim())
+                    .map(Value::Number)
+                    .unwrap_or(Value::String(cell))
+            } else if JSON_COLUMNS.contains(&name.as_str()) {
+                serde_json::from_str(&cell).unwrap_or(Value::String(cell))
+            } else {
+                Value::String(cell)
+            };
+            row.insert(name.clone(), value);
+        }
+        Ok(Value::Object(row))
+    }
+
+    // One csv record, RFC 4180: cells in double quotes may hold commas, line breaks and "" for a
+    // quote. Returns the line the record starts on, and false when a quote is still open at the
+    // end of the input
+    #[allow(clippy::type_complexity)]
+    async fn next_record(&mut self) -> Result<Option<(usize, Vec<String>, bool)>> {
+        let Some(mut text) = self.next_line().await? else {
+            return Ok(None);
+        };
+        let start = self.line;
+        let mut cells = vec![];
+        let mut cell = String::new();
+        let mut quoted = false;
+        loop {
+            let mut chars = text.chars().peekable();
+            while let Some(c) = chars.next() {
+                match (c, quoted) {
+                    ('"', true) if chars.peek() == Some(&'"') => {
+                        cell.push('"');
+                        chars.next();
+                    }
+                    ('"', true) => quoted = false,
+                    ('"', false) if cell.is_empty() => quoted = true,
+                    (',', false) => cells.push(std::mem::take(&mut cell)),
+                    (c, _) => cell.push(c),
+                }
+            }
+            if !quoted {
+                break;
+            }
+            // the quoted cell goes on in the next line
+            match self.next_line().await? {
+                Some(next) => {
+                    cell.push('\n');
+                    text = next;
+                }
+                None => break,
+            }
+        }
+        cells.push(cell);
+        Ok(Some((start, cells, !quoted)))
+    }
+
+    async fn next_line(&mut self) -> Result<Option<String>> {
+        let mut text = String::new();
+        if self.reader.read_line(&mut text).await? == 0 {
+            return Ok(None);
+        }
+        self.line += 1;
+        let trimmed = text.trim_end_matches(['\r', '\n']).len();
+        text.truncate(trimmed);
+        Ok(Some(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    type Row = (usize, std::result::Result<Value, Vec<FieldError>>);
+
+    async fn read(input: &str, format: BodyFormat) -> Vec<Row> {
+        let mut rows = Rows::new(input.as_bytes(), format).await.unwrap();
+        let mut read = vec![];
+        while let Some(row) = rows.next_row().await.unwrap() {
+            read.push(row);
+        }
+        read
+    }
+
+    fn error_of(row: &Row) -> String {
+        let errors = row.1.as_ref().unwrap_err();
+        errors[0].message.clone()
+    }
+
+    #[tokio::test]
+    async fn csv_cells_become_json() {
+        let input =
+            "name,stars,lat,hours\nNord,4,52.5,\"{\"\"timezone\"\":\"\"Europe/Berlin\"\"}\"\n";
+        let rows = read(input, BodyFormat::Csv).await;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].0, 2);
+        assert_eq!(
+            rows[0].1.as_ref().unwrap(),
+            &json!({"name": "Nord", "stars": 4, "lat": 52.5, "hours": {"timezone": "Europe/Berlin"}})
+        );
+    }
+
+    #[tokio::test]
+    async fn csv_quoted_commas_and_quotes() {
+        let input = "name,description\n\"Nord, Berlin\",\"The \"\"best\"\" cake\"\n";
+        let rows = read(input, BodyFormat::Csv).await;
+        assert_eq!(
+            rows[0].1.as_ref().unwrap(),
+            &json!({"name": "Nord, Berlin", "description": "The \"best\" cake"})
+        );
+    }
+
+    #[tokio::test]
+    async fn csv_crlf_and_line_breaks_in_cells() {
+        let input = "name,description\r\nNord,\"Coffee\r\nand cake\"\r\nSüd,Tea\r\n";
+        let rows = read(input, BodyFormat::Csv).await;
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].0, 2);
+        assert_eq!(
+            rows[0].1.as_ref().unwrap()["description"],
+            "Coffee\nand cake"
+        );
+        // the record before took two lines
+        assert_eq!(rows[1].0, 4);
+        assert_eq!(
+            rows[1].1.as_ref().unwrap(),
+            &json!({"name": "Süd", "description": "Tea"})
+        );
+    }
+
+    #[tokio::test]
+    async fn csv_rows_of_the_wrong_length() {
+        let input = "name,type,stars\nNord,cafe\nSüd,cafe,3,extra\nWest,cafe,5\n";
+        let rows = read(input, BodyFormat::Csv).await;
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].0, 2);
+        assert_eq!(error_of(&rows[0]), "has 2 columns, the header has 3");
+        assert_eq!(rows[1].0, 3);
+        assert_eq!(error_of(&rows[1]), "has 4 columns, the header has 3");
+        assert_eq!(rows[2].0, 4);
+        assert!(rows[2].1.is_ok());
+    }
+
+    #[tokio::test]
+    async fn csv_blank_lines_and_empty_cells() {
+        let input = "\u{feff}name,email\nNord,\n\n,\nSüd,sued@example.com\n\n";
+        let rows = read(input, BodyFormat::Csv).await;
+        assert_eq!(rows.len(), 2);
+        // an empty cell is a missing field
+        assert_eq!(rows[0].1.as_ref().unwrap(), &json!({"name": "Nord"}));
+        assert_eq!(rows[1].0, 5);
+        assert_eq!(rows[1].1.as_ref().unwrap()["name"], "Süd");
+    }
+
+    #[tokio::test]
+    async fn csv_unclosed_quote() {
+        let input = "name,description\nNord,ok\nSüd,\"never closed\nstill open\n";
+        let rows = read(input, BodyFormat::Csv).await;
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].0, 3);
+        assert_eq!(error_of(&rows[1]), "has a quote that is never closed");
+    }
+
+    #[tokio::test]
+    async fn ndjson_rows() {
+        let input = "{\"name\":\"Nord\"}\n\n{\"name\": oops}\r\n{\"name\":\"Süd\"}";
+        let rows = read(input, BodyFormat::Ndjson).await;
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].0, 1);
+        assert_eq!(rows[0].1.as_ref().unwrap(), &json!({"name": "Nord"}));
+        assert_eq!(rows[1].0, 3);
+        assert!(error_of(&rows[1]).starts_with("is not valid json"));
+        // no line break after the last one
+        assert_eq!(rows[2].0, 4);
+        assert_eq!(rows[2].1.as_ref().unwrap(), &json!({"name": "Süd"}));
+    }
+
+    #[tokio::test]
+    async fn empty_input_has_no_rows() {
+        assert!(read("", BodyFormat::Ndjson).await.is_empty());
+        assert!(read("", BodyFormat::Csv).await.is_empty());
+        assert!(read("name,type\n", BodyFormat::Csv).await.is_empty());
+    }
+
+    #[test]
+    fn formats_by_name() {
+        assert_eq!(parse_format("CSV"), Some(BodyFormat::Csv));
+        assert_eq!(parse_format("jsonl"), Some(BodyFormat::Ndjson));
+        assert_eq!(parse_format("xlsx"), None);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::dbs::{self, CREATED_AT_FIELD, UPDATED_AT_FIELD};
+use crate::request::Request;
+use bson::{doc, Bson, DateTime, Document};
+use model::business::DELETED_AT_FIELD;
+use serde_json::{json, Value};
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+// Each has an index together with id, see MongoDb::ensure_listing_indexes
+pub const SORT_FIELDS: [&str; 6] = ["id", "name", "stars", "type", "createdAt", "updatedAt"];
+
+// GET /api/business. Filters by exact type, zipCode and stars, a star range, a name prefix and
+// created/updated dates, sorts by one of SORT_FIELDS (`-` in front for descending) and pages
+// with the cursor of the previous page. Soft deleted businesses are never listed. Errors name
+// the parameter and go into the 400 body as they are
+pub struct ListQuery {
+    filter: Document,
+    sort_field: String,
+    descending: bool,
+    // sort value and id of the last business of the previous page
+    after: Option<(Bson, i64)>,
+    pub limit: usize,
+}
+
+impl ListQuery {
+    pub fn parse(req: &Request) -> Result<ListQuery, String> {
+        let mut filter = Document::new();
+        filter.insert(DELETED_AT_FIELD, doc! {"$exists": false});
+        for field in ["type", "zipCode"] {
+            if let Some(value) = req.query.get(field) {
+                filter.insert(field, value);
+            }
+        }
+
+        let mut stars = Document::new();
+        if let Some(value) = number(req, "stars")? {
+            stars.insert("$eq", value);
+        }
+        if let Some(value) = number(req, "minStars")? {
+            stars.insert("$gte", value);
+        }
+        if let Some(value) = number(req, "maxStars")? {
+            stars.insert("$lte", value);
+        }
+        if !stars.is_empty() {
+            filter.insert("stars", stars);
+        }
+
+        // anchored and case sensitive, so that the index on name is used
+        if let Some(prefix) = req.query.get("name") {
+            let pattern = format!("^{}", escape_regex(prefix));
+            filter.insert("name", doc! {"$regex": pattern});
+        }
+
+        for (field, after, before) in [
+            (CREATED_AT_FIELD, "createdAfter", "createdBefore"),
+            (UPDATED_AT_FIELD, "updatedAfter", "updatedBefore"),
+        ] {
+            let mut range = Document::new();
+            if let Some(date) = date(req, after)? {
+                range.insert("$gt", date);
+            }
+            if let Some(date) = date(req, before)? {
+                range.insert("$lt", date);
+            }
+            if !range.is_empty() {
+                filter.insert(field, range);
+            }
+        }
+
+        let sort = req.query.get("sort").map(|s| s.as_str()).unwrap_or("id");
+        let (descending, sort_field) = match sort.strip_prefix('-') {
+            Some(field) => (true, field),
+            None => (false, sort),
+        };
+        if !SORT_FIELDS.contains(&sort_field) {
+            return Err(format!("sort must be one of {}", SORT_FIELDS.join(", ")));
+        }
+
+        let after = match req.query.get("cursor") {
+            Some(cursor) => Some(decode_cursor(cursor).ok_or("cursor is not valid")?),
+            None => None,
+        };
+        let limit = match req.query.get("limit").map(|v| v.parse::<usize>()) {
+            None => DEFAULT_LIMIT,
+            Some(Ok(limit)) if limit > 0 => limit.min(MAX_LIMIT),
+            Some(_) => return Err("limit must be a whole number greater than 0".to_string()),
+        };
+
+        Ok(ListQuery {
+            filter,
+            sort_field: sort_field.to_string(),
+            descending,
+            after,
+            limit,
+        })
+    }
+
+    // id breaks ties, so that every business has one place in the order and pages neither skip
+    // nor repeat businesses with the same sort value
+    pub fn sort(&self) -> Document {
+        let direction = if self.descending { -1 } else { 1 };
+        let mut sort = Document::new();
+        sort.insert(self.sort_field.as_str(), direction);
+        sort.insert("id", direction);
+        sort
+    }
+
+    // The filter of the request, narrowed to what comes after the cursor
+    pub fn filter(&self) -> Document {
+        let Some((value, id)) = &self.after else {
+            return self.filter.clone();
+        };
+        let op = if self.descending { "$lt" } else { "$gt" };
+        let mut past_id = Document::new();
+        past_id.insert(op, id);
+        let position = if self.sort_field == "id" {
+            doc! {"id": past_id}
+        } else {
+            let mut past_value = Document::new();
+            past_value.insert(self.sort_field.as_str(), doc! {op: value.clone()});
+            let mut same_value = Document::new();
+            same_value.insert(self.sort_field.as_str(), value.clone());
+            same_value.insert("id", past_id);
+            doc! {"$or": [past_value, same_value]}
+        };
+        doc! {"$and": [self.filter.clone(), position]}
+    }
+
+    // Cursor of the page that follows the given last business
+    pub fn cursor_after(&self, last: &Document) -> Option<String> {
+        let id = dbs::id_of(last)?;
+        let value = last.get(&self.sort_field).cloned().unwrap_or(Bson::Null);
+        let cursor = json!([value.into_relaxed_extjson(), id]).to_string();
+        Some(cursor.bytes().map(|byte| format!("{:02x}", byte)).collect())
+    }
+}
+
+// The cursor is hex encoded json, opaque to clients and safe in a url as it is
+fn decode_cursor(cursor: &str) -> Option<(Bson, i64)> {
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(cursor.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let decoded: Value = serde_json::from_slice(&bytes).ok()?;
+    let [value, id] = decoded.as_array()?.as_slice() else {
+        return None;
+    };
+    Some((Bson::try_from(value.clone()).ok()?, id.as_i64()?))
+}
+
+fn number(req: &Request, name: &str) -> Result<Option<i32>, String> {
+    match req.query.get(name).map(|v| v.parse::<u8>()) {
+        None => Ok(None),
+        Some(Ok(value)) => Ok(Some(value as i32)),
+        Some(_) => Err(format!("{} must be a whole number", name)),
+    }
+}
+
+fn date(req: &Request, name: &str) -> Result<Option<DateTime>, String> {
+    match req.query.get(name).map(DateTime::parse_rfc3339_str) {
+        None => Ok(None),
+        Some(Ok(date)) => Ok(Some(date)),
+        Some(Err(_)) => Err(format!(
+            "{} must be an RFC 3339 date, e.g. 2024-05-01T00:00:00Z",
+            name
+        )),
+    }
+}
+
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -1,9 +1,17 @@
+mod cli;
 mod config;
 mod dbs;
+mod import;
+mod listing;
+mod migrate_ids;
+mod outbox;
 mod path_finder;
+mod rebalance;
+mod reconcile;
 mod request;
 mod response;
 mod router;
+mod sync;
 
 use config::ServerConfig;
 use dbs::DBConnections;
@@ -11,6 +19,7 @@ use request::Request;
 use request::{handle_request, parse_tcp_stream};
 use response::Result;
 use router::Router;
+use std::env;
 use std::io::Write;
 use std::net::{TcpListener, TcpStream};
 use std::time::Instant;
@@ -18,7 +27,12 @@ use std::time::Instant;
 #[tokio::main]
 async fn main() -> Result<()> {
     let config: ServerConfig = ServerConfig::get();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(command) = args.first() {
+        return cli::run(&config, command, &args[1..]).await;
+    }
     let mut connections = DBConnections::init(&config).await?;
+    outbox::spawn_worker(connections.clone());
     let listener = TcpListener::bind(format!("0.0.0.0:{}", config.port))
         .expect("Server failed to start at {config.port}");
     println!("Server is listening at {}", config.port);
@@ -49,6 +63,10 @@ async fn handle_tcp_stream<'a>(
     parse_tcp_stream(&mut stream, &mut req);
     let response = handle_request(&mut req, router, db_clients).await;
     println!("Time took {:?}", start_time.elapsed());
+    // a streamed body is read without blocking, see BodyStream::reader
+    if let Err(e) = stream.set_nonblocking(false) {
+        println!("Error resetting the stream: {}", e);
+    }
     stream
         .write_all(response.as_bytes())
         .expect("Failed to write back")
@@ -0,0 +1,73 @@
+use crate::dbs::{self, DBConnections};
+use crate::{config::ServerConfig, response::Result};
+use bson::{doc, Bson, Document};
+use model::business::{BusinessData, DELETED_AT_FIELD};
+
+// Gives a new id to every business that shares its id with another one, which ids counted from
+// the number of documents ended up doing after deletes and concurrent inserts, then adds the
+// unique index on `id`. The oldest document of each group keeps the id. The ids shared one hash
+// and one geo member in Redis, so every business of a group is written to Redis again
+pub async fn run(config: &ServerConfig) -> Result<()> {
+    let mut dbs = DBConnections::init(config).await?;
+    let businesses = dbs
+        .mongo
+        .get_businesses_collection()
+        .clone_with_type::<Document>();
+
+    let pipeline = vec![
+        doc! {"$sort": {"_id": 1}},
+        doc! {"$group": {"_id": "$id", "documents": {"$push": "$_id"}, "count": {"$sum": 1}}},
+        doc! {"$match": {"count": {"$gt": 1}}},
+    ];
+    let mut cursor = businesses.aggregate(pipeline, None).await?;
+    let mut groups = vec![];
+    while cursor.advance().await? {
+        groups.push(cursor.deserialize_current()?);
+    }
+
+    let mut renumbered = 0;
+    for group in &groups {
+        let documents = group.get_array("documents")?;
+        for (i, document_id) in documents.iter().enumerate() {
+            if i > 0 {
+                let new_id = dbs.mongo.next_business_id().await?;
+                businesses
+                    .update_one(
+                        doc! {"_id": document_id},
+                        doc! {"$set": {"id": new_id as i64}},
+                        None,
+                    )
+                    .await?;
+                renumbered += 1;
+            }
+            sync_document(&mut dbs, document_id).await?;
+        }
+    }
+
+    dbs.mongo.ensure_unique_ids().await?;
+    println!(
+        "Migrated business ids: {} duplicated ids, {} businesses got a new one",
+        groups.len(),
+        renumbered
+    );
+    Ok(())
+}
+
+// Soft deleted businesses are only renumbered, they are not in Redis
+async fn sync_document(dbs: &mut DBConnections, document_id: &Bson) -> Result<()> {
+    let document = dbs
+        .mongo
+        .get_businesses_collection()
+        .clone_with_type::<Document>()
+        .find_one(doc! {"_id": document_id}, None)
+        .await?;
+    let Some(document) = document else {
+        return Ok(());
+    };
+    if document.contains_key(DELETED_AT_FIELD) {
+        return Ok(());
+    }
+    let mut data: BusinessData = bson::from_document(document.clone())?;
+    data.id = dbs::id_of(&document).map(|id| id as u64);
+    dbs::write_to_redis(dbs, &data).await
+}
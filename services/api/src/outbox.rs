@@ -0,0 +1,273 @@
+use crate::dbs::{self, DBConnections, MongoDb};
+use crate::response::Result;
+use bson::oid::ObjectId;
+use bson::{doc, DateTime, Document};
+use model::business::{BusinessData, DELETED_AT_FIELD};
+use mongodb::options::{FindOneAndUpdateOptions, FindOneOptions, ReturnDocument};
+use mongodb::{ClientSession, IndexModel};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const OUTBOX_COLLECTION: &str = "outbox";
+// The request that made a write applies its event itself, the worker only takes the ones that
+// are still there after this long
+const INLINE_GRACE: Duration = Duration::from_secs(5);
+// Other workers leave a claimed event alone for this long
+const CLAIM_LEASE: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+// Writes that keep racing with a sync are left to their own events after this many rounds
+const MAX_SYNC_ROUNDS: usize = 5;
+
+// A business that Redis has to catch up with. It doesn't carry the data: applying it copies
+// whatever Mongo holds at that time, so an event applied twice, or after a newer one, does no harm
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxEvent {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub business_id: i64,
+    // lon and lat before the write, searches there may still list the business
+    pub previous: Option<[f64; 2]>,
+    pub created_at: DateTime,
+    pub next_attempt_at: DateTime,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+}
+
+impl OutboxEvent {
+    pub fn new(business_id: u64, previous: Option<&BusinessData>) -> OutboxEvent {
+        let now = DateTime::now();
+        OutboxEvent {
+            id: ObjectId::new(),
+            business_id: business_id as i64,
+            previous: previous.map(|data| [data.lon as f64, data.lat as f64]),
+            created_at: now,
+            next_attempt_at: after(now, INLINE_GRACE),
+            attempts: 0,
+            last_error: None,
+        }
+    }
+}
+
+// Counts of this api instance, the outbox itself is shared by all of them
+#[derive(Default)]
+pub struct OutboxStats {
+    pub applied: u64,
+    pub failed: u64,
+}
+
+pub type SharedOutboxStats = Arc<Mutex<OutboxStats>>;
+
+pub async fn ensure_index(mongo: &MongoDb) -> Result<()> {
+    let index = IndexModel::builder()
+        .keys(doc! {"nextAttemptAt": 1})
+        .build();
+    mongo
+        .get_outbox_collection()
+        .create_index(index, None)
+        .await?;
+    Ok(())
+}
+
+// Part of the transaction of the write
+pub async fn record(
+    mongo: &MongoDb,
+    event: &OutboxEvent,
+    session: &mut ClientSession,
+) -> Result<()> {
+    mongo
+        .get_outbox_collection()
+        .insert_one_with_session(event, None, session)
+        .await?;
+    Ok(())
+}
+
+pub async fn record_many(
+    mongo: &MongoDb,
+    events: &[OutboxEvent],
+    session: &mut ClientSession,
+) -> Result<()> {
+    mongo
+        .get_outbox_collection()
+        .insert_many_with_session(events, None, session)
+        .await?;
+    Ok(())
+}
+
+// For events that were applied some other way, e.g. a batch written to Redis in one go. Like
+// apply_now, a failure is only logged: the worker applies the events once more, which is harmless
+pub async fn complete(dbs: &mut DBConnections, events: &[OutboxEvent]) {
+    let ids: Vec<ObjectId> = events.iter().map(|event| event.id).collect();
+    let result = dbs
+        .mongo
+        .get_outbox_collection()
+        .delete_many(doc! {"_id": {"$in": ids}}, None)
+        .await;
+    match result {
+        Ok(_) => dbs.outbox_stats.lock().unwrap().applied += events.len() as u64,
+        Err(e) => println!(
+            "Failed to remove {} applied outbox events: {:?}",
+            events.len(),
+            e
+        ),
+    }
+}
+
+// Right after the write is committed. A failure is only logged, the write itself went through
+pub async fn apply_now(dbs: &mut DBConnections, event: &OutboxEvent) {
+    let error = match apply(dbs, event).await {
+        Ok(()) => return,
+        Err(e) => e.to_string(),
+    };
+    println!(
+        "Failed to sync business {} to Redis, the outbox worker will retry: {}",
+        event.business_id, error
+    );
+    reschedule(dbs, event, error).await;
+}
+
+// Every api instance runs one. Events are claimed before they are applied, so two workers don't
+// take the same one
+pub fn spawn_worker(mut dbs: DBConnections) {
+    tokio::spawn(async move {
+        loop {
+            // as text, an error can't be held across an await in a spawned task
+            let claimed = claim(&dbs.mongo).await.map_err(|e| e.to_string());
+            match claimed {
+                Ok(Some(event)) => {
+                    let error = match apply(&mut dbs, &event).await {
+                        Ok(()) => continue,
+                        Err(e) => e.to_string(),
+                    };
+                    println!(
+                        "Outbox failed to sync business {} (attempt {}): {}",
+                        event.business_id,
+                        event.attempts + 1,
+                        error
+                    );
+                    reschedule(&mut dbs, &event, error).await;
+                }
+                Ok(None) => tokio::time::sleep(POLL_INTERVAL).await,
+                Err(e) => {
+                    println!("Outbox worker failed to claim an event: {}", e);
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    });
+}
+
+pub async fn metrics(dbs: &DBConnections) -> Result<Value> {
+    let events = dbs.mongo.get_outbox_collection();
+    let pending = events.count_documents(None, None).await?;
+    let failing = events
+        .count_documents(doc! {"attempts": {"$gt": 0}}, None)
+        .await?;
+    let options = FindOneOptions::builder()
+        .sort(doc! {"createdAt": 1})
+        .build();
+    let oldest = events.find_one(None, options).await?;
+    let lag_ms = oldest
+        .map(|event| DateTime::now().timestamp_millis() - event.created_at.timestamp_millis())
+        .unwrap_or(0);
+    let stats = dbs.outbox_stats.lock().unwrap();
+
+    Ok(json!({
+        "pending": pending,
+        "failing": failing,
+        "lagMs": lag_ms,
+        "applied": stats.applied,
+        "failed": stats.failed,
+    }))
+}
+
+async fn claim(mongo: &MongoDb) -> Result<Option<OutboxEvent>> {
+    let now = DateTime::now();
+    let options = FindOneAndUpdateOptions::builder()
+        .sort(doc! {"nextAttemptAt": 1})
+        .return_document(ReturnDocument::After)
+        .build();
+    Ok(mongo
+        .get_outbox_collection()
+        .find_one_and_update(
+            doc! {"nextAttemptAt": {"$lte": now}},
+            doc! {"$set": {"nextAttemptAt": after(now, CLAIM_LEASE)}},
+            options,
+        )
+        .await?)
+}
+
+async fn apply(dbs: &mut DBConnections, event: &OutboxEvent) -> Result<()> {
+    sync_business(dbs, event.business_id as u64, event.previous).await?;
+    dbs.mongo
+        .get_outbox_collection()
+        .delete_one(doc! {"_id": event.id}, None)
+        .await?;
+    dbs.outbox_stats.lock().unwrap().applied += 1;
+    Ok(())
+}
+
+// Backs off exponentially, up to MAX_BACKOFF. Events are never dropped, `failing` in the metrics
+// shows the ones that are stuck
+async fn reschedule(dbs: &mut DBConnections, event: &OutboxEvent, error: String) {
+    dbs.outbox_stats.lock().unwrap().failed += 1;
+    let attempts = event.attempts + 1;
+    let backoff = Duration::from_secs(2u64.saturating_pow(attempts as u32)).min(MAX_BACKOFF);
+    let update = doc! {
+        "$set": {"nextAttemptAt": after(DateTime::now(), backoff), "lastError": error},
+        "$inc": {"attempts": 1},
+    };
+    let result = dbs
+        .mongo
+        .get_outbox_collection()
+        .update_one(doc! {"_id": event.id}, update, None)
+        .await;
+    if let Err(e) = result {
+        println!("Failed to reschedule outbox event {}: {:?}", event.id, e);
+    }
+}
+
+// Makes Redis match the business as it is in Mongo now, `previous` is where it was before the
+// change. The document is read again afterwards: if a write landed in between, its own sync may
+// have run before this one and been overwritten with the older state, so it is synced once more
+pub async fn sync_business(
+    dbs: &mut DBConnections,
+    id: u64,
+    previous: Option<[f64; 2]>,
+) -> Result<()> {
+    let mut current = find_business(&dbs.mongo, id).await?;
+    for _ in 0..MAX_SYNC_ROUNDS {
+        match current.as_ref() {
+            Some(document) if !document.contains_key(DELETED_AT_FIELD) => {
+                let mut data: BusinessData = bson::from_document(document.clone())?;
+                data.id = Some(id);
+                dbs::write_to_redis(dbs, &data).await?;
+            }
+            _ => dbs::remove_from_redis(dbs, id).await?,
+        }
+        let latest = find_business(&dbs.mongo, id).await?;
+        if latest == current {
+            if let Some([lon, lat]) = previous {
+                dbs.redis_business.invalidate_search_cache(lon, lat).await?;
+            }
+            return Ok(());
+        }
+        current = latest;
+    }
+    Err(format!("Business {} kept changing while it was synced", id).into())
+}
+
+async fn find_business(mongo: &MongoDb, id: u64) -> Result<Option<Document>> {
+    Ok(mongo
+        .get_businesses_collection()
+        .clone_with_type::<Document>()
+        .find_one(doc! {"id": id as i64}, None)
+        .await?)
+}
+
+fn after(time: DateTime, duration: Duration) -> DateTime {
+    DateTime::from_millis(time.timestamp_millis() + duration.as_millis() as i64)
+}
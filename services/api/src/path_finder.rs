@@ -43,6 +43,7 @@ pub struct Node {
 }
 
 #[derive(Clone, Debug)]
+#[allow(dead_code)]
 pub struct GraphNode {
     pub data: Node,
     pub visited: bool,
@@ -165,8 +166,8 @@ fn find_distance_between_points(pos1: LatLonPos, pos2: LatLonPos) -> u32 {
 fn connect_nodes(way: &ApiWay, graph: &mut Graph) {
     let mut prev_node: Option<&u64> = None;
     for node_id in way.nodes.iter() {
-        if prev_node.is_some() {
-            graph.interconnect(prev_node.unwrap(), node_id);
+        if let Some(prev_id) = prev_node {
+            graph.interconnect(prev_id, node_id);
         }
         prev_node = Some(node_id);
     }
@@ -0,0 +1,119 @@
+use crate::{config::ServerConfig, dbs::RedisGeo, response::Result};
+use model::shards::{SHARD_LIST_KEY, UNSHARDED_KEY};
+use redis::aio::MultiplexedConnection;
+use redis::geo::Coord;
+use redis::{AsyncCommands, Pipeline};
+
+const BATCH_SIZE: usize = 500;
+
+// Moves every position into the shard that the current GEO_SHARD_PRECISION and GEO_SHARD_NODES
+// put it in. Run it after changing either of them, or after seeding data into "world".
+// `--from <uri>` (repeatable) also drains nodes that are no longer in GEO_SHARD_NODES.
+// Positions are written to their new shard before they are removed from the old one, so a search
+// running meanwhile can see a business twice but never misses it
+pub async fn run(config: &ServerConfig, args: &[String]) -> Result<()> {
+    let mut geo = RedisGeo::connect(
+        &config.redis_geo,
+        &config.geo_shard_nodes,
+        config.geo_shard_precision,
+    )
+    .await?;
+
+    let mut sources = geo.nodes.clone();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--from", Some(uri)) => {
+                let client = redis::Client::open(uri.as_str())?;
+                sources.push(client.get_multiplexed_async_connection().await?);
+            }
+            _ => return Err(format!("Unexpected argument: {}", arg).into()),
+        }
+    }
+
+    let mut scanned = 0;
+    let mut moved = 0;
+    for (i, source) in sources.iter_mut().enumerate() {
+        // nodes given with --from are not part of the shard map, everything on them moves
+        let source_node = if i < geo.nodes.len() { Some(i) } else { None };
+        let mut keys: Vec<String> = source.smembers(SHARD_LIST_KEY).await?;
+        keys.push(UNSHARDED_KEY.to_string());
+        for key in keys {
+            let (key_scanned, key_moved) = drain_key(&mut geo, source, source_node, &key).await?;
+            scanned += key_scanned;
+            moved += key_moved;
+        }
+    }
+
+    println!(
+        "Rebalanced geo shards (precision {}, {} nodes): {} positions scanned, {} moved",
+        geo.shards.precision, geo.shards.nodes, scanned, moved
+    );
+    Ok(())
+}
+
+async fn drain_key(
+    geo: &mut RedisGeo,
+    source: &mut MultiplexedConnection,
+    source_node: Option<usize>,
+    key: &str,
+) -> Result<(usize, usize)> {
+    let mut scanned = 0;
+    let mut moved = 0;
+    let mut cursor: u64 = 0;
+    loop {
+        let (next, members): (u64, Vec<(String, f64)>) = redis::cmd("ZSCAN")
+            .arg(key)
+            .arg(cursor)
+            .arg("COUNT")
+            .arg(BATCH_SIZE)
+            .query_async(source)
+            .await?;
+        scanned += members.len();
+
+        if !members.is_empty() {
+            let names: Vec<&String> = members.iter().map(|(member, _)| member).collect();
+            let positions: Vec<Option<Coord<f64>>> = source.geo_pos(key, &names).await?;
+
+            let mut pipes: Vec<Option<Pipeline>> = vec![None; geo.nodes.len()];
+            let mut removed: Vec<&String> = vec![];
+            for ((member, score), position) in members.iter().zip(positions) {
+                // removed since the scan
+                let Some(position) = position else { continue };
+                let target = geo.shards.key_for(position.longitude, position.latitude);
+                let node = geo.shards.node_for(&target);
+                if source_node == Some(node) && target == key {
+                    continue;
+                }
+                // the score is the position itself, copying it keeps the exact position
+                let pipe = pipes[node].get_or_insert_with(Pipeline::new);
+                pipe.zadd(&target, member, *score).ignore();
+                if geo.shards.is_sharded() {
+                    pipe.sadd(SHARD_LIST_KEY, &target).ignore();
+                }
+                removed.push(member);
+            }
+
+            for (pipe, connection) in pipes.into_iter().zip(geo.nodes.iter_mut()) {
+                if let Some(pipe) = pipe {
+                    let _: () = pipe.query_async(connection).await?;
+                }
+            }
+            if !removed.is_empty() {
+                let _: () = source.zrem(key, &removed).await?;
+                moved += removed.len();
+            }
+        }
+
+        cursor = next;
+        if cursor == 0 {
+            break;
+        }
+    }
+
+    let left: usize = source.zcard(key).await?;
+    if left == 0 {
+        let _: () = source.srem(SHARD_LIST_KEY, key).await?;
+    }
+    Ok((scanned, moved))
+}
@@ -0,0 +1,440 @@
+use crate::dbs::{self, DBConnections};
+use crate::{config::ServerConfig, response::Result};
+use bson::{doc, Document};
+use model::business::{BusinessData, DELETED_AT_FIELD};
+use model::shards::{SHARD_LIST_KEY, UNSHARDED_KEY};
+use mongodb::options::FindOptions;
+use redis::geo::Coord;
+use redis::AsyncCommands;
+use std::collections::{HashMap, HashSet};
+
+const BATCH_SIZE: usize = 500;
+// Degrees, about 10 meters. Mongo keeps positions as f32 and GEOADD rounds them to its 52 bit
+// geohash, so they never match exactly
+const POSITION_TOLERANCE: f64 = 1e-4;
+// Discrepancies printed one by one, the rest are only counted
+const MAX_LISTED: usize = 100;
+
+#[derive(PartialEq)]
+enum Mode {
+    Report,
+    Repair,
+    Rebuild,
+}
+
+// Compares the Redis stores with Mongo and lists what doesn't match: businesses missing from the
+// business hashes, the geo shards or the suggestions, hashes with other values than Mongo,
+// positions off from Mongo's, and entries of businesses that are no longer in Mongo (or soft
+// deleted), or sitting in a shard they don't belong to.
+// `--repair` writes the businesses that don't match again and removes the orphans, `--rebuild`
+// writes every business. `--dry-run` only tells what they would do
+pub async fn run(config: &ServerConfig, args: &[String]) -> Result<()> {
+    let mut mode = Mode::Report;
+    let mut dry_run = false;
+    for arg in args {
+        match arg.as_str() {
+            "--repair" => mode = Mode::Repair,
+            "--rebuild" => mode = Mode::Rebuild,
+            "--dry-run" => dry_run = true,
+            _ => return Err(format!("Unexpected argument: {}", arg).into()),
+        }
+    }
+    let fix = mode != Mode::Report && !dry_run;
+
+    let mut dbs = DBConnections::init(config).await?;
+    let mut report = Report::default();
+    let live = check_businesses(&mut dbs, &mode, fix, &mut report).await?;
+    check_orphans(&mut dbs, &live, fix, &mut report).await?;
+
+    report.print(&mode, dry_run);
+    Ok(())
+}
+
+// Removes the Redis entries of every business that is no longer in Mongo, for the sync when a
+// delete doesn't say which business it was. Returns how many were removed
+pub async fn remove_orphans(dbs: &mut DBConnections) -> Result<usize> {
+    let mut filter = Document::new();
+    filter.insert(DELETED_AT_FIELD, doc! {"$exists": false});
+    let options = FindOptions::builder()
+        .batch_size(BATCH_SIZE as u32)
+        .projection(doc! {"_id": 0, "id": 1, "lon": 1, "lat": 1})
+        .build();
+    let mut cursor = dbs
+        .mongo
+        .get_businesses_collection()
+        .clone_with_type::<Document>()
+        .find(filter, options)
+        .await?;
+    let mut live = HashMap::new();
+    while cursor.advance().await? {
+        let document = cursor.deserialize_current()?;
+        let Some(id) = dbs::id_of(&document) else {
+            continue;
+        };
+        // NaN leaves the position alone wherever it is, it is not checked for its shard
+        let [lon, lat] = dbs::position_of(&document).unwrap_or([f64::NAN; 2]);
+        live.insert(id as u64, (lon, lat));
+    }
+    let mut report = Report::default();
+    check_orphans(dbs, &live, true, &mut report).await?;
+    Ok(report.removed)
+}
+
+// What `--repair` does, for the sync when changes it missed can't be read back from the oplog
+pub async fn repair(dbs: &mut DBConnections) -> Result<()> {
+    let mut report = Report::default();
+    let live = check_businesses(dbs, &Mode::Repair, true, &mut report).await?;
+    check_orphans(dbs, &live, true, &mut report).await?;
+    report.print(&Mode::Repair, false);
+    Ok(())
+}
+
+#[derive(Default)]
+struct Report {
+    checked: usize,
+    unreadable: usize,
+    missing_hash: usize,
+    mismatched_hash: usize,
+    missing_position: usize,
+    mismatched_position: usize,
+    missing_suggestion: usize,
+    mismatched_suggestion: usize,
+    orphaned_hash: usize,
+    orphaned_position: usize,
+    misplaced_position: usize,
+    orphaned_suggestion: usize,
+    written: usize,
+    removed: usize,
+    listed: usize,
+}
+
+impl Report {
+    fn list(&mut self, id: u64, problem: &str) {
+        self.listed += 1;
+        if self.listed <= MAX_LISTED {
+            println!("Business {}: {}", id, problem);
+        } else if self.listed == MAX_LISTED + 1 {
+            println!("More discrepancies are only counted...");
+        }
+    }
+
+    fn print(&self, mode: &Mode, dry_run: bool) {
+        println!("Checked {} businesses from Mongo", self.checked);
+        let counts = [
+            ("unreadable in Mongo", self.unreadable),
+            ("missing hash", self.missing_hash),
+            ("hash differs from Mongo", self.mismatched_hash),
+            ("missing geo position", self.missing_position),
+            ("geo position differs from Mongo", self.mismatched_position),
+            ("missing suggestion", self.missing_suggestion),
+            ("suggestion differs from Mongo", self.mismatched_suggestion),
+            ("orphaned hash", self.orphaned_hash),
+            ("orphaned geo position", self.orphaned_position),
+            ("geo position in the wrong shard", self.misplaced_position),
+            ("orphaned suggestion", self.orphaned_suggestion),
+        ];
+        for (name, count) in counts {
+            println!("  {}: {}", name, count);
+        }
+        let verb = if dry_run { "Would have" } else { "Have" };
+        match mode {
+            Mode::Report => {
+                println!("Run with --repair to fix them, or --rebuild to write all of Redis again")
+            }
+            _ => println!(
+                "{} written {} businesses to Redis and removed {} orphaned entries",
+                verb, self.written, self.removed
+            ),
+        }
+    }
+}
+
+// Returns the position of every live business, to tell orphans and misplaced entries apart later
+async fn check_businesses(
+    dbs: &mut DBConnections,
+    mode: &Mode,
+    fix: bool,
+    report: &mut Report,
+) -> Result<HashMap<u64, (f64, f64)>> {
+    let businesses = dbs
+        .mongo
+        .get_businesses_collection()
+        .clone_with_type::<Document>();
+    let mut filter = Document::new();
+    filter.insert(DELETED_AT_FIELD, doc! {"$exists": false});
+    let total = businesses.count_documents(filter.clone(), None).await?;
+    let options = FindOptions::builder()
+        .batch_size(BATCH_SIZE as u32)
+        .projection(doc! {"_id": 0, "location": 0})
+        .build();
+    let mut cursor = businesses.find(filter, options).await?;
+
+    let mut live = HashMap::new();
+    let mut batch = vec![];
+    while cursor.advance().await? {
+        let document = cursor.deserialize_current()?;
+        let Some(id) = dbs::id_of(&document) else {
+            report.unreadable += 1;
+            continue;
+        };
+        let id = id as u64;
+        match bson::from_document::<BusinessData>(document) {
+            Ok(mut data) => {
+                data.id = Some(id);
+                live.insert(id, (data.lon as f64, data.lat as f64));
+                batch.push(data);
+            }
+            Err(e) => {
+                // its Redis entries are left alone, they are not orphans
+                live.insert(id, (f64::NAN, f64::NAN));
+                report.unreadable += 1;
+                report.list(id, &format!("unreadable in Mongo: {}", e));
+            }
+        }
+        if batch.len() == BATCH_SIZE {
+            check_batch(dbs, &batch, mode, fix, report).await?;
+            batch.clear();
+            println!("Checked {} of {} businesses", report.checked, total);
+        }
+    }
+    if !batch.is_empty() {
+        check_batch(dbs, &batch, mode, fix, report).await?;
+        println!("Checked {} of {} businesses", report.checked, total);
+    }
+    Ok(live)
+}
+
+async fn check_batch(
+    dbs: &mut DBConnections,
+    batch: &[BusinessData],
+    mode: &Mode,
+    fix: bool,
+    report: &mut Report,
+) -> Result<()> {
+    let ids: Vec<u64> = batch.iter().filter_map(|data| data.id).collect();
+    let hashes = dbs.redis_business.get_hashes(&ids).await?;
+    let positions = read_positions(dbs, batch).await?;
+    let suggestions = read_suggestions(dbs, &ids).await?;
+
+    for (((data, hash), position), suggestion) in
+        batch.iter().zip(hashes).zip(positions).zip(suggestions)
+    {
+        let id = data.id.unwrap_or_default();
+        let mut matches = true;
+        if let Some(problem) = compare_hash(data, hash) {
+            matches = false;
+            if problem == "missing hash" {
+                report.missing_hash += 1;
+            } else {
+                report.mismatched_hash += 1;
+            }
+            report.list(id, &problem);
+        }
+        match position {
+            None => {
+                matches = false;
+                report.missing_position += 1;
+                report.list(id, "missing geo position");
+            }
+            Some(position) => {
+                let (lon, lat) = (data.lon as f64, data.lat as f64);
+                if (position.longitude - lon).abs() > POSITION_TOLERANCE
+                    || (position.latitude - lat).abs() > POSITION_TOLERANCE
+                {
+                    matches = false;
+                    report.mismatched_position += 1;
+                    report.list(
+                        id,
+                        &format!(
+                            "geo position is {},{} instead of {},{}",
+                            position.longitude, position.latitude, lon, lat
+                        ),
+                    );
+                }
+            }
+        }
+        match suggestion {
+            (None, None) => {
+                matches = false;
+                report.missing_suggestion += 1;
+                report.list(id, "missing suggestion");
+            }
+            (name, r#type) => {
+                if name.as_deref() != Some(data.name.as_str())
+                    || r#type.as_deref() != Some(data.r#type.as_str())
+                {
+                    matches = false;
+                    report.mismatched_suggestion += 1;
+                    report.list(id, "suggestion differs from Mongo");
+                }
+            }
+        }
+
+        report.checked += 1;
+        if mode == &Mode::Rebuild || (mode == &Mode::Repair && !matches) {
+            if fix {
+                dbs::write_to_redis(dbs, data).await?;
+            }
+            report.written += 1;
+        }
+    }
+    Ok(())
+}
+
+// Both sides go through the hash codec, so that the way a value was written (e.g. a position
+// with more digits by the seeding script) doesn't count as a difference
+fn compare_hash(data: &BusinessData, hash: HashMap<String, String>) -> Option<String> {
+    if hash.is_empty() {
+        return Some("missing hash".to_string());
+    }
+    let id = data.id.unwrap_or_default();
+    let cached = match BusinessData::from_hash(id, hash) {
+        // fields Mongo doesn't know are left to the version that wrote them
+        Ok(cached) => BusinessData {
+            extra: HashMap::new(),
+            ..cached
+        },
+        Err(e) => return Some(format!("unreadable hash: {}", e)),
+    };
+    let expected: HashMap<String, String> = data.to_hash().ok()?.into_iter().collect();
+    let found: HashMap<String, String> = cached.to_hash().ok()?.into_iter().collect();
+    let mut fields: Vec<&String> = expected
+        .keys()
+        .chain(found.keys())
+        .filter(|field| expected.get(*field) != found.get(*field))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+    fields.sort();
+    Some(format!("hash differs from Mongo in {:?}", fields))
+}
+
+// GEOPOS in the shard each business belongs to, one pipeline per node
+async fn read_positions(
+    dbs: &mut DBConnections,
+    batch: &[BusinessData],
+) -> Result<Vec<Option<Coord<f64>>>> {
+    let geo = &mut dbs.redis_geo;
+    let mut pipes: Vec<redis::Pipeline> = vec![redis::pipe(); geo.nodes.len()];
+    let mut owners = vec![];
+    for data in batch {
+        let key = geo.shards.key_for(data.lon as f64, data.lat as f64);
+        let node = geo.shards.node_for(&key);
+        pipes[node].geo_pos(&key, data.id.unwrap_or_default());
+        owners.push(node);
+    }
+    let mut found: Vec<std::vec::IntoIter<Vec<Option<Coord<f64>>>>> = vec![];
+    for (pipe, connection) in pipes.iter().zip(geo.nodes.iter_mut()) {
+        let positions: Vec<Vec<Option<Coord<f64>>>> = pipe.query_async(connection).await?;
+        found.push(positions.into_iter());
+    }
+    Ok(owners
+        .into_iter()
+        .map(|node| {
+            found[node]
+                .next()
+                .and_then(|positions| positions.into_iter().next().flatten())
+        })
+        .collect())
+}
+
+async fn read_suggestions(
+    dbs: &mut DBConnections,
+    ids: &[u64],
+) -> Result<Vec<(Option<String>, Option<String>)>> {
+    let mut pipe = redis::pipe();
+    for id in ids {
+        pipe.hget(format!("suggest:{}", id), &["name", "type"]);
+    }
+    Ok(pipe.query_async(&mut dbs.redis_geo.connection).await?)
+}
+
+// Entries of businesses that Mongo doesn't have (or has soft deleted), and positions outside of
+// the shard of the business. Cached searches that list an orphan expire on their own
+async fn check_orphans(
+    dbs: &mut DBConnections,
+    live: &HashMap<u64, (f64, f64)>,
+    fix: bool,
+    report: &mut Report,
+) -> Result<()> {
+    println!("Looking for orphaned entries...");
+    match dbs.redis_business.business_ids().await? {
+        Some(ids) => {
+            for id in ids.into_iter().filter(|id| !live.contains_key(id)) {
+                report.orphaned_hash += 1;
+                report.list(id, "orphaned hash");
+                if fix {
+                    dbs.redis_business.delete_hash(id).await?;
+                    dbs.redis_business.publish_change(id).await?;
+                }
+                report.removed += 1;
+            }
+        }
+        None => println!("The business store is a cluster, orphaned hashes are not looked for"),
+    }
+
+    let shards = dbs.redis_geo.shards.clone();
+    for (node, connection) in dbs.redis_geo.nodes.iter_mut().enumerate() {
+        let mut keys: Vec<String> = connection.smembers(SHARD_LIST_KEY).await?;
+        keys.push(UNSHARDED_KEY.to_string());
+        for key in keys {
+            let mut stale = vec![];
+            let mut members = connection.zscan::<_, (String, f64)>(&key).await?;
+            while let Some((member, _)) = members.next_item().await {
+                let Ok(id) = member.parse::<u64>() else {
+                    continue;
+                };
+                match live.get(&id) {
+                    None => {
+                        report.orphaned_position += 1;
+                        stale.push((id, "orphaned geo position".to_string()));
+                    }
+                    Some((lon, lat)) if !lon.is_nan() => {
+                        let expected = shards.key_for(*lon, *lat);
+                        if expected != key || shards.node_for(&expected) != node {
+                            report.misplaced_position += 1;
+                            stale.push((id, format!("geo position in {} on node {}", key, node)));
+                        }
+                    }
+                    Some(_) => {}
+                }
+            }
+            drop(members);
+            for (id, problem) in stale {
+                report.list(id, &problem);
+                if fix {
+                    let _: () = connection.zrem(&key, id).await?;
+                }
+                report.removed += 1;
+            }
+        }
+    }
+
+    let mut orphaned = vec![];
+    let mut keys = dbs
+        .redis_geo
+        .connection
+        .scan_match::<_, String>("suggest:*")
+        .await?;
+    while let Some(key) = keys.next_item().await {
+        let id = key.trim_start_matches("suggest:").parse::<u64>();
+        if let Ok(id) = id {
+            if !live.contains_key(&id) {
+                orphaned.push(id);
+            }
+        }
+    }
+    drop(keys);
+    for id in orphaned {
+        report.orphaned_suggestion += 1;
+        report.list(id, "orphaned suggestion");
+        if fix {
+            dbs.redis_geo.remove_suggestion(id).await?;
+        }
+        report.removed += 1;
+    }
+    Ok(())
+}
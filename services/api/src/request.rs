@@ -5,6 +5,46 @@ use serde_json::Value;
 use std::collections::{HashMap, VecDeque};
 use std::io::Read;
 use std::net::TcpStream;
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, AsyncReadExt, BufReader};
+
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+// json bodies are read whole, streamed ones have no limit
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+// Content types of bodies that are not read up front but streamed to the handler, rows at a time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyFormat {
+    Ndjson,
+    Csv,
+}
+
+// The socket, the part of the body that came in with the headers and the length of the whole body
+#[derive(Clone)]
+pub struct BodyStream {
+    stream: Arc<TcpStream>,
+    read: Vec<u8>,
+    length: usize,
+}
+
+impl BodyStream {
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+    // Read through tokio, so that a slow client doesn't hold up the runtime thread while a large
+    // body trickles in. The socket is switched to non-blocking for that, handle_tcp_stream
+    // switches it back before it answers
+    pub fn reader(&self) -> std::io::Result<impl AsyncBufRead + Unpin + '_> {
+        let stream = self.stream.try_clone()?;
+        stream.set_nonblocking(true)?;
+        let stream = tokio::net::TcpStream::from_std(stream)?;
+        let rest = self.length.saturating_sub(self.read.len()) as u64;
+        Ok(BufReader::new(AsyncReadExt::chain(
+            self.read.as_slice(),
+            stream.take(rest),
+        )))
+    }
+}
 
 // Very basic request struct. We're not going implement entire HTTP protocol
 #[derive(Clone)]
@@ -18,6 +58,8 @@ pub struct Request {
     pub http_version: Option<String>,
     pub body: Option<Value>,
     pub params: HashMap<String, String>,
+    pub query: HashMap<String, String>,
+    pub body_stream: Option<BodyStream>,
 }
 impl Request {
     pub fn default() -> Request {
@@ -31,19 +73,45 @@ impl Request {
             http_version: Some(String::from("HTTP/1.1")),
             body: None,
             params: HashMap::new(),
+            query: HashMap::new(),
+            body_stream: None,
+        }
+    }
+    pub fn body_format(&self) -> Option<BodyFormat> {
+        let content_type = self.content_type.as_deref()?;
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        match mime.to_lowercase().as_str() {
+            "application/x-ndjson" | "application/ndjson" | "application/jsonl" => {
+                Some(BodyFormat::Ndjson)
+            }
+            "text/csv" => Some(BodyFormat::Csv),
+            _ => None,
         }
     }
 }
 pub fn parse_tcp_stream(stream: &mut TcpStream, request_struct: &mut Request) {
-    let mut buffer = [0; 5024];
+    let mut buffer = [0; 1024];
+    let mut raw: Vec<u8> = vec![];
 
-    let request_raw;
-    if let Ok(size) = stream.read(&mut buffer) {
-        request_raw = String::from_utf8_lossy(&buffer[..size])
-    } else {
-        println!("Error reading incoming stream");
-        return;
-    }
+    // headers first, the body is read once we know what it is
+    let body_start = loop {
+        if let Some(end) = raw.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if raw.len() >= MAX_HEADER_SIZE {
+            break raw.len();
+        }
+        match stream.read(&mut buffer) {
+            Ok(0) => break raw.len(),
+            Ok(size) => raw.extend_from_slice(&buffer[..size]),
+            Err(_) => {
+                println!("Error reading incoming stream");
+                return;
+            }
+        }
+    };
+    let body = raw.split_off(body_start);
+    let request_raw = String::from_utf8_lossy(&raw);
 
     println!("Raw: {:?}", request_raw);
 
@@ -62,6 +130,7 @@ pub fn parse_tcp_stream(stream: &mut TcpStream, request_struct: &mut Request) {
         if ind == 0 {
             request_struct.method = Some(splitted[0].to_string());
             request_struct.path = Some(splitted[1].to_string());
+            parse_query(splitted[1], &mut request_struct.query);
             request_struct.http_version = Some(splitted[2].to_string());
         }
         if splitted[0] == "Host:" {
@@ -76,15 +145,72 @@ pub fn parse_tcp_stream(stream: &mut TcpStream, request_struct: &mut Request) {
         if splitted[0] == "Content-Length:" {
             request_struct.content_length = Some(splitted[1].to_string());
         }
-        if splitted[0].contains('{') {
-            let parsed_body: Option<Value> = serde_json::from_str(line.unwrap()).unwrap_or(None);
-            if let Some(value) = parsed_body {
-                request_struct.body = Some(value);
-            }
-        }
 
         ind += 1;
     }
+
+    let length = request_struct
+        .content_length
+        .as_ref()
+        .and_then(|length| length.parse::<usize>().ok())
+        .unwrap_or(0);
+    if request_struct.body_format().is_some() {
+        // the handler reads it, it can be far larger than what we keep in memory
+        request_struct.body_stream = stream.try_clone().ok().map(|stream| BodyStream {
+            stream: Arc::new(stream),
+            read: body,
+            length,
+        });
+        return;
+    }
+    let mut body = body;
+    let length = length.min(MAX_BODY_SIZE);
+    while body.len() < length {
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(size) => body.extend_from_slice(&buffer[..size]),
+        }
+    }
+    if !body.is_empty() {
+        request_struct.body = serde_json::from_slice(&body).ok();
+    }
+}
+
+fn parse_query(path: &str, query_map: &mut HashMap<String, String>) {
+    let query_raw = path.split_once('?');
+    if query_raw.is_none() {
+        return;
+    }
+
+    let pairs: Vec<&str> = query_raw.unwrap().1.split('&').collect();
+
+    for pair in pairs {
+        let splitted = pair.split_once('=').unwrap_or(("", ""));
+        query_map.insert(splitted.0.to_string(), decode_query_value(splitted.1));
+    }
+}
+
+// Query values come percent-encoded from the browser (e.g. `name=joe%27s+cafe`)
+fn decode_query_value(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'+', _) => decoded.push(b' '),
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 2;
+            }
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
 }
 
 pub async fn handle_request<'a>(
@@ -1,3 +1,4 @@
+use model::validation::FieldError;
 use serde_json::{json, Value};
 use std::{
     error::Error,
@@ -8,6 +9,9 @@ const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n
 const BAD_REQUEST: &str = "HTTP/1.1 400 BAD_REQUEST\r\nContent-Type: application/json\r\n";
 const UNAUTHORIZED: &str = "HTTP/1.1 401 UNAUTHORIZED\r\nContent-Type: application/json\r\n";
 const NOT_FOUND: &str = "HTTP/1.1 404 NOT FOUND\r\nContent-Type: application/json\r\n";
+const CONFLICT: &str = "HTTP/1.1 409 CONFLICT\r\nContent-Type: application/json\r\n";
+const UNPROCESSABLE_ENTITY: &str =
+    "HTTP/1.1 422 UNPROCESSABLE ENTITY\r\nContent-Type: application/json\r\n";
 const INTERNAL_SERVER_ERROR: &str =
     "HTTP/1.1 500 INTERNAL SERVER ERROR\r\nContent-Type: application/json\r\n";
 
@@ -56,6 +60,19 @@ impl Response {
             body: json!({"message": message.unwrap_or("Resource not found!")}),
         }
     }
+    pub fn conflict(message: Option<&str>) -> Response {
+        Response {
+            status: 409,
+            body: json!({"message": message.unwrap_or("Conflicting request")}),
+        }
+    }
+    // The body was well formed, but fields of it are not valid. Lists every one of them
+    pub fn unprocessable(errors: &[FieldError]) -> Response {
+        Response {
+            status: 422,
+            body: json!({"message": "Invalid fields", "errors": errors}),
+        }
+    }
     pub fn to_response_string(&self) -> String {
         if self.status == 200 {
             return self.construct_response_string(OK_RESPONSE);
@@ -69,16 +86,20 @@ impl Response {
         if self.status == 404 {
             return self.construct_response_string(NOT_FOUND);
         }
+        if self.status == 409 {
+            return self.construct_response_string(CONFLICT);
+        }
+        if self.status == 422 {
+            return self.construct_response_string(UNPROCESSABLE_ENTITY);
+        }
         self.construct_response_string(INTERNAL_SERVER_ERROR)
     }
     fn construct_response_string(&self, response_type: &str) -> String {
         let allow_origin = "Access-Control-Allow-Origin: *\r\n".to_string();
-        let allow_methods = "Access-Control-Allow-Methods: GET, POST, PUT\r\n".to_string();
+        let allow_methods =
+            "Access-Control-Allow-Methods: GET, POST, PUT, PATCH, DELETE\r\n".to_string();
         let allow_headers = "Access-Control-Allow-Headers: DNT,User-Agent,X-Requested-With,If-Modified-Since,Cache-Control,Content-Type,Range\r\n".to_string();
-        let content_length = format!(
-            "Content-Length: {}\r\n\r\n",
-            self.body.to_string().as_bytes().len()
-        );
+        let content_length = format!("Content-Length: {}\r\n\r\n", self.body.to_string().len());
         let server = format!("Server: {}\r\n", "Rust");
         let response = format!(
             "{}{}{}{}{}{}{}\r\n\r\n",
@@ -94,6 +115,7 @@ impl Response {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct InternalError;
 
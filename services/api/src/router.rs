@@ -1,8 +1,13 @@
-use crate::dbs::{BusinessData, DBConnections};
+use crate::dbs::{self, DBConnections, CREATED_AT_FIELD, UPDATED_AT_FIELD};
+use crate::import;
+use crate::listing::ListQuery;
+use crate::outbox;
 use crate::path_finder::{create_path, OverpassApiResponse};
 use crate::response::{Response, Result};
 use crate::Request;
-use serde_json::json;
+use model::business::BusinessData;
+use model::validation;
+use serde_json::{json, Map, Value};
 use std::collections::HashMap;
 use std::collections::VecDeque;
 use std::future::IntoFuture;
@@ -15,10 +20,16 @@ pub struct Router<'a> {
 impl<'a> Router<'a> {
     pub fn init() -> Router<'a> {
         let routes = vec![
+            "GET /api/business",
             "GET /api/business/:id",
             "PUT /api/business/:id",
+            "PATCH /api/business/:id",
             "POST /api/business",
+            "POST /api/business/import",
+            "DELETE /api/business/:id",
+            "POST /api/business/:id/restore",
             "POST /api/createRoute",
+            "GET /api/metrics",
         ];
 
         Router { routes }
@@ -28,9 +39,12 @@ impl<'a> Router<'a> {
         req: &mut Request,
         connections: &mut DBConnections,
     ) -> Result<Response> {
+        // the query string is in req.query
+        let path = req.path.clone().unwrap();
+        let path = path.split_once('?').map_or(path.as_str(), |(path, _)| path);
         let matched_path = self.path_parser(
             self.routes.clone(),
-            req.path.as_ref().unwrap(),
+            path,
             &mut req.params,
             req.method.as_ref().unwrap(),
         );
@@ -40,31 +54,87 @@ impl<'a> Router<'a> {
         let matched_path = matched_path.unwrap();
 
         match matched_path {
+            "GET /api/business" => self.handle_list_businesses(req, connections).await,
             "GET /api/business/:id" => self.handle_get_business(req, connections).await,
             "PUT /api/business/:id" => self.handle_update_business(req, connections).await,
+            "PATCH /api/business/:id" => self.handle_patch_business(req, connections).await,
             "POST /api/business" => self.handle_create_business(req, connections).await,
+            "POST /api/business/import" => self.handle_import_businesses(req, connections).await,
+            "DELETE /api/business/:id" => self.handle_delete_business(req, connections).await,
+            "POST /api/business/:id/restore" => {
+                self.handle_restore_business(req, connections).await
+            }
             "POST /api/createRoute" => self.handle_calculate_route(req).await,
+            "GET /api/metrics" => self.handle_get_metrics(connections).await,
             _ => Ok(Response::not_found(None)),
         }
     }
 
+    async fn handle_list_businesses(
+        &self,
+        req: &Request,
+        connections: &mut DBConnections,
+    ) -> Result<Response> {
+        let query = match ListQuery::parse(req) {
+            Ok(query) => query,
+            Err(message) => return Ok(Response::bad_request(Some(&message))),
+        };
+        let mut businesses = dbs::list_businesses(&connections.mongo, &query).await?;
+        let next_cursor = if businesses.len() > query.limit {
+            businesses.truncate(query.limit);
+            businesses.last().and_then(|last| query.cursor_after(last))
+        } else {
+            None
+        };
+
+        let mut data = vec![];
+        for business in businesses {
+            let dates = [CREATED_AT_FIELD, UPDATED_AT_FIELD].map(|field| {
+                business
+                    .get_datetime(field)
+                    .ok()
+                    .and_then(|date| date.try_to_rfc3339_string().ok())
+            });
+            let id = dbs::id_of(&business).map(|id| id as u64);
+            let mut business: BusinessData = bson::from_document(business)?;
+            business.id = id;
+            let open_now = business.opening_hours().is_open_now();
+            let mut item = serde_json::to_value(business)?;
+            item[CREATED_AT_FIELD] = json!(dates[0]);
+            item[UPDATED_AT_FIELD] = json!(dates[1]);
+            item["openNow"] = json!(open_now);
+            data.push(item);
+        }
+        Ok(Response::success(
+            json!({"data": data, "nextCursor": next_cursor}),
+            None,
+        ))
+    }
+
     async fn handle_get_business(
         &self,
         req: &Request,
         connections: &mut DBConnections,
     ) -> Result<Response> {
         let id = req.params.get("id").unwrap().parse()?;
-        let data = BusinessData::get_business_by_id(connections, id).await?;
+        let data = dbs::get_business_by_id(connections, id).await?;
 
         if data.is_none() {
             return Ok(Response::success(json!({"data": ""}), None));
         }
+        let data = data.unwrap();
         let body = json!({
-            "data": data.unwrap()
+            "openNow": data.opening_hours().is_open_now(),
+            "data": data
         });
         Ok(Response::success(body, None))
     }
 
+    async fn handle_get_metrics(&self, connections: &DBConnections) -> Result<Response> {
+        let outbox = outbox::metrics(connections).await?;
+        Ok(Response::success(json!({ "outbox": outbox }), None))
+    }
+
     async fn handle_calculate_route(&self, req: &Request) -> Result<Response> {
         let data = req.body.as_ref();
         if data.is_none() {
@@ -165,17 +235,39 @@ impl<'a> Router<'a> {
         if req.body.is_none() {
             return Ok(Response::bad_request(Some("Missing data for new item")));
         }
-        let data = req.body.as_ref().unwrap();
-        let serialized = BusinessData::from_value(data);
-        if serialized.is_err() {
-            return Ok(Response::bad_request(Some("Invalid data for new item")));
-        }
-        match BusinessData::create_business(connections, serialized.unwrap()).await {
-            Ok(res) => Ok(Response::success(json!({"id": res}), None)),
+        let data = match validation::parse_business(req.body.as_ref().unwrap()) {
+            Ok(data) => data,
+            Err(errors) => return Ok(Response::unprocessable(&errors)),
+        };
+        match dbs::create_business(connections, data).await {
+            Ok(Some(res)) => Ok(Response::success(json!({"id": res}), None)),
+            Ok(None) => Ok(Response::conflict(Some(
+                "The id given to the business was already taken, try again",
+            ))),
             Err(e) => Err(e),
         }
     }
 
+    // The body is read while the rows are written, the report comes once all of it is in
+    async fn handle_import_businesses(
+        &self,
+        req: &Request,
+        connections: &mut DBConnections,
+    ) -> Result<Response> {
+        let (Some(format), Some(body)) = (req.body_format(), req.body_stream.as_ref()) else {
+            return Ok(Response::bad_request(Some(
+                "Send the businesses as NDJSON (application/x-ndjson) or CSV (text/csv)",
+            )));
+        };
+        if body.is_empty() {
+            return Ok(Response::bad_request(Some(
+                "Content-Length is required and the body cannot be empty",
+            )));
+        }
+        let report = import::import(connections, body.reader()?, format).await?;
+        Ok(Response::success(serde_json::to_value(report)?, None))
+    }
+
     async fn handle_update_business(
         &self,
         req: &Request,
@@ -185,14 +277,73 @@ impl<'a> Router<'a> {
         if req.body.is_none() {
             return Ok(Response::bad_request(Some("Missing data for update")));
         }
-        let data = req.body.as_ref().unwrap();
-        let serialized = BusinessData::from_value(data);
+        let data = match validation::parse_business(req.body.as_ref().unwrap()) {
+            Ok(data) => data,
+            Err(errors) => return Ok(Response::unprocessable(&errors)),
+        };
+        match dbs::update_business_by_id(connections, id, data).await {
+            Ok(true) => Ok(Response::default()),
+            Ok(false) => Ok(Response::not_found(Some("Business does not exist"))),
+            Err(e) => Err(e),
+        }
+    }
 
-        if serialized.is_err() {
-            return Ok(Response::bad_request(Some("Invalid data for update")));
+    // Only the fields in the body change, the rest is taken from Mongo. The merged record is
+    // validated like a PUT body, except for details an older record never had
+    async fn handle_patch_business(
+        &self,
+        req: &Request,
+        connections: &mut DBConnections,
+    ) -> Result<Response> {
+        let id = req.params.get("id").unwrap().parse()?;
+        let changes = match req.body.as_ref() {
+            Some(Value::Object(changes)) => changes,
+            _ => return Ok(Response::bad_request(Some("Missing data for update"))),
+        };
+        if changes.contains_key("id") {
+            return Ok(Response::bad_request(Some("id cannot be changed")));
+        }
+        let current = match dbs::get_business_by_id_mongo(&connections.mongo, id).await? {
+            Some(current) => current,
+            None => return Ok(Response::not_found(Some("Business does not exist"))),
+        };
+
+        let merged = merge_patch(current, changes)?;
+        let data = match validation::parse_business_patch(&merged, changes) {
+            Ok(data) => data,
+            Err(errors) => return Ok(Response::unprocessable(&errors)),
+        };
+        match dbs::update_business_by_id(connections, id, data).await {
+            Ok(true) => Ok(Response::default()),
+            Ok(false) => Ok(Response::not_found(Some("Business does not exist"))),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Hard or soft depending on DELETE_MODE, see config.rs
+    async fn handle_delete_business(
+        &self,
+        req: &Request,
+        connections: &mut DBConnections,
+    ) -> Result<Response> {
+        let id = req.params.get("id").unwrap().parse()?;
+        let soft = connections.soft_delete;
+        match dbs::delete_business_by_id(connections, id, soft).await {
+            Ok(true) => Ok(Response::default()),
+            Ok(false) => Ok(Response::not_found(Some("Business does not exist"))),
+            Err(e) => Err(e),
         }
-        match BusinessData::update_business_by_id(connections, id, serialized.unwrap()).await {
-            Ok(_) => Ok(Response::default()),
+    }
+
+    async fn handle_restore_business(
+        &self,
+        req: &Request,
+        connections: &mut DBConnections,
+    ) -> Result<Response> {
+        let id = req.params.get("id").unwrap().parse()?;
+        match dbs::restore_business_by_id(connections, id).await {
+            Ok(true) => Ok(Response::default()),
+            Ok(false) => Ok(Response::not_found(Some("No deleted business to restore"))),
             Err(e) => Err(e),
         }
     }
@@ -244,6 +395,9 @@ impl<'a> Router<'a> {
     }
 }
 fn compare_paths(requested: &VecDeque<&str>, existing: &VecDeque<&str>) -> bool {
+    if requested.len() != existing.len() {
+        return false;
+    }
     for (ind, item) in requested.iter().enumerate() {
         let current_requested = *item;
         let current_existing = existing[ind];
@@ -257,3 +411,152 @@ fn compare_paths(requested: &VecDeque<&str>, existing: &VecDeque<&str>) -> bool
 
     true
 }
+
+// The stored business with the changes of a PATCH on top. Hours and a timezone the api derived
+// from the old opening times and position are derived again from the new ones, those a client
+// set are kept
+fn merge_patch(current: BusinessData, changes: &Map<String, Value>) -> Result<Value> {
+    let derived_hours = current.has_derived_hours();
+    let derived_timezone = current.has_derived_timezone();
+    let mut merged = match serde_json::to_value(current)? {
+        Value::Object(merged) => merged,
+        _ => return Err("Business is not a json object".into()),
+    };
+    if !changes.contains_key("hours") {
+        let retimed = changes.contains_key("opensAt") || changes.contains_key("closesAt");
+        let moved = changes.contains_key("lon") || changes.contains_key("lat");
+        if retimed && derived_hours {
+            merged.remove("hours");
+        } else if moved && derived_timezone {
+            if let Some(Value::Object(hours)) = merged.get_mut("hours") {
+                hours.remove("timezone");
+                hours.remove("derivedTimezone");
+            }
+        }
+    }
+    merged.extend(changes.clone());
+    Ok(Value::Object(merged))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::hours::OpeningHours;
+
+    // as it is stored, with the hours the api derived or was given
+    fn stored(hours: Option<Value>) -> BusinessData {
+        let mut business = json!({
+            "id": 7,
+            "name": "Café Nord",
+            "type": "cafe",
+            "zipCode": "10115",
+            "description": "Coffee and cake",
+            "email": "nord@example.com",
+            "phone": "+49 30 1234567",
+            "stars": 4,
+            "averagePrice": 12,
+            "opensAt": 9,
+            "closesAt": 17,
+            "lat": 52.53,
+            "lon": 13.38
+        });
+        if let Some(hours) = hours {
+            business["hours"] = hours;
+        }
+        let mut data: BusinessData = serde_json::from_value(business).unwrap();
+        data.hours = Some(data.opening_hours());
+        data
+    }
+
+    fn patch(current: BusinessData, changes: Value) -> BusinessData {
+        let merged = merge_patch(current, changes.as_object().unwrap()).unwrap();
+        let mut data =
+            validation::parse_business_patch(&merged, changes.as_object().unwrap()).unwrap();
+        data.hours = Some(data.opening_hours());
+        data
+    }
+
+    fn custom_hours() -> Value {
+        json!({
+            "timezone": "Europe/Lisbon",
+            "weekly": {
+                "mon": [{"opens": "08:00", "closes": "12:00"}, {"opens": "14:00", "closes": "18:00"}]
+            },
+            "exceptions": [{"date": "2024-12-25"}]
+        })
+    }
+
+    #[test]
+    fn patching_closes_at_keeps_custom_hours() {
+        let current = stored(Some(custom_hours()));
+        let expected = current.hours.clone();
+        let data = patch(current, json!({"closesAt": 20}));
+        assert_eq!(data.closes_at, 20);
+        assert_eq!(data.hours, expected);
+    }
+
+    #[test]
+    fn patching_closes_at_derives_derived_hours_again() {
+        let data = patch(stored(None), json!({"closesAt": 20}));
+        let hours = data.hours.unwrap();
+        assert!(hours.derived);
+        assert_eq!(hours.weekly, OpeningHours::from_legacy(9, 20).weekly);
+        assert_eq!(hours.timezone.as_deref(), Some("Europe/Berlin"));
+    }
+
+    #[test]
+    fn moving_keeps_a_timezone_the_client_set() {
+        let data = patch(
+            stored(Some(custom_hours())),
+            json!({"lon": -74.0, "lat": 40.7}),
+        );
+        assert_eq!(
+            data.hours.unwrap().timezone.as_deref(),
+            Some("Europe/Lisbon")
+        );
+    }
+
+    #[test]
+    fn moving_looks_up_a_derived_timezone_again() {
+        let mut hours = custom_hours();
+        hours.as_object_mut().unwrap().remove("timezone");
+        let current = stored(Some(hours));
+        assert_eq!(
+            current.hours.as_ref().unwrap().timezone.as_deref(),
+            Some("Europe/Berlin")
+        );
+        let data = patch(current, json!({"lon": -74.0, "lat": 40.7}));
+        let hours = data.hours.unwrap();
+        assert_eq!(hours.timezone.as_deref(), Some("America/New_York"));
+        assert!(!hours.derived);
+        assert_eq!(hours.exceptions.len(), 1);
+
+        let data = patch(stored(None), json!({"lon": -74.0, "lat": 40.7}));
+        assert_eq!(
+            data.hours.unwrap().timezone.as_deref(),
+            Some("America/New_York")
+        );
+    }
+
+    #[test]
+    fn patching_a_business_without_details() {
+        let mut current = stored(None);
+        current.zip_code.clear();
+        current.email.clear();
+        current.phone.clear();
+        let data = patch(current, json!({"stars": 5}));
+        assert_eq!(data.stars, 5);
+        assert_eq!(data.phone, "");
+    }
+
+    #[test]
+    fn hours_in_the_patch_replace_the_stored_ones() {
+        let data = patch(
+            stored(None),
+            json!({"closesAt": 20, "hours": custom_hours()}),
+        );
+        let hours = data.hours.unwrap();
+        assert!(!hours.derived);
+        assert_eq!(hours.timezone.as_deref(), Some("Europe/Lisbon"));
+    }
+}
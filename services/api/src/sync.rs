@@ -0,0 +1,213 @@
+use crate::dbs::{self, DBConnections, MongoDb};
+use crate::{config::ServerConfig, response::Result};
+use crate::{outbox, reconcile};
+use bson::oid::ObjectId;
+use bson::{doc, DateTime, Document};
+use futures_util::StreamExt;
+use mongodb::change_stream::event::{ChangeStreamEvent, OperationType, ResumeToken};
+use mongodb::change_stream::ChangeStream;
+use mongodb::error::ErrorKind;
+use mongodb::options::{
+    ChangeStreamOptions, FindOptions, FullDocumentBeforeChangeType, FullDocumentType, UpdateOptions,
+};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+// Document of sync_state that holds the resume token of this stream
+const STREAM_NAME: &str = "businesses";
+// The oplog no longer goes back to the resume token
+const CHANGE_STREAM_HISTORY_LOST: i32 = 286;
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+// Business id of every document of main.businesses by its _id. A delete whose event has no
+// pre-image, e.g. because it expired, is only told apart this way. A few dozen bytes per business
+type BusinessIds = HashMap<ObjectId, i64>;
+
+// Follows the change stream of main.businesses and brings Redis in line with every change,
+// whoever made it: the api, a migration or someone in the Mongo shell. Changes made by the api
+// are then applied twice, which is harmless, see outbox.rs. The resume token of the last applied
+// change is kept in Mongo, so a restart carries on where the last run stopped
+pub async fn run(config: &ServerConfig) -> Result<()> {
+    let mut dbs = DBConnections::init(config).await?;
+    dbs.mongo.enable_change_pre_images().await.map_err(|e| {
+        format!(
+            "Could not enable change stream pre-images, which tell deletes apart (Mongo 6 or later): {}",
+            e
+        )
+    })?;
+    loop {
+        if let Err(e) = follow_changes(&mut dbs).await {
+            println!("Change stream failed, restarting: {:?}", e);
+        }
+        tokio::time::sleep(RESTART_DELAY).await;
+    }
+}
+
+async fn follow_changes(dbs: &mut DBConnections) -> Result<()> {
+    let token = load_token(&dbs.mongo).await?;
+    let resuming = token.is_some();
+    let mut stream = match watch(&dbs.mongo, token).await {
+        Ok(stream) => stream,
+        Err(e) if resuming && is_history_lost(&e) => {
+            // the changes in between are only found by comparing all of Redis with Mongo. The
+            // new stream is opened first, so that nothing changed during the repair is missed,
+            // and the old token is kept until the repair is done, so a failed one runs again
+            println!(
+                "Changes since the last run are gone from the oplog, repairing Redis from Mongo"
+            );
+            let stream = watch(&dbs.mongo, None).await?;
+            reconcile::repair(dbs).await?;
+            clear_token(&dbs.mongo).await?;
+            stream
+        }
+        Err(e) => return Err(e.into()),
+    };
+    // loaded once the stream is open, so that every later change shows up in the events
+    let mut known = load_business_ids(&dbs.mongo).await?;
+    println!(
+        "Following changes of main.businesses{}",
+        if resuming { " from the last run" } else { "" }
+    );
+
+    while let Some(event) = stream.next().await {
+        let event = event?;
+        match event.operation_type {
+            OperationType::Insert
+            | OperationType::Update
+            | OperationType::Replace
+            | OperationType::Delete => apply_change(dbs, &mut known, &event).await?,
+            // the collection is gone, the stream ends after this and can't be resumed
+            OperationType::Drop | OperationType::Rename | OperationType::DropDatabase => {
+                println!("main.businesses was dropped or renamed, Redis is left as it is");
+            }
+            OperationType::Invalidate => {
+                clear_token(&dbs.mongo).await?;
+                return Ok(());
+            }
+            _ => {}
+        }
+        save_token(&dbs.mongo, &event.id).await?;
+    }
+    Ok(())
+}
+
+async fn watch(
+    mongo: &MongoDb,
+    token: Option<ResumeToken>,
+) -> mongodb::error::Result<ChangeStream<ChangeStreamEvent<Document>>> {
+    let options = ChangeStreamOptions::builder()
+        .full_document(Some(FullDocumentType::UpdateLookup))
+        .full_document_before_change(Some(FullDocumentBeforeChangeType::WhenAvailable))
+        .resume_after(token)
+        .build();
+    mongo
+        .get_businesses_collection()
+        .clone_with_type::<Document>()
+        .watch(None, options)
+        .await
+}
+
+// The business before and after the change can have different ids, when a migration renumbered
+// it, and both have to be synced. The id the document had before comes from the pre-image, or
+// from `known` when there is none
+async fn apply_change(
+    dbs: &mut DBConnections,
+    known: &mut BusinessIds,
+    event: &ChangeStreamEvent<Document>,
+) -> Result<()> {
+    let before = event.full_document_before_change.as_ref();
+    let after = event.full_document.as_ref();
+    let mut ids: HashSet<i64> = [before, after]
+        .into_iter()
+        .flatten()
+        .filter_map(dbs::id_of)
+        .collect();
+    let key = event
+        .document_key
+        .as_ref()
+        .and_then(|key| key.get_object_id("_id").ok());
+    if let Some(key) = key {
+        let previous = match after.and_then(dbs::id_of) {
+            Some(id) => known.insert(key, id),
+            None => known.remove(&key),
+        };
+        ids.extend(previous);
+    }
+    if ids.is_empty() {
+        if event.operation_type == OperationType::Delete {
+            // deleted after the last saved change and before this run started, so neither the
+            // event nor Mongo still tell which business it was. Every business gone from Mongo
+            // is removed from Redis instead
+            println!(
+                "Delete of document {:?} names no business, removing every orphan from Redis",
+                event.document_key
+            );
+            let removed = reconcile::remove_orphans(dbs).await?;
+            println!("Removed {} orphaned entries", removed);
+        } else {
+            println!(
+                "Change {:?} of document {:?} names no business, skipped",
+                event.operation_type, event.document_key
+            );
+        }
+        return Ok(());
+    }
+    let previous = before.and_then(dbs::position_of);
+    for id in ids {
+        outbox::sync_business(dbs, id as u64, previous).await?;
+    }
+    Ok(())
+}
+
+async fn load_business_ids(mongo: &MongoDb) -> Result<BusinessIds> {
+    let options = FindOptions::builder()
+        .projection(doc! {"_id": 1, "id": 1})
+        .build();
+    let mut cursor = mongo
+        .get_businesses_collection()
+        .clone_with_type::<Document>()
+        .find(None, options)
+        .await?;
+    let mut known = BusinessIds::new();
+    while cursor.advance().await? {
+        let document = cursor.deserialize_current()?;
+        if let (Ok(key), Some(id)) = (document.get_object_id("_id"), dbs::id_of(&document)) {
+            known.insert(key, id);
+        }
+    }
+    Ok(known)
+}
+
+fn is_history_lost(error: &mongodb::error::Error) -> bool {
+    matches!(error.kind.as_ref(), ErrorKind::Command(e) if e.code == CHANGE_STREAM_HISTORY_LOST)
+}
+
+async fn load_token(mongo: &MongoDb) -> Result<Option<ResumeToken>> {
+    let state = mongo
+        .get_sync_state_collection()
+        .find_one(doc! {"_id": STREAM_NAME}, None)
+        .await?;
+    match state.as_ref().and_then(|state| state.get("resumeToken")) {
+        Some(token) => Ok(Some(bson::from_bson(token.clone())?)),
+        None => Ok(None),
+    }
+}
+
+async fn save_token(mongo: &MongoDb, token: &ResumeToken) -> Result<()> {
+    let update =
+        doc! {"$set": {"resumeToken": bson::to_bson(token)?, "updatedAt": DateTime::now()}};
+    let options = UpdateOptions::builder().upsert(true).build();
+    mongo
+        .get_sync_state_collection()
+        .update_one(doc! {"_id": STREAM_NAME}, update, options)
+        .await?;
+    Ok(())
+}
+
+async fn clear_token(mongo: &MongoDb) -> Result<()> {
+    mongo
+        .get_sync_state_collection()
+        .delete_one(doc! {"_id": STREAM_NAME}, None)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use crate::dbs::{self, DBConnections, MongoDb, Result};
+use crate::fields;
+use crate::params;
+use model::business::BusinessSummary;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+pub const MAX_QUERIES: usize = 500;
+
+// One search of `POST /search/batch`, with the filters it can take. Lon and lat are required,
+// radius defaults to 500 meters like on /search
+pub struct BatchQuery {
+    lon: f64,
+    lat: f64,
+    radius: f64,
+    open_now: bool,
+    r#type: Option<String>,
+    min_stars: Option<u8>,
+}
+
+impl BatchQuery {
+    // An invalid query becomes the error of its own result, the rest of the batch still runs
+    pub fn parse(value: &Value, max_radius: f64) -> std::result::Result<BatchQuery, String> {
+        if !value.is_object() {
+            return Err("Query must be an object".to_string());
+        }
+        let lat = number(value, "lat")?.ok_or("lat was not specified")?;
+        let lon = number(value, "lon")?.ok_or("lon was not specified")?;
+        let point = params::check_point(lon, lat)?;
+        let radius = params::check_radius(number(value, "radius")?.unwrap_or(500.0), max_radius)?;
+        let open_now = match value.get("openNow") {
+            None | Some(Value::Null) => false,
+            Some(Value::Bool(open_now)) => *open_now,
+            Some(_) => return Err("openNow must be true or false".to_string()),
+        };
+        let r#type = match value.get("type") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(r#type)) => Some(r#type.clone()),
+            Some(_) => return Err("type must be a string".to_string()),
+        };
+        let min_stars = match number(value, "minStars")? {
+            None => None,
+            Some(stars) if (0.0..=5.0).contains(&stars) => Some(stars as u8),
+            Some(_) => return Err("minStars must be between 0 and 5".to_string()),
+        };
+
+        Ok(BatchQuery {
+            lon: point.lon,
+            lat: point.lat,
+            radius,
+            open_now,
+            r#type,
+            min_stars,
+        })
+    }
+
+    fn matches(&self, business: &BusinessSummary, open_now: bool) -> bool {
+        (!self.open_now || open_now)
+            && self.r#type.iter().all(|t| *t == business.r#type)
+            && self.min_stars.iter().all(|stars| business.stars >= *stars)
+    }
+}
+
+// Every search runs in one go through the geo index, and a business found by several of them is
+// read once. Results are in the order of the queries
+pub async fn search(
+    conns: &mut DBConnections,
+    queries: &[std::result::Result<BatchQuery, String>],
+) -> Result<Vec<Value>> {
+    let searches: Vec<(f64, f64, f64)> = queries
+        .iter()
+        .flatten()
+        .map(|query| (query.lon, query.lat, query.radius))
+        .collect();
+    let found = conns.geo_index.radius_many(&searches).await?;
+
+    let mut seen = HashSet::new();
+    let ids: Vec<String> = found
+        .iter()
+        .flatten()
+        .filter(|point| seen.insert(point.id))
+        .map(|point| point.id.to_string())
+        .collect();
+    let businesses: HashMap<u64, (BusinessSummary, bool)> = dbs::hydrate(conns, &ids)
+        .await?
+        .into_iter()
+        .filter_map(|business| {
+            let open_now = business.opening_hours().is_open_now();
+            Some((business.id?, (business, open_now)))
+        })
+        .collect();
+
+    let mut found = found.into_iter();
+    Ok(queries
+        .iter()
+        .map(|query| match query {
+            Ok(query) => {
+                let points = found.next().unwrap_or_default();
+                let selected: Vec<Value> = points
+                    .iter()
+                    .filter_map(|point| businesses.get(&point.id))
+                    .filter(|(business, open_now)| query.matches(business, *open_now))
+                    .map(|(business, open_now)| fields::to_json(business, None, *open_now))
+                    .collect();
+                json!({ "businesses": selected })
+            }
+            Err(message) => json!({ "error": message }),
+        })
+        .collect())
+}
+
+// Same results straight from Mongo, one query at a time, for when Redis can't answer
+pub async fn search_mongo(
+    mongo: &MongoDb,
+    queries: &[std::result::Result<BatchQuery, String>],
+) -> Result<Vec<Value>> {
+    let mut results = Vec::with_capacity(queries.len());
+    for query in queries {
+        let query = match query {
+            Ok(query) => query,
+            Err(message) => {
+                results.push(json!({ "error": message }));
+                continue;
+            }
+        };
+        let businesses: Vec<Value> = mongo
+            .find_near(query.lon, query.lat, Some(query.radius), None)
+            .await?
+            .iter()
+            .map(|business| (business.summary(), business.opening_hours().is_open_now()))
+            .filter(|(business, open_now)| query.matches(business, *open_now))
+            .map(|(business, open_now)| fields::to_json(&business, None, open_now))
+            .collect();
+        results.push(json!({ "businesses": businesses }));
+    }
+    Ok(results)
+}
+
+fn number(value: &Value, name: &str) -> std::result::Result<Option<f64>, String> {
+    match value.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(number) => match number.as_f64() {
+            Some(number) if number.is_finite() => Ok(Some(number)),
+            _ => Err(format!("{} must be a number", name)),
+        },
+    }
+}
@@ -0,0 +1,126 @@
+use crate::dbs::Result;
+use crate::events::ChangeSubscriber;
+use async_trait::async_trait;
+use lru::LruCache;
+use model::business::BusinessSummary;
+use serde_json::{json, Value};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+
+pub type SharedBusinessCache = Arc<Mutex<BusinessCache>>;
+
+// Bounded in-process copy of business hashes, so that popular businesses are not read from
+// redis-business on every search. Limited both by number of entries and by approximate size
+pub struct BusinessCache {
+    entries: LruCache<u64, (BusinessSummary, usize)>,
+    max_bytes: usize,
+    used_bytes: usize,
+    pub stats: BusinessCacheStats,
+}
+
+#[derive(Default)]
+pub struct BusinessCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub invalidations: u64,
+}
+
+impl BusinessCache {
+    pub fn new(max_entries: NonZeroUsize, max_bytes: usize) -> BusinessCache {
+        BusinessCache {
+            entries: LruCache::new(max_entries),
+            max_bytes,
+            used_bytes: 0,
+            stats: BusinessCacheStats::default(),
+        }
+    }
+
+    pub fn get(&mut self, id: u64) -> Option<BusinessSummary> {
+        match self.entries.get(&id) {
+            Some((data, _)) => {
+                self.stats.hits += 1;
+                Some(data.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, id: u64, data: BusinessSummary) {
+        let size = approximate_size(&data);
+        if size > self.max_bytes {
+            return;
+        }
+        if let Some((evicted_id, (_, evicted_size))) = self.entries.push(id, (data, size)) {
+            self.used_bytes -= evicted_size;
+            if evicted_id != id {
+                self.stats.evictions += 1;
+            }
+        }
+        self.used_bytes += size;
+
+        while self.used_bytes > self.max_bytes {
+            match self.entries.pop_lru() {
+                Some((_, (_, evicted_size))) => {
+                    self.used_bytes -= evicted_size;
+                    self.stats.evictions += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        if let Some((_, size)) = self.entries.pop(&id) {
+            self.used_bytes -= size;
+            self.stats.invalidations += 1;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.used_bytes = 0;
+    }
+
+    pub fn to_json(&self) -> Value {
+        let lookups = self.stats.hits + self.stats.misses;
+        let hit_rate = if lookups == 0 {
+            0.0
+        } else {
+            self.stats.hits as f64 / lookups as f64
+        };
+        json!({
+            "entries": self.entries.len(),
+            "maxEntries": self.entries.cap().get(),
+            "usedBytes": self.used_bytes,
+            "maxBytes": self.max_bytes,
+            "hits": self.stats.hits,
+            "misses": self.stats.misses,
+            "hitRate": hit_rate,
+            "evictions": self.stats.evictions,
+            "invalidations": self.stats.invalidations
+        })
+    }
+}
+
+// Serialized size plus the struct itself is close enough to what the entry holds on to
+fn approximate_size(data: &BusinessSummary) -> usize {
+    let serialized = serde_json::to_vec(data).map(|v| v.len()).unwrap_or(0);
+    std::mem::size_of::<BusinessSummary>() + serialized
+}
+
+// Changed businesses are dropped from the cache, the whole cache is dropped after (re)subscribing
+#[async_trait]
+impl ChangeSubscriber for SharedBusinessCache {
+    async fn on_change(&mut self, id: u64) -> Result<()> {
+        self.lock().unwrap().remove(id);
+        Ok(())
+    }
+    async fn on_resync(&mut self) -> Result<()> {
+        self.lock().unwrap().clear();
+        Ok(())
+    }
+}
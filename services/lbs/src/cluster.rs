@@ -0,0 +1,207 @@
+use crate::dbs::{DBConnections, Result};
+use crate::geo_index::{GeoPoint, Viewport};
+use model::geohash;
+use redis::Pipeline;
+use serde::Serialize;
+use std::collections::HashMap;
+
+pub const MAX_ZOOM: u8 = 22;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClusterPoint {
+    pub id: u64,
+    pub name: String,
+    pub r#type: String,
+    pub stars: u8,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Cluster {
+    pub cell: String,
+    pub count: usize,
+    pub lat: f64,
+    pub lon: f64,
+    pub bounds: [f64; 4],
+    pub types: HashMap<String, usize>,
+    pub representative: ClusterPoint,
+}
+
+// One geohash character is worth roughly 2.5 zoom levels, which keeps cells at a few dozen
+// pixels on screen regardless of zoom
+pub fn precision_for_zoom(zoom: u8) -> usize {
+    ((zoom.min(MAX_ZOOM) as usize + 3) * 2 / 5).clamp(1, 9)
+}
+
+// Above this many businesses in the viewport a clustering reads the details of the
+// representatives only, one per cluster, instead of those of every business
+pub const MAX_DETAILED_POINTS: usize = 2000;
+
+// Positions read for one clustering at most. A viewport with more businesses is clustered out of
+// the ones closest to its center, and said to be truncated
+pub const MAX_VIEWPORT_POINTS: usize = 50_000;
+
+// The clusters of the viewport, the number of businesses they are made of and whether the
+// viewport had more than MAX_VIEWPORT_POINTS
+pub async fn find_clusters(
+    conns: &mut DBConnections,
+    viewport: &Viewport,
+    precision: usize,
+) -> Result<(usize, bool, Vec<Cluster>)> {
+    let (found, truncated) = conns
+        .geo_index
+        .within_box(viewport, MAX_VIEWPORT_POINTS)
+        .await?;
+    let total = found.len();
+    let clusters = cluster_positions(conns, found, precision).await?;
+    Ok((total, truncated, clusters))
+}
+
+// Up to MAX_DETAILED_POINTS businesses every one is read, the best rated represents its cluster
+// and `types` counts them. Past that the clusters are made of the positions the geo index
+// returned, the business closest to the middle represents each and `types` stays empty
+pub async fn cluster_positions(
+    conns: &mut DBConnections,
+    found: Vec<GeoPoint>,
+    precision: usize,
+) -> Result<Vec<Cluster>> {
+    if found.len() <= MAX_DETAILED_POINTS {
+        let points = read_details(conns, found).await?;
+        return Ok(group_points(points, precision));
+    }
+
+    let mut cells: HashMap<String, Vec<GeoPoint>> = HashMap::default();
+    for point in found {
+        let cell = geohash::encode(point.lon, point.lat, precision);
+        cells.entry(cell).or_default().push(point);
+    }
+    let mut groups = Vec::with_capacity(cells.len());
+    let mut representatives = Vec::with_capacity(cells.len());
+    for (cell, members) in cells {
+        let (lon, lat) = centroid(members.iter().map(|m| (m.lon, m.lat)));
+        let closest = members
+            .iter()
+            .min_by(|a, b| {
+                geohash::distance(a.lon, a.lat, lon, lat)
+                    .total_cmp(&geohash::distance(b.lon, b.lat, lon, lat))
+            })
+            .unwrap()
+            .clone();
+        groups.push((cell, members.len(), lon, lat));
+        representatives.push(closest);
+    }
+    let representatives = read_details(conns, representatives).await?;
+
+    let mut clusters: Vec<Cluster> = groups
+        .into_iter()
+        .zip(representatives)
+        .map(|((cell, count, lon, lat), representative)| Cluster {
+            bounds: cell_bounds(&cell, lon, lat),
+            cell,
+            count,
+            lat,
+            lon,
+            types: HashMap::default(),
+            representative,
+        })
+        .collect();
+    sort_clusters(&mut clusters);
+    Ok(clusters)
+}
+
+pub async fn read_details(
+    conns: &mut DBConnections,
+    found: Vec<GeoPoint>,
+) -> Result<Vec<ClusterPoint>> {
+    if found.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut pipe = Pipeline::new();
+    for point in &found {
+        pipe.hget(point.id, &["name", "type", "stars"]);
+    }
+    let details: Vec<(Option<String>, Option<String>, Option<String>)> =
+        conns.redis_business.read_pipe(&pipe).await?;
+
+    let mut points = Vec::with_capacity(found.len());
+    for (point, (name, r#type, stars)) in found.into_iter().zip(details) {
+        points.push(ClusterPoint {
+            id: point.id,
+            name: name.unwrap_or("null".to_string()),
+            r#type: r#type.unwrap_or("null".to_string()),
+            stars: stars.and_then(|s| s.parse::<u8>().ok()).unwrap_or(0),
+            lat: point.lat,
+            lon: point.lon,
+        });
+    }
+
+    Ok(points)
+}
+
+pub fn group_points(points: Vec<ClusterPoint>, precision: usize) -> Vec<Cluster> {
+    let mut cells: HashMap<String, Vec<ClusterPoint>> = HashMap::default();
+    for point in points {
+        let cell = geohash::encode(point.lon, point.lat, precision);
+        cells.entry(cell).or_default().push(point);
+    }
+
+    let mut clusters: Vec<Cluster> = cells
+        .into_iter()
+        .map(|(cell, members)| build_cluster(cell, members))
+        .collect();
+    sort_clusters(&mut clusters);
+    clusters
+}
+
+fn sort_clusters(clusters: &mut [Cluster]) {
+    clusters.sort_by(|a, b| b.count.cmp(&a.count).then(a.cell.cmp(&b.cell)));
+}
+
+fn centroid(positions: impl ExactSizeIterator<Item = (f64, f64)>) -> (f64, f64) {
+    let count = positions.len() as f64;
+    let (lon, lat) = positions.fold((0.0, 0.0), |(lon, lat), p| (lon + p.0, lat + p.1));
+    (lon / count, lat / count)
+}
+
+fn cell_bounds(cell: &str, lon: f64, lat: f64) -> [f64; 4] {
+    match geohash::decode(cell) {
+        Some(c) => [c.min_lon, c.min_lat, c.max_lon, c.max_lat],
+        None => [lon, lat, lon, lat],
+    }
+}
+
+fn build_cluster(cell: String, members: Vec<ClusterPoint>) -> Cluster {
+    let count = members.len();
+    let (lon, lat) = centroid(members.iter().map(|m| (m.lon, m.lat)));
+
+    let mut types: HashMap<String, usize> = HashMap::default();
+    for member in &members {
+        *types.entry(member.r#type.clone()).or_default() += 1;
+    }
+
+    // best rated business represents the cluster, the one closest to the centroid wins a tie
+    let representative = members
+        .iter()
+        .min_by(|a, b| {
+            b.stars.cmp(&a.stars).then(
+                geohash::distance(a.lon, a.lat, lon, lat)
+                    .total_cmp(&geohash::distance(b.lon, b.lat, lon, lat)),
+            )
+        })
+        .unwrap()
+        .clone();
+
+    Cluster {
+        bounds: cell_bounds(&cell, lon, lat),
+        cell,
+        count,
+        lat,
+        lon,
+        types,
+        representative,
+    }
+}
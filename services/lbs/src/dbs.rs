@@ -1,142 +1,232 @@
+use crate::business_cache::{BusinessCache, SharedBusinessCache};
 use crate::config::ServerConfig;
-use redis::{FromRedisValue, RedisError, Value};
-use serde::{Deserialize, Serialize};
+use crate::events::spawn_change_listener;
+use crate::geo_index::{GeoIndex, MemoryGeoIndex, MemoryGeoIndexUpdater, RedisGeoIndex};
+use crate::redis_db::RedisDB;
+use crate::search_cache::SearchCache;
+use bson::{doc, Document};
+use futures_util::TryStreamExt;
+use model::business::{BusinessData, BusinessSummary, DELETED_AT_FIELD, SUMMARY_FIELDS};
+use mongodb::options::{FindOneOptions, FindOptions};
+use mongodb::{Client, Collection};
+use redis::Pipeline;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
 use std::{collections::HashMap, error::Error};
 pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
 pub struct DBConnections {
     pub redis_business: RedisDB,
-    pub redis_geo: RedisDB,
+    pub redis_geo: Option<RedisDB>,
+    pub geo_index: Box<dyn GeoIndex>,
+    pub search_cache: SearchCache,
+    // meters, from MAX_SEARCH_RADIUS
+    pub max_radius: f64,
+    pub business_cache: Option<SharedBusinessCache>,
+    // only with GEO_FALLBACK=mongo
+    pub mongo_fallback: Option<MongoDb>,
 }
-#[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct BusinessData {
-    pub id: Option<u64>,
-    pub name: String,
-    pub stars: u8,
-    pub r#type: String,
-    pub lat: f32,
-    pub lon: f32,
-    pub opens_at: u8,
-    pub closes_at: u8,
+// Businesses within the radius, nearest first
+pub async fn find_in_radius(
+    conns: &mut DBConnections,
+    lon: f64,
+    lat: f64,
+    radius: f64,
+) -> Result<Vec<BusinessSummary>> {
+    let ids: Vec<String> = conns
+        .geo_index
+        .radius(lon, lat, radius)
+        .await?
+        .into_iter()
+        .map(|point| point.id.to_string())
+        .collect();
+    hydrate(conns, &ids).await
 }
 
-impl BusinessData {
-    pub fn default() -> BusinessData {
-        BusinessData {
-            id: None,
-            name: "null".to_string(),
-            stars: 0,
-            r#type: "null".to_string(),
-            lat: 0.0,
-            lon: 0.0,
-            opens_at: 0,
-            closes_at: 0,
+// Reads the summary of businesses by id keeping the order of ids. Ids without a hash are skipped
+pub async fn hydrate(conns: &mut DBConnections, ids: &[String]) -> Result<Vec<BusinessSummary>> {
+    let mut found: Vec<Option<BusinessSummary>> = vec![None; ids.len()];
+    let mut missing: Vec<usize> = vec![];
+    for (i, id) in ids.iter().enumerate() {
+        let cached = match &conns.business_cache {
+            Some(cache) => cache.lock().unwrap().get(id.parse::<u64>()?),
+            None => None,
+        };
+        match cached {
+            Some(data) => found[i] = Some(data),
+            None => missing.push(i),
         }
     }
-    pub fn from_hashmap(map: HashMap<String, String>) -> Result<BusinessData> {
-        if map.is_empty() {
-            return Err("Hashmap cannot be empty".into());
-        }
-        let mut data = BusinessData::default();
-
-        for (key, value) in map.iter() {
-            let value = value.to_owned();
-            match key.as_str() {
-                "name" => data.name = value,
-                "lon" => data.lon = value.parse::<f32>()?,
-                "lat" => data.lat = value.parse::<f32>()?,
-                "stars" => data.stars = value.parse::<u8>()?,
-                "type" => data.r#type = value,
-                "opensAt" => data.opens_at = value.parse::<u8>()?,
-                "closesAt" => data.closes_at = value.parse::<u8>()?,
-                _ => continue,
-            }
-        }
-
-        Ok(data)
-    }
-    pub fn from_builk_redis(values: &[Value]) -> Result<BusinessData> {
-        let mut map: HashMap<String, String> = HashMap::default();
-        let mut key = "".to_string();
-        for (i, val) in values.iter().enumerate() {
-            let is_val = i % 2 != 0;
-            if is_val {
-                // in this case it would be actual value
-                let v = match val {
-                    redis::Value::Data(n) => {
-                        String::from_utf8(n.to_owned()).unwrap_or("null".to_string())
-                    }
-                    _ => "null".to_string(),
-                };
-                map.insert(key.to_owned(), v);
-            } else {
-                // in this case it is field key
-                key = match val {
-                    redis::Value::Data(n) => {
-                        String::from_utf8(n.to_owned()).unwrap_or("".to_string())
-                    }
-                    _ => "".to_string(),
-                }
-            }
-        }
-        let data = BusinessData::from_hashmap(map)?;
-        Ok(data)
+    if missing.is_empty() {
+        return Ok(found.into_iter().flatten().collect());
     }
-}
 
-impl FromRedisValue for BusinessData {
-    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
-        match v {
-            redis::Value::Nil => Ok(BusinessData::default()),
-            redis::Value::Bulk(values) => match BusinessData::from_builk_redis(values) {
-                Ok(data) => Ok(data),
-                Err(_) => Err(RedisError::from((
-                    redis::ErrorKind::TypeError,
-                    "Could not convert redis hash into BusinessData",
-                ))),
-            },
-            _ => Err(RedisError::from((
-                redis::ErrorKind::TypeError,
-                "Could not construct BusinessData from single value",
-            ))),
-        }
+    let mut pipe = Pipeline::new();
+    for i in &missing {
+        pipe.cmd("HMGET").arg(&ids[*i]).arg(&SUMMARY_FIELDS);
     }
-    fn from_redis_values(items: &[redis::Value]) -> redis::RedisResult<Vec<Self>> {
-        let mut result: Vec<BusinessData> = vec![];
-        for item in items {
-            match BusinessData::from_redis_value(item) {
-                Ok(data) => result.push(data),
-                Err(e) => {
-                    println!("Failed to convert value: {:?}", e)
-                }
+    let values: Vec<Vec<Option<String>>> = conns.redis_business.read_pipe(&pipe).await?;
+
+    for (i, values) in missing.into_iter().zip(values) {
+        let id = ids[i].parse::<u64>()?;
+        let hash: HashMap<String, String> = SUMMARY_FIELDS
+            .iter()
+            .zip(values)
+            .filter_map(|(field, value)| Some((field.to_string(), value?)))
+            .collect();
+        let data = match BusinessSummary::from_hash(id, hash) {
+            Ok(data) => data,
+            Err(e) => {
+                println!("Failed to convert business {}: {:?}", ids[i], e);
+                continue;
             }
+        };
+        if let Some(cache) = &conns.business_cache {
+            cache.lock().unwrap().insert(id, data.clone());
         }
-
-        Ok(result)
+        found[i] = Some(data);
     }
+
+    Ok(found.into_iter().flatten().collect())
 }
 
 impl DBConnections {
     pub async fn init(config: &ServerConfig) -> Result<DBConnections> {
-        let redis_business = RedisDB::connect(&config.redis_business).await?;
-        let redis_geo = RedisDB::connect(&config.redis_geo).await?;
+        let redis_business = RedisDB::connect(&config.redis_business, &config.replicas).await?;
+        let redis_geo = match &config.redis_geo {
+            Some(redis_geo) => Some(RedisDB::connect(redis_geo, &config.replicas).await?),
+            None => None,
+        };
+
+        let mongo = MongoDb::connect(&config.mongo).await?;
+        let mongo_fallback = match config.geo_fallback.as_str() {
+            "mongo" => Some(mongo.clone()),
+            "none" => None,
+            other => return Err(format!("Unsupported geo fallback: {}", other).into()),
+        };
+
+        let geo_index: Box<dyn GeoIndex> = match config.geo_index.as_str() {
+            "redis" => {
+                let nodes = match (&config.geo_shard_nodes, &redis_geo) {
+                    (Some(shard_nodes), _) => {
+                        let mut nodes = vec![];
+                        for node in shard_nodes {
+                            nodes.push(RedisDB::connect(node, &config.replicas).await?);
+                        }
+                        nodes
+                    }
+                    (None, Some(redis_geo)) => vec![redis_geo.clone()],
+                    (None, None) => return Err("The redis geo index needs REDIS_GEO_URI".into()),
+                };
+                Box::new(RedisGeoIndex::new(nodes, config.geo_shard_precision))
+            }
+            "memory" => {
+                let mongo = mongo.clone();
+                let index = MemoryGeoIndex::load(&mongo).await?;
+                spawn_change_listener(
+                    "Memory geo index",
+                    redis_business.clone(),
+                    MemoryGeoIndexUpdater {
+                        index: index.clone(),
+                        mongo,
+                    },
+                );
+                Box::new(index)
+            }
+            other => return Err(format!("Unsupported geo index: {}", other).into()),
+        };
+        println!("Using {} geo index", geo_index.name());
+
+        let business_cache = NonZeroUsize::new(config.business_cache_entries).map(|entries| {
+            let cache = Arc::new(Mutex::new(BusinessCache::new(
+                entries,
+                config.business_cache_max_bytes,
+            )));
+            spawn_change_listener("Business cache", redis_business.clone(), cache.clone());
+            cache
+        });
 
         Ok(DBConnections {
             redis_business,
             redis_geo,
+            geo_index,
+            search_cache: SearchCache::new(config.search_cache_ttl),
+            max_radius: config.max_search_radius,
+            business_cache,
+            mongo_fallback,
         })
     }
 }
-pub struct RedisDB {
-    pub connection: redis::aio::MultiplexedConnection,
+#[derive(Clone)]
+pub struct MongoDb {
+    client: Client,
+}
+
+// Soft deleted businesses stay in Mongo until they are restored, lbs never shows them
+fn not_deleted(mut filter: Document) -> Document {
+    filter.insert(DELETED_AT_FIELD, doc! {"$exists": false});
+    filter
 }
 
-impl RedisDB {
-    pub async fn connect(conn_str: &str) -> Result<RedisDB> {
-        let client = redis::Client::open(conn_str).unwrap();
-        let connection = client.get_multiplexed_async_connection().await.unwrap();
+impl MongoDb {
+    pub async fn connect(mongo_uri: &str) -> Result<MongoDb> {
+        let client = Client::with_uri_str(mongo_uri).await?;
+        Ok(MongoDb { client })
+    }
+    // Documents rather than BusinessData, most reads here only need the position
+    pub fn get_businesses_collection(&self) -> Collection<Document> {
+        self.client.database("main").collection("businesses")
+    }
+    pub async fn find_positions(&self) -> Result<mongodb::Cursor<Document>> {
+        let options = FindOptions::builder()
+            .projection(doc! {"id": 1, "lon": 1, "lat": 1})
+            .build();
+        Ok(self
+            .get_businesses_collection()
+            .find(not_deleted(doc! {}), options)
+            .await?)
+    }
+    pub async fn find_position(&self, id: u64) -> Result<Option<Document>> {
+        let options = FindOneOptions::builder()
+            .projection(doc! {"id": 1, "lon": 1, "lat": 1})
+            .build();
+        Ok(self
+            .get_businesses_collection()
+            .find_one(not_deleted(doc! {"id": id as i64}), options)
+            .await?)
+    }
+    // Full records nearest first, straight from the 2dsphere index on `location` that the api
+    // keeps. Slower than Redis, but it doesn't need Redis at all
+    pub async fn find_near(
+        &self,
+        lon: f64,
+        lat: f64,
+        max_distance: Option<f64>,
+        limit: Option<i64>,
+    ) -> Result<Vec<BusinessData>> {
+        let mut near = doc! {"$geometry": {"type": "Point", "coordinates": [lon, lat]}};
+        if let Some(max_distance) = max_distance {
+            near.insert("$maxDistance", max_distance);
+        }
+        let options = FindOptions::builder()
+            .projection(doc! {"_id": 0, "location": 0})
+            .limit(limit)
+            .build();
+        let mut cursor = self
+            .get_businesses_collection()
+            .find(
+                not_deleted(doc! {"location": {"$nearSphere": near}}),
+                options,
+            )
+            .await?;
 
-        Ok(RedisDB { connection })
+        let mut found = vec![];
+        while let Some(document) = cursor.try_next().await? {
+            match bson::from_document::<BusinessData>(document) {
+                Ok(data) => found.push(data),
+                Err(e) => println!("Failed to convert business document: {:?}", e),
+            }
+        }
+        Ok(found)
     }
 }
@@ -0,0 +1,52 @@
+use crate::dbs::Result;
+use crate::redis_db::RedisDB;
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use model::events::BUSINESS_CHANGED_CHANNEL;
+use std::time::Duration;
+
+// Anything in lbs that keeps its own copy of business data and has to follow writes made by the api
+#[async_trait]
+pub trait ChangeSubscriber: Send + 'static {
+    async fn on_change(&mut self, id: u64) -> Result<()>;
+    // Called after every (re)subscription, messages sent while we were not listening are lost
+    async fn on_resync(&mut self) -> Result<()>;
+}
+
+// Subscribes on the primary, which is looked up again on every reconnect in case it moved
+pub fn spawn_change_listener<S: ChangeSubscriber>(
+    name: &'static str,
+    redis: RedisDB,
+    mut subscriber: S,
+) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = listen_for_changes(&redis.primary_uri(), &mut subscriber).await {
+                println!("{} change listener failed: {:?}", name, e);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+}
+
+async fn listen_for_changes<S: ChangeSubscriber>(conn_str: &str, subscriber: &mut S) -> Result<()> {
+    let client = redis::Client::open(conn_str)?;
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(BUSINESS_CHANGED_CHANNEL).await?;
+    subscriber.on_resync().await?;
+
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let payload: String = message.get_payload()?;
+        match payload.parse::<u64>() {
+            Ok(id) => {
+                if let Err(e) = subscriber.on_change(id).await {
+                    println!("Failed to apply change of business {}: {:?}", id, e);
+                }
+            }
+            Err(_) => println!("Unexpected business change message: {}", payload),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use crate::dbs::Result;
+use crate::redis_db::RedisDB;
+use crate::request::Request;
+use model::business::{BusinessDetails, BusinessSummary, DETAIL_FIELDS, SUMMARY_FIELDS};
+use redis::Pipeline;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+// Fields a search returns. `detail=summary` (the default) or `detail=full`, or `fields=` with a
+// comma separated list. id and openNow always come back
+pub struct Projection {
+    fields: Vec<String>,
+}
+
+impl Projection {
+    pub fn from_request(req: &Request) -> std::result::Result<Projection, String> {
+        if let Some(fields) = req.query.get("fields") {
+            let fields: Vec<String> = fields
+                .split(',')
+                .map(|field| field.trim().to_string())
+                .filter(|field| !field.is_empty())
+                .collect();
+            if let Some(unknown) = fields.iter().find(|field| !is_known(field)) {
+                return Err(format!("fields has an unknown field: {}", unknown));
+            }
+            return Ok(Projection { fields });
+        }
+        let fields: Vec<&str> = match req.query.get("detail").map(|d| d.as_str()) {
+            None | Some("summary") => SUMMARY_FIELDS.to_vec(),
+            Some("full") => SUMMARY_FIELDS
+                .iter()
+                .chain(&DETAIL_FIELDS)
+                .copied()
+                .collect(),
+            Some(_) => return Err("detail must be summary or full".to_string()),
+        };
+        Ok(Projection {
+            fields: fields.into_iter().map(String::from).collect(),
+        })
+    }
+
+    fn details(&self) -> Vec<&'static str> {
+        DETAIL_FIELDS
+            .into_iter()
+            .filter(|field| self.fields.iter().any(|f| f == field))
+            .collect()
+    }
+
+    // Reads the requested detail fields of every business with one HMGET each, in a single
+    // pipeline. None when no detail field is requested
+    pub async fn read_details(
+        &self,
+        redis: &RedisDB,
+        businesses: &[BusinessSummary],
+    ) -> Result<Option<Vec<BusinessDetails>>> {
+        let details = self.details();
+        if details.is_empty() {
+            return Ok(None);
+        }
+        if businesses.is_empty() {
+            return Ok(Some(vec![]));
+        }
+
+        let mut pipe = Pipeline::new();
+        for business in businesses {
+            pipe.cmd("HMGET").arg(business.id).arg(&details);
+        }
+        let values: Vec<Vec<Option<String>>> = redis.read_pipe(&pipe).await?;
+        let mut found = Vec::with_capacity(businesses.len());
+        for values in values {
+            let hash: HashMap<String, String> = details
+                .iter()
+                .zip(values)
+                .filter_map(|(field, value)| Some((field.to_string(), value?)))
+                .collect();
+            found.push(BusinessDetails::from_hash(hash)?);
+        }
+        Ok(Some(found))
+    }
+
+    pub fn apply(
+        &self,
+        business: &BusinessSummary,
+        details: Option<&BusinessDetails>,
+        open_now: bool,
+    ) -> Value {
+        let mut value = to_json(business, details, open_now);
+        if let Value::Object(object) = &mut value {
+            object.retain(|key, _| {
+                key == "id" || key == "openNow" || self.fields.iter().any(|f| f == key)
+            });
+        }
+        value
+    }
+}
+
+// A business as searches return it, the summary with openNow and, when there are any, details
+pub fn to_json(
+    business: &BusinessSummary,
+    details: Option<&BusinessDetails>,
+    open_now: bool,
+) -> Value {
+    let mut value = serde_json::to_value(business).unwrap_or_default();
+    if let Value::Object(object) = &mut value {
+        if let Some(Value::Object(details)) = details.map(|d| json!(d)) {
+            object.extend(details);
+        }
+        object.insert("openNow".to_string(), json!(open_now));
+    }
+    value
+}
+
+fn is_known(field: &str) -> bool {
+    SUMMARY_FIELDS.contains(&field) || DETAIL_FIELDS.contains(&field)
+}
@@ -0,0 +1,483 @@
+use crate::dbs::{MongoDb, Result};
+use crate::events::ChangeSubscriber;
+use crate::quadtree::QuadTree;
+use crate::redis_db::RedisDB;
+use async_trait::async_trait;
+use bson::{Bson, Document};
+use futures_util::future::try_join_all;
+use model::geohash;
+use model::shards::{ShardMap, SHARD_LIST_KEY, UNSHARDED_KEY};
+use redis::geo::RadiusSearchResult;
+use redis::Pipeline;
+use std::sync::{Arc, RwLock};
+
+#[derive(Clone, Debug)]
+pub struct GeoPoint {
+    pub id: u64,
+    pub lon: f64,
+    pub lat: f64,
+}
+
+pub struct Viewport {
+    pub min_lon: f64,
+    pub min_lat: f64,
+    pub max_lon: f64,
+    pub max_lat: f64,
+}
+
+impl Viewport {
+    pub fn center(&self) -> (f64, f64) {
+        (
+            (self.min_lon + self.max_lon) / 2.0,
+            (self.min_lat + self.max_lat) / 2.0,
+        )
+    }
+    pub fn contains(&self, lon: f64, lat: f64) -> bool {
+        lon >= self.min_lon && lon <= self.max_lon && lat >= self.min_lat && lat <= self.max_lat
+    }
+    // Size of the box in meters, for BYBOX. Width is taken along the parallel so that boxes wider
+    // than half the globe stay wide. Redis measures how far east or west a point is at the
+    // point's own latitude, so the width is that of the parallel closest to the equator, where
+    // the box is widest. Points that fall outside of the actual box are trimmed with `contains`
+    fn size(&self) -> (f64, f64) {
+        let widest_lat = 0.0_f64.clamp(self.min_lat, self.max_lat);
+        let width = (self.max_lon - self.min_lon).to_radians()
+            * geohash::EARTH_RADIUS
+            * widest_lat.to_radians().cos();
+        let height = (self.max_lat - self.min_lat).to_radians() * geohash::EARTH_RADIUS;
+        (width, height)
+    }
+}
+
+// Where business positions are looked up. Redis GEO is the default, the in-memory quadtree lets
+// lbs run without a geo Redis and is there to compare latency against it
+#[async_trait]
+pub trait GeoIndex: Send + Sync {
+    fn name(&self) -> &str;
+    // Redis nodes behind the index, for readiness
+    fn redis_nodes(&self) -> &[RedisDB] {
+        &[]
+    }
+    async fn insert(&mut self, point: GeoPoint) -> Result<()>;
+    async fn remove(&mut self, id: u64) -> Result<()>;
+    // Nearest first
+    async fn radius(&mut self, lon: f64, lat: f64, radius: f64) -> Result<Vec<GeoPoint>>;
+    // A radius search per (lon, lat, radius), for backends that can answer them together
+    async fn radius_many(&mut self, searches: &[(f64, f64, f64)]) -> Result<Vec<Vec<GeoPoint>>> {
+        let mut found = Vec::with_capacity(searches.len());
+        for (lon, lat, radius) in searches {
+            found.push(self.radius(*lon, *lat, *radius).await?);
+        }
+        Ok(found)
+    }
+    // At most `limit` points, the ones closest to the center of the box when there are more, and
+    // whether there were more
+    async fn within_box(&mut self, area: &Viewport, limit: usize) -> Result<(Vec<GeoPoint>, bool)>;
+    // Nearest first
+    async fn knn(&mut self, lon: f64, lat: f64, k: usize) -> Result<Vec<GeoPoint>>;
+}
+
+// Positions in the geo Redis nodes, split into regional shards (see model::shards). Queries go to
+// every shard they touch, one pipeline per node with the nodes asked concurrently, and the
+// results are merged
+pub struct RedisGeoIndex {
+    nodes: Vec<RedisDB>,
+    shards: ShardMap,
+}
+
+impl RedisGeoIndex {
+    pub fn new(nodes: Vec<RedisDB>, precision: usize) -> RedisGeoIndex {
+        let shards = ShardMap::new(precision, nodes.len());
+        RedisGeoIndex { nodes, shards }
+    }
+
+    // Groups one command per shard key into a pipeline per node
+    fn plan(&self, keys: &[String], command: impl Fn(&str) -> redis::Cmd) -> Vec<Option<Pipeline>> {
+        let mut pipes: Vec<Option<Pipeline>> = vec![None; self.nodes.len()];
+        for key in keys {
+            pipes[self.shards.node_for(key)]
+                .get_or_insert_with(Pipeline::new)
+                .add_command(command(key));
+        }
+        pipes
+    }
+
+    async fn gather(&self, pipes: Vec<Option<Pipeline>>) -> Result<Vec<GeoPoint>> {
+        let found = self.gather_replies(pipes).await?;
+        to_points(found.into_iter().flatten().collect())
+    }
+
+    // The reply of every command, node by node
+    async fn gather_replies(
+        &self,
+        pipes: Vec<Option<Pipeline>>,
+    ) -> Result<Vec<Vec<RadiusSearchResult>>> {
+        let queries = pipes
+            .into_iter()
+            .zip(self.nodes.iter())
+            .filter_map(|(pipe, node)| {
+                pipe.map(|pipe| async move {
+                    node.read_pipe::<Vec<Vec<RadiusSearchResult>>>(&pipe).await
+                })
+            });
+        Ok(try_join_all(queries).await?.into_iter().flatten().collect())
+    }
+
+    // Shard keys that hold positions on any node
+    async fn listed_keys(&self) -> Result<Vec<String>> {
+        if !self.shards.is_sharded() {
+            return Ok(vec![UNSHARDED_KEY.to_string()]);
+        }
+        let queries = self.nodes.iter().map(|node| async move {
+            node.read_cmd::<Vec<String>>(redis::cmd("SMEMBERS").arg(SHARD_LIST_KEY))
+                .await
+        });
+        let mut keys: Vec<String> = try_join_all(queries).await?.into_iter().flatten().collect();
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl GeoIndex for RedisGeoIndex {
+    fn name(&self) -> &str {
+        "redis"
+    }
+
+    fn redis_nodes(&self) -> &[RedisDB] {
+        &self.nodes
+    }
+
+    async fn insert(&mut self, point: GeoPoint) -> Result<()> {
+        // the business may have moved to another region
+        self.remove(point.id).await?;
+        let key = self.shards.key_for(point.lon, point.lat);
+        let mut pipe = Pipeline::new();
+        pipe.geo_add(&key, (point.lon, point.lat, point.id))
+            .ignore();
+        if self.shards.is_sharded() {
+            pipe.sadd(SHARD_LIST_KEY, &key).ignore();
+        }
+        let node = &self.nodes[self.shards.node_for(&key)];
+        let _: () = pipe.query_async(&mut node.primary()).await?;
+        Ok(())
+    }
+
+    async fn remove(&mut self, id: u64) -> Result<()> {
+        let keys = self.listed_keys().await?;
+        let pipes = self.plan(&keys, |key| {
+            let mut cmd = redis::cmd("ZREM");
+            cmd.arg(key).arg(id);
+            cmd
+        });
+        for (pipe, node) in pipes.into_iter().zip(self.nodes.iter()) {
+            if let Some(pipe) = pipe {
+                let _: () = pipe.query_async(&mut node.primary()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn radius(&mut self, lon: f64, lat: f64, radius: f64) -> Result<Vec<GeoPoint>> {
+        let keys = self.shards.keys_for_radius(lon, lat, radius);
+        let pipes = self.plan(&keys, |key| radius_cmd(key, lon, lat, radius));
+        let points = self.gather(pipes).await?;
+        Ok(nearest_first(points, lon, lat))
+    }
+
+    // Every search goes into the same pipeline per node, so a batch costs one round trip per
+    // node rather than one per search
+    async fn radius_many(&mut self, searches: &[(f64, f64, f64)]) -> Result<Vec<Vec<GeoPoint>>> {
+        let mut pipes: Vec<Option<Pipeline>> = vec![None; self.nodes.len()];
+        // search of every command, per node
+        let mut owners: Vec<Vec<usize>> = vec![vec![]; self.nodes.len()];
+        for (i, (lon, lat, radius)) in searches.iter().enumerate() {
+            for key in self.shards.keys_for_radius(*lon, *lat, *radius) {
+                let node = self.shards.node_for(&key);
+                pipes[node]
+                    .get_or_insert_with(Pipeline::new)
+                    .add_command(radius_cmd(&key, *lon, *lat, *radius));
+                owners[node].push(i);
+            }
+        }
+
+        let queries = pipes
+            .into_iter()
+            .zip(self.nodes.iter())
+            .zip(owners)
+            .filter_map(|((pipe, node), owners)| {
+                pipe.map(|pipe| async move {
+                    let found = node
+                        .read_pipe::<Vec<Vec<RadiusSearchResult>>>(&pipe)
+                        .await?;
+                    Ok::<_, redis::RedisError>(owners.into_iter().zip(found))
+                })
+            });
+        let mut found: Vec<Vec<RadiusSearchResult>> = searches.iter().map(|_| vec![]).collect();
+        for (i, results) in try_join_all(queries).await?.into_iter().flatten() {
+            found[i].extend(results);
+        }
+
+        let mut points = Vec::with_capacity(searches.len());
+        for ((lon, lat, _), results) in searches.iter().zip(found) {
+            points.push(nearest_first(to_points(results)?, *lon, *lat));
+        }
+        Ok(points)
+    }
+
+    async fn within_box(&mut self, area: &Viewport, limit: usize) -> Result<(Vec<GeoPoint>, bool)> {
+        let (lon, lat) = area.center();
+        let (width, height) = area.size();
+        let keys = self
+            .shards
+            .keys_for_box(area.min_lon, area.min_lat, area.max_lon, area.max_lat);
+        // COUNT keeps Redis from sending, and us from holding, every business of a wide box.
+        // Each shard gives its nearest to the center, the merged ones are cut down again below
+        let pipes = self.plan(&keys, |key| {
+            let mut cmd = redis::cmd("GEOSEARCH");
+            cmd.arg(key)
+                .arg("FROMLONLAT")
+                .arg(lon)
+                .arg(lat)
+                .arg("BYBOX")
+                .arg(width)
+                .arg(height)
+                .arg("m")
+                .arg("COUNT")
+                .arg(limit)
+                .arg("ASC")
+                .arg("WITHCOORD");
+            cmd
+        });
+        let replies = self.gather_replies(pipes).await?;
+        let mut truncated = replies.iter().any(|reply| reply.len() >= limit);
+        // BYBOX works on a projected box, trim whatever falls outside of the actual area
+        let points: Vec<GeoPoint> = to_points(replies.into_iter().flatten().collect())?
+            .into_iter()
+            .filter(|p| area.contains(p.lon, p.lat))
+            .collect();
+        let mut points = nearest_first(points, lon, lat);
+        if points.len() > limit {
+            points.truncate(limit);
+            truncated = true;
+        }
+        Ok((points, truncated))
+    }
+
+    async fn knn(&mut self, lon: f64, lat: f64, k: usize) -> Result<Vec<GeoPoint>> {
+        // the nearest businesses can be in any region, each shard gives its own k nearest
+        let keys = self.listed_keys().await?;
+        let pipes = self.plan(&keys, |key| {
+            // half of the equator covers the whole globe
+            let mut cmd = redis::cmd("GEOSEARCH");
+            cmd.arg(key)
+                .arg("FROMLONLAT")
+                .arg(lon)
+                .arg(lat)
+                .arg("BYRADIUS")
+                .arg(20_037)
+                .arg("km")
+                .arg("ASC")
+                .arg("COUNT")
+                .arg(k)
+                .arg("WITHCOORD");
+            cmd
+        });
+        let mut points = nearest_first(self.gather(pipes).await?, lon, lat);
+        points.truncate(k);
+        Ok(points)
+    }
+}
+
+fn radius_cmd(key: &str, lon: f64, lat: f64, radius: f64) -> redis::Cmd {
+    let mut cmd = redis::cmd("GEOSEARCH");
+    cmd.arg(key)
+        .arg("FROMLONLAT")
+        .arg(lon)
+        .arg(lat)
+        .arg("BYRADIUS")
+        .arg(radius)
+        .arg("m")
+        .arg("ASC")
+        .arg("WITHCOORD");
+    cmd
+}
+
+fn nearest_first(points: Vec<GeoPoint>, lon: f64, lat: f64) -> Vec<GeoPoint> {
+    let mut found: Vec<(f64, GeoPoint)> = points
+        .into_iter()
+        .map(|p| (geohash::distance(lon, lat, p.lon, p.lat), p))
+        .collect();
+    found.sort_by(|a, b| a.0.total_cmp(&b.0));
+    found.into_iter().map(|(_, p)| p).collect()
+}
+
+fn to_points(found: Vec<RadiusSearchResult>) -> Result<Vec<GeoPoint>> {
+    let mut points = Vec::with_capacity(found.len());
+    for item in found {
+        let coord = item.coord.ok_or("Redis did not return coordinates")?;
+        points.push(GeoPoint {
+            id: item.name.parse::<u64>()?,
+            lon: coord.longitude,
+            lat: coord.latitude,
+        });
+    }
+    Ok(points)
+}
+
+#[derive(Clone)]
+pub struct MemoryGeoIndex {
+    tree: Arc<RwLock<QuadTree>>,
+}
+
+impl MemoryGeoIndex {
+    // Positions come from Mongo, the source of truth, rather than from the geo Redis
+    pub async fn load(mongo: &MongoDb) -> Result<MemoryGeoIndex> {
+        let index = MemoryGeoIndex {
+            tree: Arc::new(RwLock::new(QuadTree::new())),
+        };
+        index.reload(mongo).await?;
+        Ok(index)
+    }
+
+    pub async fn reload(&self, mongo: &MongoDb) -> Result<()> {
+        let mut tree = QuadTree::new();
+        let mut cursor = mongo.find_positions().await?;
+        while cursor.advance().await? {
+            match point_from_document(&cursor.deserialize_current()?) {
+                Some(point) => tree.insert(point),
+                None => continue,
+            }
+        }
+        println!("Loaded {} businesses into the memory geo index", tree.len());
+        *self.tree.write().unwrap() = tree;
+        Ok(())
+    }
+}
+
+// Changed businesses are re-read from Mongo, the ones that are gone are removed. Positions
+// written between loading the index and subscribing would be missed, so every subscription
+// (the first one included) reloads it
+pub struct MemoryGeoIndexUpdater {
+    pub index: MemoryGeoIndex,
+    pub mongo: MongoDb,
+}
+
+#[async_trait]
+impl ChangeSubscriber for MemoryGeoIndexUpdater {
+    async fn on_change(&mut self, id: u64) -> Result<()> {
+        let found = self.mongo.find_position(id).await?;
+        match found.as_ref().and_then(point_from_document) {
+            Some(point) => self.index.insert(point).await,
+            None => self.index.remove(id).await,
+        }
+    }
+    async fn on_resync(&mut self) -> Result<()> {
+        self.index.reload(&self.mongo).await
+    }
+}
+
+#[async_trait]
+impl GeoIndex for MemoryGeoIndex {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    async fn insert(&mut self, point: GeoPoint) -> Result<()> {
+        self.tree.write().unwrap().insert(point);
+        Ok(())
+    }
+
+    async fn remove(&mut self, id: u64) -> Result<()> {
+        self.tree.write().unwrap().remove(id);
+        Ok(())
+    }
+
+    async fn radius(&mut self, lon: f64, lat: f64, radius: f64) -> Result<Vec<GeoPoint>> {
+        Ok(self.tree.read().unwrap().radius(lon, lat, radius))
+    }
+
+    async fn within_box(&mut self, area: &Viewport, limit: usize) -> Result<(Vec<GeoPoint>, bool)> {
+        let points = self.tree.read().unwrap().within_box(area);
+        if points.len() <= limit {
+            return Ok((points, false));
+        }
+        let (lon, lat) = area.center();
+        let mut points = nearest_first(points, lon, lat);
+        points.truncate(limit);
+        Ok((points, true))
+    }
+
+    async fn knn(&mut self, lon: f64, lat: f64, k: usize) -> Result<Vec<GeoPoint>> {
+        Ok(self.tree.read().unwrap().knn(lon, lat, k))
+    }
+}
+
+pub fn point_from_document(document: &Document) -> Option<GeoPoint> {
+    Some(GeoPoint {
+        id: bson_number(document.get("id")?)? as u64,
+        lon: bson_number(document.get("lon")?)?,
+        lat: bson_number(document.get("lat")?)?,
+    })
+}
+
+// The api writes ids as 64 bit ints, the seeding script leaves them to the driver
+fn bson_number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Double(n) => Some(*n),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Redis keeps a point of BYBOX when it is within half the width east or west of the center,
+    // measured along the point's parallel
+    fn in_redis_box(area: &Viewport, lon: f64, lat: f64) -> bool {
+        let (center_lon, center_lat) = area.center();
+        let (width, height) = area.size();
+        geohash::distance(center_lon, lat, lon, lat) <= width / 2.0
+            && geohash::distance(lon, center_lat, lon, lat) <= height / 2.0
+    }
+
+    #[test]
+    fn tall_box_reaches_the_equator_side_corners() {
+        let area = Viewport {
+            min_lon: 10.0,
+            min_lat: 5.0,
+            max_lon: 20.0,
+            max_lat: 65.0,
+        };
+        for (lon, lat) in [(10.01, 5.01), (19.99, 5.01), (19.99, 64.99), (15.0, 35.0)] {
+            assert!(area.contains(lon, lat));
+            assert!(in_redis_box(&area, lon, lat), "{},{}", lon, lat);
+        }
+    }
+
+    #[test]
+    fn box_across_the_equator_is_as_wide_as_at_the_equator() {
+        let area = Viewport {
+            min_lon: -1.0,
+            min_lat: -50.0,
+            max_lon: 1.0,
+            max_lat: 10.0,
+        };
+        let (width, _) = area.size();
+        let equator = 2.0_f64.to_radians() * geohash::EARTH_RADIUS;
+        assert!((width - equator).abs() < 1e-6);
+        assert!(in_redis_box(&area, 0.99, 0.0));
+
+        let south = Viewport {
+            min_lon: -1.0,
+            min_lat: -60.0,
+            max_lon: 1.0,
+            max_lat: -20.0,
+        };
+        assert!(in_redis_box(&south, -0.99, -20.01));
+    }
+}
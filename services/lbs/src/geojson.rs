@@ -0,0 +1,43 @@
+use crate::request::Request;
+use model::business::BusinessSummary;
+use serde_json::{json, Value};
+
+pub const CONTENT_TYPE: &str = "application/geo+json";
+
+// Either `Accept: application/geo+json` or `format=geojson` switches search results to GeoJSON,
+// the query parameter is there for clients that cannot set headers (e.g. a link in GIS tools)
+pub fn is_requested(req: &Request) -> bool {
+    if let Some(format) = req.query.get("format") {
+        return format.eq_ignore_ascii_case("geojson");
+    }
+    match &req.accept {
+        Some(accept) => accept
+            .split(',')
+            .any(|item| item.split(';').next().unwrap_or("").trim() == CONTENT_TYPE),
+        None => false,
+    }
+}
+
+// Properties are the business as the search returns it, see fields.rs
+pub fn feature_collection(businesses: &[BusinessSummary], properties: Vec<Value>) -> Value {
+    let features: Vec<Value> = businesses
+        .iter()
+        .zip(properties)
+        .map(|(business, properties)| {
+            json!({
+                "type": "Feature",
+                "id": business.id,
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [business.lon, business.lat]
+                },
+                "properties": properties
+            })
+        })
+        .collect();
+
+    json!({
+        "type": "FeatureCollection",
+        "features": features
+    })
+}
@@ -1,29 +1,60 @@
+mod batch;
+mod business_cache;
+mod cluster;
 mod config;
 mod dbs;
+mod events;
+mod fields;
+mod geo_index;
+mod geojson;
+mod mvt;
+mod params;
+mod quadtree;
+mod redis_db;
 mod request;
 mod response;
+mod search_cache;
+mod suggest;
+mod tiles;
 
 use config::ServerConfig;
 use dbs::{DBConnections, Result};
-use redis::{
-    geo::{RadiusOptions, RadiusOrder, Unit},
-    AsyncCommands, Pipeline,
-};
 use request::{parse_tcp_stream, Request};
 use response::Response;
-use serde_json::json;
+use serde_json::{json, Value};
 use std::io::Write;
 use std::net::{TcpListener, TcpStream};
 use std::time::Instant;
 
-use crate::dbs::BusinessData;
+use crate::batch::BatchQuery;
+use crate::fields::Projection;
+use crate::geo_index::Viewport;
+use crate::redis_db::RedisDB;
+use crate::tiles::TileId;
+use model::business::{BusinessData, BusinessDetails, BusinessSummary};
 
-pub const ROUTES: [&str; 1] = ["GET /search"];
+pub const ROUTES: [&str; 8] = [
+    "GET /search",
+    "POST /search/batch",
+    "GET /ready",
+    "GET /nearest",
+    "GET /metrics",
+    "GET /suggest",
+    "GET /clusters",
+    "GET /tiles/:z/:x/:y",
+];
+const MAX_SUGGESTIONS: usize = 25;
+const MAX_NEAREST: usize = 100;
+// set on responses served from Mongo because Redis could not answer
+const DEGRADED_HEADER: &str = "X-Degraded";
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let config: ServerConfig = ServerConfig::get();
     let mut connections = DBConnections::init(&config).await?;
+    if let Some(redis_geo) = &connections.redis_geo {
+        suggest::ensure_index(redis_geo).await?;
+    }
     let listener = TcpListener::bind(format!("0.0.0.0:{}", config.port))
         .expect("Server failed to start at {config.port}");
     println!("Server is listening at {}", config.port);
@@ -53,14 +84,12 @@ async fn handle_tcp_stream(mut stream: TcpStream, connections: &mut DBConnection
             println!("Time took: {:?}", start_time.elapsed());
             println!("Response status: {:?}", res.status);
 
-            stream
-                .write(res.to_response_string().as_bytes())
-                .unwrap_or_default()
+            stream.write(&res.to_response_bytes()).unwrap_or_default()
         }
         Err(e) => {
             println!("{:?}", e);
             stream
-                .write(Response::internal(None).to_response_string().as_bytes())
+                .write(&Response::internal(None).to_response_bytes())
                 .unwrap_or_default()
         }
     }
@@ -76,6 +105,13 @@ async fn handle_request<'a>(
 
     match req.matched_path.unwrap() {
         "GET /search" => handle_get_area_businesses(req, connections).await,
+        "POST /search/batch" => handle_batch_search(req, connections).await,
+        "GET /nearest" => handle_get_nearest_businesses(req, connections).await,
+        "GET /metrics" => handle_get_metrics(connections),
+        "GET /ready" => handle_get_ready(connections).await,
+        "GET /suggest" => handle_get_suggestions(req, connections).await,
+        "GET /clusters" => handle_get_clusters(req, connections).await,
+        "GET /tiles/:z/:x/:y" => handle_get_tile(req, connections).await,
         _ => Ok(Response::not_found(None)),
     }
 }
@@ -84,57 +120,340 @@ async fn handle_get_area_businesses<'a>(
     req: &Request<'a>,
     conns: &mut DBConnections,
 ) -> Result<Response> {
-    if !req.query.contains_key("lon") || !req.query.contains_key("lat") {
-        return Ok(Response::bad_request(Some("Lon or lat was not specified")));
+    let point = match params::point(req) {
+        Ok(point) => point,
+        Err(message) => return Ok(Response::bad_request(Some(&message))),
+    };
+    let radius = match params::radius(req, 500.0, conns.max_radius) {
+        Ok(radius) => radius,
+        Err(message) => return Ok(Response::bad_request(Some(&message))),
+    };
+    let (lon, lat) = (point.lon, point.lat);
+
+    let bypass = req.headers.contains_key("x-cache-bypass");
+    let (businesses, cache_status) =
+        match search_cache::find_businesses(conns, lon, lat, radius, bypass).await {
+            Ok(found) => found,
+            Err(e) => match &conns.mongo_fallback {
+                Some(mongo) => {
+                    println!("Search failed, falling back to Mongo: {:?}", e);
+                    let businesses = mongo.find_near(lon, lat, Some(radius), None).await?;
+                    let response = mongo_search_response(req, conns, businesses).await?;
+                    return Ok(response.header(DEGRADED_HEADER, "mongo"));
+                }
+                None => return Err(e),
+            },
+        };
+
+    let response = search_response(req, &conns.redis_business, businesses, None).await?;
+    Ok(response.header("X-Cache", cache_status.as_str()))
+}
+
+// Results come in the order of the queries, each either {"businesses": [...]} or {"error": ".."}
+// when the query itself is invalid
+async fn handle_batch_search<'a>(req: &Request<'a>, conns: &mut DBConnections) -> Result<Response> {
+    let queries = match req.body.as_ref().and_then(|body| body.get("queries")) {
+        Some(Value::Array(queries)) => queries,
+        _ => {
+            return Ok(Response::bad_request(Some(
+                "Body must be a json object with a list of queries",
+            )))
+        }
+    };
+    if queries.is_empty() || queries.len() > batch::MAX_QUERIES {
+        return Ok(Response::bad_request(Some(&format!(
+            "A batch takes between 1 and {} queries",
+            batch::MAX_QUERIES
+        ))));
     }
-    let lat = req.query.get("lat").unwrap().parse::<f64>()?;
-    let lon = req.query.get("lon").unwrap().parse::<f64>()?;
-    let radius = req
-        .query
-        .get("radius")
-        .unwrap_or(&"500".to_string())
-        .parse::<f64>()?;
+    let queries: Vec<_> = queries
+        .iter()
+        .map(|query| BatchQuery::parse(query, conns.max_radius))
+        .collect();
+
+    let results = match batch::search(conns, &queries).await {
+        Ok(results) => results,
+        Err(e) => match &conns.mongo_fallback {
+            Some(mongo) => {
+                println!("Batch search failed, falling back to Mongo: {:?}", e);
+                let results = batch::search_mongo(mongo, &queries).await?;
+                return Ok(Response::success(json!({ "results": results }), None)
+                    .header(DEGRADED_HEADER, "mongo"));
+            }
+            None => return Err(e),
+        },
+    };
+
+    Ok(Response::success(json!({ "results": results }), None))
+}
+
+async fn handle_get_nearest_businesses<'a>(
+    req: &Request<'a>,
+    conns: &mut DBConnections,
+) -> Result<Response> {
+    let point = match params::point(req) {
+        Ok(point) => point,
+        Err(message) => return Ok(Response::bad_request(Some(&message))),
+    };
+    let limit = match params::limit(req, 10, MAX_NEAREST) {
+        Ok(limit) => limit,
+        Err(message) => return Ok(Response::bad_request(Some(&message))),
+    };
+    let (lon, lat) = (point.lon, point.lat);
+
+    let businesses = match find_nearest(conns, lon, lat, limit).await {
+        Ok(businesses) => businesses,
+        Err(e) => match &conns.mongo_fallback {
+            Some(mongo) => {
+                println!("Nearest search failed, falling back to Mongo: {:?}", e);
+                let businesses = mongo.find_near(lon, lat, None, Some(limit as i64)).await?;
+                let response = mongo_search_response(req, conns, businesses).await?;
+                return Ok(response.header(DEGRADED_HEADER, "mongo"));
+            }
+            None => return Err(e),
+        },
+    };
+
+    search_response(req, &conns.redis_business, businesses, None).await
+}
 
+async fn find_nearest(
+    conns: &mut DBConnections,
+    lon: f64,
+    lat: f64,
+    limit: usize,
+) -> Result<Vec<BusinessSummary>> {
     let ids: Vec<String> = conns
-        .redis_geo
-        .connection
-        .geo_radius(
-            "world",
-            lon,
-            lat,
-            radius,
-            Unit::Meters,
-            RadiusOptions::default().order(RadiusOrder::Asc),
-        )
-        .await?;
-    if ids.is_empty() {
-        return Ok(Response::success(json!({"businesses": []}), None));
+        .geo_index
+        .knn(lon, lat, limit)
+        .await?
+        .into_iter()
+        .map(|point| point.id.to_string())
+        .collect();
+    dbs::hydrate(conns, &ids).await
+}
+
+// Mongo has the full records, so the details come from them rather than from Redis
+async fn mongo_search_response(
+    req: &Request<'_>,
+    conns: &DBConnections,
+    businesses: Vec<BusinessData>,
+) -> Result<Response> {
+    let details = businesses.iter().map(BusinessData::details).collect();
+    let businesses = businesses.iter().map(BusinessData::summary).collect();
+    search_response(req, &conns.redis_business, businesses, Some(details)).await
+}
+
+// details, when given, are in the order of businesses. Otherwise the ones the projection asks
+// for are read from Redis
+async fn search_response(
+    req: &Request<'_>,
+    redis: &RedisDB,
+    mut businesses: Vec<BusinessSummary>,
+    mut details: Option<Vec<BusinessDetails>>,
+) -> Result<Response> {
+    let projection = match Projection::from_request(req) {
+        Ok(projection) => projection,
+        Err(message) => return Ok(Response::bad_request(Some(&message))),
+    };
+    let mut open: Vec<bool> = businesses
+        .iter()
+        .map(|business| business.opening_hours().is_open_now())
+        .collect();
+    if req
+        .query
+        .get("openNow")
+        .map(|v| v == "true")
+        .unwrap_or(false)
+    {
+        let mut keep = open.clone().into_iter();
+        businesses.retain(|_| keep.next().unwrap_or(false));
+        if let Some(details) = details.as_mut() {
+            let mut keep = open.clone().into_iter();
+            details.retain(|_| keep.next().unwrap_or(false));
+        }
+        open.retain(|open| *open);
     }
-    if ids.len() == 1 {
-        let mut single_item: BusinessData =
-            conns.redis_business.connection.hgetall(&ids[0]).await?;
-        single_item.id = Some(ids[0].parse::<u64>()?);
-        return Ok(Response::success(
-            json!({"businesses": vec![single_item]}),
-            None,
-        ));
+    let details = match details {
+        Some(details) => Some(details),
+        None => projection.read_details(redis, &businesses).await?,
+    };
+    let projected: Vec<Value> = businesses
+        .iter()
+        .enumerate()
+        .map(|(i, business)| {
+            let details = details.as_ref().and_then(|details| details.get(i));
+            projection.apply(business, details, open[i])
+        })
+        .collect();
+    if geojson::is_requested(req) {
+        return Ok(
+            Response::success(geojson::feature_collection(&businesses, projected), None)
+                .content_type(geojson::CONTENT_TYPE),
+        );
     }
+    Ok(Response::success(json!({ "businesses": projected }), None))
+}
 
-    let mut pipe = Pipeline::new();
-    for id in &ids {
-        pipe.hgetall(id);
+fn handle_get_metrics(conns: &DBConnections) -> Result<Response> {
+    let business_cache = match &conns.business_cache {
+        Some(cache) => cache.lock().unwrap().to_json(),
+        None => json!(null),
+    };
+    Ok(Response::success(
+        json!({
+            "geoIndex": conns.geo_index.name(),
+            "searchCache": conns.search_cache.stats.to_json(),
+            "businessCache": business_cache
+        }),
+        None,
+    ))
+}
+
+// 503 while any Redis primary lbs depends on is unreachable. Replicas don't affect readiness,
+// reads fall back to the primary without them
+async fn handle_get_ready(conns: &DBConnections) -> Result<Response> {
+    let (business_ready, business) = conns.redis_business.health().await;
+    let mut ready = business_ready;
+    let geo = match &conns.redis_geo {
+        Some(redis_geo) => {
+            let (geo_ready, geo) = redis_geo.health().await;
+            ready &= geo_ready;
+            geo
+        }
+        None => json!(null),
+    };
+    let mut geo_shards = vec![];
+    for node in conns.geo_index.redis_nodes() {
+        let (node_ready, node) = node.health().await;
+        ready &= node_ready;
+        geo_shards.push(node);
     }
-    let mut businesses: Vec<BusinessData> = pipe
-        .query_async(&mut conns.redis_business.connection)
-        .await?;
 
-    for (i, business) in businesses.iter_mut().enumerate() {
-        business.id = Some(ids[i].parse::<u64>()?);
+    Ok(Response::success(
+        json!({
+            "ready": ready,
+            "geoIndex": conns.geo_index.name(),
+            "redisBusiness": business,
+            "redisGeo": geo,
+            "geoShards": geo_shards
+        }),
+        Some(if ready { 200 } else { 503 }),
+    ))
+}
+
+async fn handle_get_suggestions<'a>(
+    req: &Request<'a>,
+    conns: &mut DBConnections,
+) -> Result<Response> {
+    let point = match params::point(req) {
+        Ok(point) => point,
+        Err(message) => return Ok(Response::bad_request(Some(&message))),
+    };
+    let text = req.query.get("q").map(|q| q.trim()).unwrap_or("");
+    if text.is_empty() {
+        return Ok(Response::bad_request(Some("Search text was not specified")));
     }
+    let radius = match params::radius(req, 5000_f64.min(conns.max_radius), conns.max_radius) {
+        Ok(radius) => radius,
+        Err(message) => return Ok(Response::bad_request(Some(&message))),
+    };
+    let limit = match params::limit(req, 10, MAX_SUGGESTIONS) {
+        Ok(limit) => limit,
+        Err(message) => return Ok(Response::bad_request(Some(&message))),
+    };
+    let (lon, lat) = (point.lon, point.lat);
+
+    let redis_geo = match &conns.redis_geo {
+        Some(redis_geo) => redis_geo,
+        None => {
+            return Ok(Response::unavailable(Some(
+                "Suggestions need a geo Redis, REDIS_GEO_URI is not set",
+            )))
+        }
+    };
+    let suggestions = suggest::find_suggestions(redis_geo, text, lon, lat, radius, limit).await?;
+
+    Ok(Response::success(
+        json!({ "suggestions": suggestions }),
+        None,
+    ))
+}
+
+async fn handle_get_clusters<'a>(req: &Request<'a>, conns: &mut DBConnections) -> Result<Response> {
+    let mut bounds = [0.0; 4];
+    for (i, key) in ["minLon", "minLat", "maxLon", "maxLat"].iter().enumerate() {
+        match req.query.get(*key).map(|v| v.parse::<f64>()) {
+            Some(Ok(value)) => bounds[i] = value,
+            _ => {
+                return Ok(Response::bad_request(Some(&format!(
+                    "{} was not specified or is not a number",
+                    key
+                ))))
+            }
+        }
+    }
+    let viewport = Viewport {
+        min_lon: bounds[0],
+        min_lat: bounds[1],
+        max_lon: bounds[2],
+        max_lat: bounds[3],
+    };
+    for (corner, lon, lat) in [
+        ("min", viewport.min_lon, viewport.min_lat),
+        ("max", viewport.max_lon, viewport.max_lat),
+    ] {
+        if let Err(message) = params::check_point(lon, lat) {
+            return Ok(Response::bad_request(Some(&format!(
+                "{} ({}Lon, {}Lat)",
+                message, corner, corner
+            ))));
+        }
+    }
+    // a box of no width or height has nothing to cluster, and one crossing the antimeridian is
+    // asked as two boxes
+    if viewport.min_lon >= viewport.max_lon || viewport.min_lat >= viewport.max_lat {
+        return Ok(Response::bad_request(Some(
+            "minLon must be less than maxLon and minLat less than maxLat",
+        )));
+    }
+    let zoom = match req.query.get("zoom").map(|v| v.parse::<u8>()) {
+        Some(Ok(zoom)) if zoom <= cluster::MAX_ZOOM => zoom,
+        _ => {
+            return Ok(Response::bad_request(Some(&format!(
+                "zoom must be a whole number between 0 and {}",
+                cluster::MAX_ZOOM
+            ))))
+        }
+    };
+
+    let precision = cluster::precision_for_zoom(zoom);
+    let (total, truncated, clusters) = cluster::find_clusters(conns, &viewport, precision).await?;
+
+    Ok(Response::success(
+        json!({
+            "zoom": zoom,
+            "precision": precision,
+            "total": total,
+            "truncated": truncated,
+            "clusters": clusters
+        }),
+        None,
+    ))
+}
+
+async fn handle_get_tile<'a>(req: &Request<'a>, conns: &mut DBConnections) -> Result<Response> {
+    let tile = TileId::parse(
+        req.params.get("z").unwrap(),
+        req.params.get("x").unwrap(),
+        req.params.get("y").unwrap(),
+    );
+    let Some(tile) = tile else {
+        return Ok(Response::not_found(Some("Tile does not exist")));
+    };
 
-    let body = json!({
-        "businesses": businesses
-    });
+    let data = tiles::build_tile(conns, &tile).await?;
 
-    Ok(Response::success(body, None))
+    Ok(Response::binary(data, "application/vnd.mapbox-vector-tile")
+        .header("Cache-Control", tiles::TILE_CACHE_CONTROL))
 }
@@ -0,0 +1,266 @@
+// Minimal encoder for Mapbox Vector Tiles (spec v2.1), only point features are supported.
+// See https://github.com/mapbox/vector-tile-spec/blob/master/2.1/vector_tile.proto
+use std::collections::HashMap;
+
+pub const EXTENT: u32 = 4096;
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_FIXED64: u32 = 1;
+const WIRE_LEN: u32 = 2;
+const GEOM_POINT: u64 = 1;
+const CMD_MOVE_TO: u32 = 1;
+
+#[derive(Clone, Debug)]
+pub enum MvtValue {
+    String(String),
+    Double(f64),
+    Uint(u64),
+}
+
+impl MvtValue {
+    // Values are deduplicated within a layer, floats are not hashable so we key them by text
+    fn key(&self) -> String {
+        match self {
+            MvtValue::String(v) => format!("s:{}", v),
+            MvtValue::Double(v) => format!("d:{}", v),
+            MvtValue::Uint(v) => format!("u:{}", v),
+        }
+    }
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        match self {
+            MvtValue::String(v) => write_bytes(&mut buf, 1, v.as_bytes()),
+            MvtValue::Double(v) => {
+                write_tag(&mut buf, 3, WIRE_FIXED64);
+                buf.extend_from_slice(&v.to_le_bytes());
+            }
+            MvtValue::Uint(v) => {
+                write_tag(&mut buf, 5, WIRE_VARINT);
+                write_varint(&mut buf, *v);
+            }
+        }
+        buf
+    }
+}
+
+pub struct Layer {
+    name: String,
+    keys: Vec<String>,
+    key_index: HashMap<String, u32>,
+    values: Vec<MvtValue>,
+    value_index: HashMap<String, u32>,
+    features: Vec<Vec<u8>>,
+}
+
+impl Layer {
+    pub fn new(name: &str) -> Layer {
+        Layer {
+            name: name.to_string(),
+            keys: vec![],
+            key_index: HashMap::default(),
+            values: vec![],
+            value_index: HashMap::default(),
+            features: vec![],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    // x and y are in tile coordinates, from 0 to EXTENT
+    pub fn add_point(&mut self, id: u64, x: i32, y: i32, properties: Vec<(&str, MvtValue)>) {
+        let mut tags: Vec<u64> = Vec::with_capacity(properties.len() * 2);
+        for (key, value) in properties {
+            tags.push(self.key_id(key) as u64);
+            tags.push(self.value_id(value) as u64);
+        }
+        let geometry = [
+            command(CMD_MOVE_TO, 1) as u64,
+            zigzag(x) as u64,
+            zigzag(y) as u64,
+        ];
+
+        let mut feature = vec![];
+        write_tag(&mut feature, 1, WIRE_VARINT);
+        write_varint(&mut feature, id);
+        write_packed(&mut feature, 2, &tags);
+        write_tag(&mut feature, 3, WIRE_VARINT);
+        write_varint(&mut feature, GEOM_POINT);
+        write_packed(&mut feature, 4, &geometry);
+        self.features.push(feature);
+    }
+
+    fn key_id(&mut self, key: &str) -> u32 {
+        if let Some(id) = self.key_index.get(key) {
+            return *id;
+        }
+        let id = self.keys.len() as u32;
+        self.keys.push(key.to_string());
+        self.key_index.insert(key.to_string(), id);
+        id
+    }
+
+    fn value_id(&mut self, value: MvtValue) -> u32 {
+        let key = value.key();
+        if let Some(id) = self.value_index.get(&key) {
+            return *id;
+        }
+        let id = self.values.len() as u32;
+        self.values.push(value);
+        self.value_index.insert(key, id);
+        id
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        write_tag(&mut buf, 15, WIRE_VARINT);
+        write_varint(&mut buf, 2);
+        write_bytes(&mut buf, 1, self.name.as_bytes());
+        for feature in &self.features {
+            write_bytes(&mut buf, 2, feature);
+        }
+        for key in &self.keys {
+            write_bytes(&mut buf, 3, key.as_bytes());
+        }
+        for value in &self.values {
+            write_bytes(&mut buf, 4, &value.encode());
+        }
+        write_tag(&mut buf, 5, WIRE_VARINT);
+        write_varint(&mut buf, EXTENT as u64);
+        buf
+    }
+}
+
+pub fn encode_tile(layers: &[Layer]) -> Vec<u8> {
+    let mut buf = vec![];
+    for layer in layers.iter().filter(|layer| !layer.is_empty()) {
+        write_bytes(&mut buf, 3, &layer.encode());
+    }
+    buf
+}
+
+fn command(id: u32, count: u32) -> u32 {
+    (id & 0x7) | (count << 3)
+}
+
+fn zigzag(n: i32) -> u32 {
+    ((n << 1) ^ (n >> 31)) as u32
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_tag(buf: &mut Vec<u8>, field: u32, wire_type: u32) {
+    write_varint(buf, ((field << 3) | wire_type) as u64);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_tag(buf, field, WIRE_LEN);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_packed(buf: &mut Vec<u8>, field: u32, values: &[u64]) {
+    let mut packed = vec![];
+    for value in values {
+        write_varint(&mut packed, *value);
+    }
+    write_bytes(buf, field, &packed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(value: u64) -> Vec<u8> {
+        let mut buf = vec![];
+        write_varint(&mut buf, value);
+        buf
+    }
+
+    #[test]
+    fn varints() {
+        assert_eq!(varint(0), [0x00]);
+        assert_eq!(varint(1), [0x01]);
+        assert_eq!(varint(127), [0x7f]);
+        assert_eq!(varint(128), [0x80, 0x01]);
+        assert_eq!(varint(300), [0xac, 0x02]);
+        assert_eq!(varint(4096), [0x80, 0x20]);
+        let max = varint(u64::MAX);
+        assert_eq!(max.len(), 10);
+        assert_eq!(max[9], 0x01);
+    }
+
+    #[test]
+    fn zigzag_encoding() {
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+        assert_eq!(zigzag(-2), 3);
+        assert_eq!(zigzag(25), 50);
+        assert_eq!(zigzag(i32::MAX), u32::MAX - 1);
+        assert_eq!(zigzag(i32::MIN), u32::MAX);
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(command(CMD_MOVE_TO, 1), 9);
+        assert_eq!(command(CMD_MOVE_TO, 3), 25);
+        // LineTo and ClosePath, not used but the layout is the same
+        assert_eq!(command(2, 10), 82);
+        assert_eq!(command(7, 1), 15);
+    }
+
+    #[test]
+    fn tile_with_one_point() {
+        let mut layer = Layer::new("b");
+        layer.add_point(1, 25, 17, vec![("n", MvtValue::Uint(2))]);
+        let tile = encode_tile(&[layer, Layer::new("empty")]);
+
+        #[rustfmt::skip]
+        let expected = [
+            0x1a, 0x1e, // layers, 30 bytes
+            0x78, 0x02, // version 2
+            0x0a, 0x01, b'b', // name
+            0x12, 0x0d, // feature, 13 bytes
+                0x08, 0x01, // id 1
+                0x12, 0x02, 0x00, 0x00, // tags, key 0 value 0
+                0x18, 0x01, // point
+                0x22, 0x03, 0x09, 0x32, 0x22, // MoveTo(1) 25,17
+            0x1a, 0x01, b'n', // keys
+            0x22, 0x02, 0x28, 0x02, // values, uint 2
+            0x28, 0x80, 0x20, // extent 4096
+        ];
+        assert_eq!(tile, expected);
+    }
+
+    #[test]
+    fn keys_and_values_are_shared_by_features() {
+        let mut layer = Layer::new("b");
+        layer.add_point(1, 0, 0, vec![("type", MvtValue::String("cafe".into()))]);
+        layer.add_point(2, 1, 1, vec![("type", MvtValue::String("cafe".into()))]);
+        layer.add_point(3, 2, 2, vec![("type", MvtValue::String("bar".into()))]);
+        assert_eq!(layer.keys, ["type"]);
+        assert_eq!(layer.values.len(), 2);
+        assert_eq!(layer.features.len(), 3);
+    }
+
+    #[test]
+    fn empty_layers_are_left_out() {
+        assert!(encode_tile(&[Layer::new("b")]).is_empty());
+    }
+
+    #[test]
+    fn doubles_are_fixed64() {
+        assert_eq!(
+            MvtValue::Double(1.5).encode(),
+            [0x19, 0, 0, 0, 0, 0, 0, 0xf8, 0x3f]
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use crate::request::Request;
+
+// Redis GEO only takes latitudes in the range of the web mercator projection
+const MIN_LAT: f64 = -85.05112878;
+const MAX_LAT: f64 = 85.05112878;
+
+// Query parameters shared by the search endpoints. Errors name the parameter and go into the
+// 400 body as they are
+#[derive(Debug)]
+pub struct Point {
+    pub lon: f64,
+    pub lat: f64,
+}
+
+pub fn point(req: &Request) -> Result<Point, String> {
+    let lat = number(req, "lat")?.ok_or("lat was not specified")?;
+    let lon = number(req, "lon")?.ok_or("lon was not specified")?;
+    check_point(lon, lat)
+}
+
+pub fn check_point(lon: f64, lat: f64) -> Result<Point, String> {
+    if !(MIN_LAT..=MAX_LAT).contains(&lat) {
+        return Err(format!("lat must be between {} and {}", MIN_LAT, MAX_LAT));
+    }
+    if !(-180.0..=180.0).contains(&lon) {
+        return Err("lon must be between -180 and 180".to_string());
+    }
+    Ok(Point { lon, lat })
+}
+
+// Meters. Bounded so that one request can't make Redis walk a continent
+pub fn radius(req: &Request, default: f64, max: f64) -> Result<f64, String> {
+    check_radius(number(req, "radius")?.unwrap_or(default), max)
+}
+
+pub fn check_radius(radius: f64, max: f64) -> Result<f64, String> {
+    // written so that NaN fails too
+    if !(radius > 0.0 && radius <= max) {
+        return Err(format!(
+            "radius must be more than 0 and at most {} meters",
+            max
+        ));
+    }
+    Ok(radius)
+}
+
+// Larger limits are cut down to max rather than refused
+pub fn limit(req: &Request, default: usize, max: usize) -> Result<usize, String> {
+    match req.query.get("limit").map(|v| v.parse::<usize>()) {
+        None => Ok(default.min(max)),
+        Some(Ok(limit)) if limit > 0 => Ok(limit.min(max)),
+        Some(_) => Err("limit must be a whole number greater than 0".to_string()),
+    }
+}
+
+// NaN and infinity parse as f64 but are never a valid coordinate or distance
+fn number(req: &Request, name: &str) -> Result<Option<f64>, String> {
+    match req.query.get(name).map(|v| v.parse::<f64>()) {
+        None => Ok(None),
+        Some(Ok(value)) if value.is_finite() => Ok(Some(value)),
+        Some(_) => Err(format!("{} must be a number", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(query: &[(&str, &str)]) -> Request<'static> {
+        let mut req = Request::default();
+        for (name, value) in query {
+            req.query.insert(name.to_string(), value.to_string());
+        }
+        req
+    }
+
+    #[test]
+    fn point_in_range_is_accepted() {
+        let point = check_point(13.4, 52.5).unwrap();
+        assert_eq!((point.lon, point.lat), (13.4, 52.5));
+        assert!(check_point(-180.0, MIN_LAT).is_ok());
+        assert!(check_point(180.0, MAX_LAT).is_ok());
+    }
+
+    #[test]
+    fn point_out_of_range_is_refused() {
+        for lat in [
+            90.0,
+            -90.0,
+            85.1,
+            -85.1,
+            f64::NAN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ] {
+            let error = check_point(0.0, lat).unwrap_err();
+            assert!(error.starts_with("lat "), "{}: {}", lat, error);
+        }
+        for lon in [
+            180.1,
+            -180.1,
+            360.0,
+            f64::NAN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ] {
+            let error = check_point(lon, 0.0).unwrap_err();
+            assert!(error.starts_with("lon "), "{}: {}", lon, error);
+        }
+    }
+
+    #[test]
+    fn point_from_the_query() {
+        let point = point(&request(&[("lat", "52.5"), ("lon", "13.4")])).unwrap();
+        assert_eq!((point.lon, point.lat), (13.4, 52.5));
+
+        for (lat, lon, field) in [
+            ("NaN", "13.4", "lat"),
+            ("52.5", "inf", "lon"),
+            ("52.5", "-inf", "lon"),
+            ("north", "13.4", "lat"),
+            ("52.5", "", "lon"),
+            ("95", "13.4", "lat"),
+            ("52.5", "181", "lon"),
+        ] {
+            let error = super::point(&request(&[("lat", lat), ("lon", lon)])).unwrap_err();
+            assert!(error.starts_with(field), "{} {}: {}", lat, lon, error);
+        }
+        assert_eq!(
+            super::point(&request(&[("lon", "13.4")])).unwrap_err(),
+            "lat was not specified"
+        );
+    }
+
+    #[test]
+    fn radius_is_bounded() {
+        assert_eq!(check_radius(500.0, 1000.0), Ok(500.0));
+        assert_eq!(check_radius(1000.0, 1000.0), Ok(1000.0));
+        for radius in [
+            0.0,
+            -1.0,
+            1000.1,
+            f64::NAN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ] {
+            assert!(check_radius(radius, 1000.0).is_err(), "{}", radius);
+        }
+    }
+
+    #[test]
+    fn radius_from_the_query() {
+        assert_eq!(radius(&request(&[]), 200.0, 1000.0), Ok(200.0));
+        assert_eq!(
+            radius(&request(&[("radius", "750")]), 200.0, 1000.0),
+            Ok(750.0)
+        );
+        for value in ["0", "-5", "1001", "NaN", "inf", "far"] {
+            let error = radius(&request(&[("radius", value)]), 200.0, 1000.0).unwrap_err();
+            assert!(error.starts_with("radius "), "{}: {}", value, error);
+        }
+    }
+
+    #[test]
+    fn limit_is_cut_down_to_max() {
+        assert_eq!(limit(&request(&[]), 10, 50), Ok(10));
+        assert_eq!(limit(&request(&[]), 100, 50), Ok(50));
+        assert_eq!(limit(&request(&[("limit", "20")]), 10, 50), Ok(20));
+        assert_eq!(limit(&request(&[("limit", "51")]), 10, 50), Ok(50));
+        for value in ["0", "-1", "1.5", "ten"] {
+            let error = limit(&request(&[("limit", value)]), 10, 50).unwrap_err();
+            assert!(error.starts_with("limit "), "{}: {}", value, error);
+        }
+    }
+
+    #[test]
+    fn number_names_the_field() {
+        let req = request(&[("lat", "abc"), ("lon", "1e3")]);
+        assert_eq!(number(&req, "lat"), Err("lat must be a number".to_string()));
+        assert_eq!(number(&req, "lon"), Ok(Some(1000.0)));
+        assert_eq!(number(&req, "radius"), Ok(None));
+    }
+}
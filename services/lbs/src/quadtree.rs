@@ -0,0 +1,427 @@
+use crate::geo_index::{GeoPoint, Viewport};
+use model::geohash;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+const NODE_CAPACITY: usize = 32;
+const MAX_DEPTH: usize = 24;
+
+// Point quadtree over lon/lat degrees. Nodes split in four once they hold more than
+// NODE_CAPACITY points and are never merged back, removals just leave emptier leaves behind
+pub struct QuadTree {
+    root: Node,
+    positions: HashMap<u64, (f64, f64)>,
+}
+
+struct Node {
+    bounds: Viewport,
+    depth: usize,
+    points: Vec<GeoPoint>,
+    children: Option<Box<[Node; 4]>>,
+}
+
+impl QuadTree {
+    pub fn new() -> QuadTree {
+        QuadTree {
+            root: Node::new(
+                Viewport {
+                    min_lon: -180.0,
+                    min_lat: -90.0,
+                    max_lon: 180.0,
+                    max_lat: 90.0,
+                },
+                0,
+            ),
+            positions: HashMap::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn insert(&mut self, point: GeoPoint) {
+        self.remove(point.id);
+        self.positions.insert(point.id, (point.lon, point.lat));
+        self.root.insert(point);
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        if let Some((lon, lat)) = self.positions.remove(&id) {
+            self.root.remove(id, lon, lat);
+        }
+    }
+
+    pub fn within_box(&self, area: &Viewport) -> Vec<GeoPoint> {
+        let mut found = vec![];
+        self.root.collect(area, &mut found);
+        found
+    }
+
+    // Nearest first
+    pub fn radius(&self, lon: f64, lat: f64, radius: f64) -> Vec<GeoPoint> {
+        let d_lat = (radius / geohash::EARTH_RADIUS).to_degrees();
+        let d_lon = d_lat / lat.to_radians().cos().max(0.01);
+        let area = Viewport {
+            min_lon: lon - d_lon,
+            min_lat: lat - d_lat,
+            max_lon: lon + d_lon,
+            max_lat: lat + d_lat,
+        };
+
+        let mut found: Vec<(f64, GeoPoint)> = self
+            .within_box(&area)
+            .into_iter()
+            .map(|p| (geohash::distance(lon, lat, p.lon, p.lat), p))
+            .filter(|(distance, _)| *distance <= radius)
+            .collect();
+        found.sort_by(|a, b| a.0.total_cmp(&b.0));
+        found.into_iter().map(|(_, p)| p).collect()
+    }
+
+    // Best-first search: nodes and points share one queue ordered by distance, a point that
+    // comes out of the queue is closer than anything still in it
+    pub fn knn(&self, lon: f64, lat: f64, k: usize) -> Vec<GeoPoint> {
+        let mut queue: BinaryHeap<Candidate> = BinaryHeap::new();
+        let mut found = vec![];
+        queue.push(Candidate {
+            distance: 0.0,
+            item: Item::Node(&self.root),
+        });
+
+        while let Some(candidate) = queue.pop() {
+            if found.len() >= k {
+                break;
+            }
+            match candidate.item {
+                Item::Point(point) => found.push(point.clone()),
+                Item::Node(node) => {
+                    for point in &node.points {
+                        queue.push(Candidate {
+                            distance: geohash::distance(lon, lat, point.lon, point.lat),
+                            item: Item::Point(point),
+                        });
+                    }
+                    if let Some(children) = &node.children {
+                        for child in children.iter() {
+                            queue.push(Candidate {
+                                distance: child.min_distance(lon, lat),
+                                item: Item::Node(child),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        found
+    }
+}
+
+impl Node {
+    fn new(bounds: Viewport, depth: usize) -> Node {
+        Node {
+            bounds,
+            depth,
+            points: vec![],
+            children: None,
+        }
+    }
+
+    fn insert(&mut self, point: GeoPoint) {
+        if let Some(children) = &mut self.children {
+            let quadrant = quadrant_of(&self.bounds, point.lon, point.lat);
+            children[quadrant].insert(point);
+            return;
+        }
+        self.points.push(point);
+        if self.points.len() > NODE_CAPACITY && self.depth < MAX_DEPTH {
+            self.split();
+        }
+    }
+
+    fn split(&mut self) {
+        let (mid_lon, mid_lat) = self.bounds.center();
+        let b = &self.bounds;
+        let depth = self.depth + 1;
+        let mut children = Box::new([
+            Node::new(
+                Viewport {
+                    min_lon: b.min_lon,
+                    min_lat: b.min_lat,
+                    max_lon: mid_lon,
+                    max_lat: mid_lat,
+                },
+                depth,
+            ),
+            Node::new(
+                Viewport {
+                    min_lon: mid_lon,
+                    min_lat: b.min_lat,
+                    max_lon: b.max_lon,
+                    max_lat: mid_lat,
+                },
+                depth,
+            ),
+            Node::new(
+                Viewport {
+                    min_lon: b.min_lon,
+                    min_lat: mid_lat,
+                    max_lon: mid_lon,
+                    max_lat: b.max_lat,
+                },
+                depth,
+            ),
+            Node::new(
+                Viewport {
+                    min_lon: mid_lon,
+                    min_lat: mid_lat,
+                    max_lon: b.max_lon,
+                    max_lat: b.max_lat,
+                },
+                depth,
+            ),
+        ]);
+        for point in self.points.drain(..) {
+            let quadrant = quadrant_of(&self.bounds, point.lon, point.lat);
+            children[quadrant].insert(point);
+        }
+        self.children = Some(children);
+    }
+
+    fn remove(&mut self, id: u64, lon: f64, lat: f64) {
+        if let Some(children) = &mut self.children {
+            let quadrant = quadrant_of(&self.bounds, lon, lat);
+            children[quadrant].remove(id, lon, lat);
+            return;
+        }
+        self.points.retain(|p| p.id != id);
+    }
+
+    fn collect(&self, area: &Viewport, found: &mut Vec<GeoPoint>) {
+        if !intersects(&self.bounds, area) {
+            return;
+        }
+        for point in &self.points {
+            if area.contains(point.lon, point.lat) {
+                found.push(point.clone());
+            }
+        }
+        if let Some(children) = &self.children {
+            for child in children.iter() {
+                child.collect(area, found);
+            }
+        }
+    }
+
+    // Distance to the closest point of the node, close enough on the sphere for ordering
+    fn min_distance(&self, lon: f64, lat: f64) -> f64 {
+        let closest_lon = lon.clamp(self.bounds.min_lon, self.bounds.max_lon);
+        let closest_lat = lat.clamp(self.bounds.min_lat, self.bounds.max_lat);
+        geohash::distance(lon, lat, closest_lon, closest_lat)
+    }
+}
+
+fn quadrant_of(bounds: &Viewport, lon: f64, lat: f64) -> usize {
+    let (mid_lon, mid_lat) = bounds.center();
+    let east = (lon >= mid_lon) as usize;
+    let north = (lat >= mid_lat) as usize;
+    north * 2 + east
+}
+
+fn intersects(a: &Viewport, b: &Viewport) -> bool {
+    a.min_lon <= b.max_lon
+        && a.max_lon >= b.min_lon
+        && a.min_lat <= b.max_lat
+        && a.max_lat >= b.min_lat
+}
+
+enum Item<'a> {
+    Node(&'a Node),
+    Point(&'a GeoPoint),
+}
+
+struct Candidate<'a> {
+    distance: f64,
+    item: Item<'a>,
+}
+
+// BinaryHeap is a max-heap, reversed so that the closest candidate comes out first
+impl Ord for Candidate<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.distance.total_cmp(&self.distance)
+    }
+}
+
+impl PartialOrd for Candidate<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.distance == other.distance
+    }
+}
+
+impl Eq for Candidate<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Spread around Berlin, a simple LCG keeps it the same on every run
+    fn points(count: u64) -> Vec<GeoPoint> {
+        let mut seed: u64 = 42;
+        let mut next = move || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 11) as f64 / (1u64 << 53) as f64
+        };
+        (0..count)
+            .map(|id| GeoPoint {
+                id,
+                lon: 13.0 + next(),
+                lat: 52.0 + next(),
+            })
+            .collect()
+    }
+
+    fn tree_of(points: &[GeoPoint]) -> QuadTree {
+        let mut tree = QuadTree::new();
+        for point in points {
+            tree.insert(point.clone());
+        }
+        tree
+    }
+
+    fn ids(points: &[GeoPoint]) -> Vec<u64> {
+        let mut ids: Vec<u64> = points.iter().map(|p| p.id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn insert_move_and_remove() {
+        let mut tree = QuadTree::new();
+        tree.insert(GeoPoint {
+            id: 1,
+            lon: 13.4,
+            lat: 52.5,
+        });
+        assert_eq!(tree.len(), 1);
+        assert_eq!(ids(&tree.radius(13.4, 52.5, 100.0)), [1]);
+
+        // inserting the same id again moves it
+        tree.insert(GeoPoint {
+            id: 1,
+            lon: 2.35,
+            lat: 48.85,
+        });
+        assert_eq!(tree.len(), 1);
+        assert!(tree.radius(13.4, 52.5, 1000.0).is_empty());
+        assert_eq!(ids(&tree.radius(2.35, 48.85, 100.0)), [1]);
+
+        tree.remove(1);
+        assert_eq!(tree.len(), 0);
+        assert!(tree.radius(2.35, 48.85, 1000.0).is_empty());
+        // removing what isn't there does nothing
+        tree.remove(1);
+    }
+
+    #[test]
+    fn nodes_split_and_keep_every_point() {
+        let points = points(1000);
+        let tree = tree_of(&points);
+        assert_eq!(tree.len(), 1000);
+        assert!(tree.root.children.is_some());
+
+        let area = Viewport {
+            min_lon: 13.2,
+            min_lat: 52.3,
+            max_lon: 13.7,
+            max_lat: 52.6,
+        };
+        let expected: Vec<GeoPoint> = points
+            .iter()
+            .filter(|p| area.contains(p.lon, p.lat))
+            .cloned()
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(ids(&tree.within_box(&area)), ids(&expected));
+    }
+
+    #[test]
+    fn moves_and_removals_after_a_split() {
+        let points = points(500);
+        let mut tree = tree_of(&points);
+        for id in 0..100 {
+            tree.remove(id);
+        }
+        for id in 100..200 {
+            tree.insert(GeoPoint {
+                id,
+                lon: -0.1,
+                lat: 51.5,
+            });
+        }
+        assert_eq!(tree.len(), 400);
+        let world = Viewport {
+            min_lon: -180.0,
+            min_lat: -90.0,
+            max_lon: 180.0,
+            max_lat: 90.0,
+        };
+        assert_eq!(tree.within_box(&world).len(), 400);
+        assert_eq!(tree.radius(-0.1, 51.5, 10.0).len(), 100);
+    }
+
+    #[test]
+    fn radius_across_node_bounds() {
+        let points = points(2000);
+        let tree = tree_of(&points);
+        // wide enough to take in many leaves, their bounds cut through it
+        let (lon, lat, radius) = (13.5, 52.5, 10_000.0);
+        let found = tree.radius(lon, lat, radius);
+        let expected: Vec<GeoPoint> = points
+            .iter()
+            .filter(|p| geohash::distance(lon, lat, p.lon, p.lat) <= radius)
+            .cloned()
+            .collect();
+        assert!(expected.len() > NODE_CAPACITY);
+        assert_eq!(ids(&found), ids(&expected));
+
+        let distances: Vec<f64> = found
+            .iter()
+            .map(|p| geohash::distance(lon, lat, p.lon, p.lat))
+            .collect();
+        assert!(distances.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn knn_is_nearest_first() {
+        let points = points(2000);
+        let tree = tree_of(&points);
+        let (lon, lat) = (13.31, 52.77);
+        let found = tree.knn(lon, lat, 25);
+        assert_eq!(found.len(), 25);
+
+        let mut expected = points.clone();
+        expected.sort_by(|a, b| {
+            geohash::distance(lon, lat, a.lon, a.lat)
+                .total_cmp(&geohash::distance(lon, lat, b.lon, b.lat))
+        });
+        let found: Vec<u64> = found.iter().map(|p| p.id).collect();
+        let expected: Vec<u64> = expected.iter().take(25).map(|p| p.id).collect();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn knn_with_fewer_points_than_k() {
+        let tree = tree_of(&points(3));
+        assert_eq!(tree.knn(0.0, 0.0, 10).len(), 3);
+        assert!(QuadTree::new().knn(0.0, 0.0, 10).is_empty());
+    }
+}
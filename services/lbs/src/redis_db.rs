@@ -0,0 +1,415 @@
+use crate::config::{RedisConfig, ReplicaConfig};
+use crate::dbs::Result;
+use model::redis_connection::RedisConnection;
+use redis::{Cmd, ErrorKind, FromRedisValue, Pipeline, RedisError, RedisResult};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// A Redis primary with its read replicas. Writes, and reads that have to see them, go to the
+// primary. Other reads rotate over the replicas that are healthy, i.e. connected to the primary
+// and not too far behind it, and fall back to the primary when there is none or a replica fails.
+// Health is checked in the background, which also follows a failover when Sentinel is used
+#[derive(Clone)]
+pub struct RedisDB {
+    topology: Arc<RwLock<Topology>>,
+    next_replica: Arc<AtomicUsize>,
+    sentinel_master: Option<String>,
+}
+
+struct Topology {
+    primary: Member,
+    replicas: Vec<Member>,
+}
+
+struct Member {
+    uri: String,
+    connection: Option<RedisConnection>,
+    healthy: bool,
+    lag: Option<u64>,
+    error: Option<String>,
+}
+
+impl Member {
+    fn new(uri: String, connection: Option<RedisConnection>) -> Member {
+        Member {
+            uri,
+            healthy: connection.is_some(),
+            connection,
+            lag: None,
+            error: None,
+        }
+    }
+    fn to_json(&self) -> Value {
+        json!({
+            "uri": display_uri(&self.uri),
+            "healthy": self.healthy,
+            "lagBytes": self.lag,
+            "error": self.error
+        })
+    }
+}
+
+impl RedisDB {
+    pub async fn connect(config: &RedisConfig, replica_config: &ReplicaConfig) -> Result<RedisDB> {
+        if config.cluster {
+            return connect_cluster(config).await;
+        }
+        let (primary_uri, replica_uris) = match &config.sentinel_master {
+            Some(master) => discover(&replica_config.sentinels, master).await?,
+            None => (config.primary.clone(), config.replicas.clone()),
+        };
+        let primary = connect(&primary_uri).await?;
+        let mut replicas = vec![];
+        for uri in replica_uris {
+            let connection = match connect(&uri).await {
+                Ok(connection) => Some(connection),
+                Err(e) => {
+                    println!("Replica {} is not reachable: {:?}", display_uri(&uri), e);
+                    None
+                }
+            };
+            replicas.push(Member::new(uri, connection));
+        }
+
+        let db = RedisDB {
+            topology: Arc::new(RwLock::new(Topology {
+                primary: Member::new(primary_uri, Some(primary)),
+                replicas,
+            })),
+            next_replica: Arc::new(AtomicUsize::new(0)),
+            sentinel_master: config.sentinel_master.clone(),
+        };
+        if db.sentinel_master.is_some() || !db.topology.read().unwrap().replicas.is_empty() {
+            spawn_health_monitor(db.clone(), replica_config);
+        }
+        Ok(db)
+    }
+
+    pub fn primary(&self) -> RedisConnection {
+        let topology = self.topology.read().unwrap();
+        topology.primary.connection.clone().unwrap()
+    }
+
+    pub fn primary_uri(&self) -> String {
+        self.topology.read().unwrap().primary.uri.clone()
+    }
+
+    pub async fn read_cmd<T: FromRedisValue>(&self, cmd: &Cmd) -> RedisResult<T> {
+        self.read(|mut connection| async move { cmd.query_async(&mut connection).await })
+            .await
+    }
+
+    pub async fn read_pipe<T: FromRedisValue>(&self, pipe: &Pipeline) -> RedisResult<T> {
+        self.read(|mut connection| async move { pipe.query_async(&mut connection).await })
+            .await
+    }
+
+    async fn read<T, F, Fut>(&self, query: F) -> RedisResult<T>
+    where
+        F: Fn(RedisConnection) -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        if let Some((uri, connection)) = self.pick_replica() {
+            match timed(query(connection)).await {
+                Ok(value) => return Ok(value),
+                Err(e) if is_replica_failure(&e) => self.mark_failed(&uri, &e),
+                Err(e) => return Err(e),
+            }
+        }
+        // a primary that hangs fails the read like one that is down, so callers can fall back
+        timed(query(self.primary())).await
+    }
+
+    fn pick_replica(&self) -> Option<(String, RedisConnection)> {
+        let topology = self.topology.read().unwrap();
+        let healthy: Vec<&Member> = topology
+            .replicas
+            .iter()
+            .filter(|replica| replica.healthy && replica.connection.is_some())
+            .collect();
+        if healthy.is_empty() {
+            return None;
+        }
+        let replica = healthy[self.next_replica.fetch_add(1, Ordering::Relaxed) % healthy.len()];
+        Some((replica.uri.clone(), replica.connection.clone().unwrap()))
+    }
+
+    // Taken out of rotation until the next health check finds it fine again
+    fn mark_failed(&self, uri: &str, error: &RedisError) {
+        println!(
+            "Replica {} failed, reading from primary: {:?}",
+            display_uri(uri),
+            error
+        );
+        let mut topology = self.topology.write().unwrap();
+        if let Some(replica) = topology.replicas.iter_mut().find(|r| r.uri == uri) {
+            replica.healthy = false;
+            replica.error = Some(error.to_string());
+        }
+    }
+
+    // Pings the primary, replicas are reported as last seen by the health check
+    pub async fn health(&self) -> (bool, Value) {
+        let ping: redis::RedisResult<String> =
+            timed(async { redis::cmd("PING").query_async(&mut self.primary()).await }).await;
+        let ready = ping.is_ok();
+        let topology = self.topology.read().unwrap();
+        let healthy_replicas = topology.replicas.iter().filter(|r| r.healthy).count();
+        (
+            ready,
+            json!({
+                "primary": {
+                    "uri": display_uri(&topology.primary.uri),
+                    "healthy": ready,
+                    "error": ping.err().map(|e| e.to_string())
+                },
+                "sentinelMaster": self.sentinel_master,
+                "healthyReplicas": healthy_replicas,
+                "replicas": topology.replicas.iter().map(Member::to_json).collect::<Vec<Value>>()
+            }),
+        )
+    }
+
+    async fn check_replicas(&self, max_lag: u64) {
+        let primary_offset = match replication_info(&mut self.primary()).await {
+            Ok(info) => info
+                .get("master_repl_offset")
+                .and_then(|v| v.parse::<u64>().ok()),
+            Err(e) => {
+                println!("Failed to read replication info of the primary: {:?}", e);
+                None
+            }
+        };
+
+        let replicas: Vec<(String, Option<RedisConnection>)> = {
+            let topology = self.topology.read().unwrap();
+            topology
+                .replicas
+                .iter()
+                .map(|r| (r.uri.clone(), r.connection.clone()))
+                .collect()
+        };
+        for (uri, connection) in replicas {
+            let status = check_replica(&uri, connection, primary_offset, max_lag).await;
+            let mut topology = self.topology.write().unwrap();
+            if let Some(replica) = topology.replicas.iter_mut().find(|r| r.uri == uri) {
+                match status {
+                    Ok((connection, lag)) => {
+                        replica.connection = Some(connection);
+                        replica.lag = lag;
+                        replica.healthy = lag.map(|lag| lag <= max_lag).unwrap_or(true);
+                        replica.error = match replica.healthy {
+                            true => None,
+                            false => Some("Replica is behind the primary".to_string()),
+                        };
+                    }
+                    Err(e) => {
+                        // multiplexed connections don't reconnect by themselves, the next
+                        // check opens a new one
+                        replica.connection = None;
+                        replica.healthy = false;
+                        replica.error = Some(e.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    // Follows what Sentinel reports: a new primary after a failover, replicas coming and going
+    async fn rediscover(&self, sentinels: &[String], master: &str) -> Result<()> {
+        let (primary_uri, replica_uris) = discover(sentinels, master).await?;
+        if primary_uri != self.primary_uri() {
+            let connection = connect(&primary_uri).await?;
+            println!("Redis primary moved to {}", display_uri(&primary_uri));
+            self.topology.write().unwrap().primary = Member::new(primary_uri, Some(connection));
+        }
+        let mut topology = self.topology.write().unwrap();
+        topology.replicas.retain(|r| replica_uris.contains(&r.uri));
+        for uri in replica_uris {
+            if !topology.replicas.iter().any(|r| r.uri == uri) {
+                // connected by the health check that follows
+                let mut replica = Member::new(uri, None);
+                replica.error = Some("Not checked yet".to_string());
+                topology.replicas.push(replica);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn spawn_health_monitor(db: RedisDB, config: &ReplicaConfig) {
+    let interval = Duration::from_secs(config.check_interval);
+    let max_lag = config.max_lag;
+    let sentinels = config.sentinels.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Some(master) = &db.sentinel_master {
+                if let Err(e) = db.rediscover(&sentinels, master).await {
+                    println!("Sentinel discovery of {} failed: {:?}", master, e);
+                }
+            }
+            db.check_replicas(max_lag).await;
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+// Replica is usable when its link to the primary is up. Lag is how much of the replication
+// stream it has not applied yet, unknown when the primary could not be asked
+async fn check_replica(
+    uri: &str,
+    connection: Option<RedisConnection>,
+    primary_offset: Option<u64>,
+    max_lag: u64,
+) -> Result<(RedisConnection, Option<u64>)> {
+    let mut connection = match connection {
+        Some(connection) => connection,
+        None => connect(uri).await?,
+    };
+    let info = replication_info(&mut connection).await?;
+    if info.get("master_link_status").map(|s| s.as_str()) != Some("up") {
+        return Err("Replica is not connected to the primary".into());
+    }
+    let offset = info
+        .get("slave_repl_offset")
+        .and_then(|v| v.parse::<u64>().ok());
+    let lag = match (primary_offset, offset) {
+        (Some(primary), Some(replica)) => Some(primary.saturating_sub(replica)),
+        _ => None,
+    };
+    if lag.map(|lag| lag > max_lag).unwrap_or(false) {
+        println!("Replica {} is {:?} bytes behind", display_uri(uri), lag);
+    }
+    Ok((connection, lag))
+}
+
+async fn replication_info(
+    connection: &mut RedisConnection,
+) -> redis::RedisResult<HashMap<String, String>> {
+    let info: String = timed(async {
+        redis::cmd("INFO")
+            .arg("replication")
+            .query_async(connection)
+            .await
+    })
+    .await?;
+    Ok(info
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+        .collect())
+}
+
+// Asks the sentinels in turn for the current primary and its replicas
+async fn discover(sentinels: &[String], master: &str) -> Result<(String, Vec<String>)> {
+    if sentinels.is_empty() {
+        return Err(format!("REDIS_SENTINELS must be set to look up {}", master).into());
+    }
+    for sentinel in sentinels {
+        match discover_with(sentinel, master).await {
+            Ok(found) => return Ok(found),
+            Err(e) => println!("Sentinel {} did not answer: {:?}", display_uri(sentinel), e),
+        }
+    }
+    Err(format!("No sentinel knows {}", master).into())
+}
+
+async fn discover_with(sentinel: &str, master: &str) -> Result<(String, Vec<String>)> {
+    let mut connection = connect(sentinel).await?;
+    let (host, port): (String, u16) = timed(async {
+        redis::cmd("SENTINEL")
+            .arg("get-master-addr-by-name")
+            .arg(master)
+            .query_async(&mut connection)
+            .await
+    })
+    .await?;
+    let replicas: Vec<HashMap<String, String>> = timed(async {
+        redis::cmd("SENTINEL")
+            .arg("replicas")
+            .arg(master)
+            .query_async(&mut connection)
+            .await
+    })
+    .await?;
+
+    let replicas = replicas
+        .into_iter()
+        .filter(|replica| {
+            let flags = replica.get("flags").map(|f| f.as_str()).unwrap_or("");
+            !flags.contains("down") && !flags.contains("disconnected")
+        })
+        .filter_map(|replica| {
+            Some(format!(
+                "redis://{}:{}",
+                replica.get("ip")?,
+                replica.get("port")?
+            ))
+        })
+        .collect();
+    Ok((format!("redis://{}:{}", host, port), replicas))
+}
+
+// A cluster serves reads from its own replicas and fails over by itself, so there is nothing to
+// monitor. Pub/sub messages reach every node, the first seed stands in as the primary for them
+async fn connect_cluster(config: &RedisConfig) -> Result<RedisDB> {
+    let seeds: Vec<String> = config
+        .primary
+        .split(',')
+        .map(|uri| uri.trim().to_string())
+        .filter(|uri| !uri.is_empty())
+        .collect();
+    let connection =
+        match tokio::time::timeout(CONNECT_TIMEOUT, RedisConnection::connect_cluster(&seeds)).await
+        {
+            Ok(connection) => connection?,
+            Err(_) => return Err("Timed out connecting to the Redis cluster".into()),
+        };
+    let first_seed = seeds.first().cloned().unwrap_or_default();
+    Ok(RedisDB {
+        topology: Arc::new(RwLock::new(Topology {
+            primary: Member::new(first_seed, Some(connection)),
+            replicas: vec![],
+        })),
+        next_replica: Arc::new(AtomicUsize::new(0)),
+        sentinel_master: None,
+    })
+}
+
+async fn connect(uri: &str) -> Result<RedisConnection> {
+    match tokio::time::timeout(CONNECT_TIMEOUT, RedisConnection::connect(uri)).await {
+        Ok(connection) => Ok(connection?),
+        Err(_) => Err(format!("Timed out connecting to {}", display_uri(uri)).into()),
+    }
+}
+
+async fn timed<T>(query: impl Future<Output = redis::RedisResult<T>>) -> redis::RedisResult<T> {
+    match tokio::time::timeout(QUERY_TIMEOUT, query).await {
+        Ok(result) => result,
+        Err(_) => Err(RedisError::from((ErrorKind::IoError, "Query timed out"))),
+    }
+}
+
+// Errors that say something about the replica rather than the query
+fn is_replica_failure(error: &RedisError) -> bool {
+    error.is_io_error()
+        || error.is_connection_dropped()
+        || error.is_timeout()
+        || error.kind() == ErrorKind::BusyLoadingError
+        || error.kind() == ErrorKind::ReadOnly
+}
+
+// Uris can carry a password, keep it out of logs and readiness output
+fn display_uri(uri: &str) -> String {
+    match (uri.split_once("://"), uri.rsplit_once('@')) {
+        (Some((scheme, _)), Some((_, host))) => format!("{}://{}", scheme, host),
+        _ => uri.to_string(),
+    }
+}
@@ -3,6 +3,11 @@ use std::io::Read;
 use std::net::TcpStream;
 
 use crate::ROUTES;
+use serde_json::Value;
+
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+// larger bodies are cut off, which leaves them unparsed
+const MAX_BODY_SIZE: usize = 1024 * 1024;
 
 #[derive(Clone)]
 pub struct Request<'a> {
@@ -10,12 +15,17 @@ pub struct Request<'a> {
     pub method: Option<String>,
     pub user_agent: Option<String>,
     pub content_type: Option<String>,
+    pub accept: Option<String>,
     pub content_length: Option<String>,
     pub path: Option<String>,
     pub http_version: Option<String>,
     pub query: HashMap<String, String>,
     pub matched_path: Option<&'a str>,
     pub params: HashMap<String, String>,
+    // every header by its lowercased name, for the ones that don't have a field of their own
+    pub headers: HashMap<String, String>,
+    // json body, None when there is none or it doesn't parse
+    pub body: Option<Value>,
 }
 impl<'a> Request<'a> {
     pub fn default<'b>() -> Request<'b> {
@@ -25,23 +35,51 @@ impl<'a> Request<'a> {
             user_agent: Some(String::from("unknown")),
             path: Some(String::from("/")),
             content_type: Some(String::from("application/json")),
+            accept: None,
             content_length: Some(String::from("0")),
             http_version: Some(String::from("HTTP/1.1")),
             query: HashMap::default(),
             matched_path: None,
             params: HashMap::default(),
+            headers: HashMap::default(),
+            body: None,
         }
     }
 }
 pub fn parse_tcp_stream(stream: &mut TcpStream, request_struct: &mut Request) {
     let mut buffer = [0; 1024];
+    let mut raw: Vec<u8> = vec![];
+
+    // headers first, then as much of the body as Content-Length announces
+    let mut body_start = None;
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => raw.extend_from_slice(&buffer[..size]),
+            Err(_) => {
+                println!("Error reading incoming stream");
+                return;
+            }
+        }
+        if body_start.is_none() {
+            body_start = raw
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .map(|end| end + 4);
+        }
+        let expected = match body_start {
+            Some(start) => start + content_length(&raw[..start]).min(MAX_BODY_SIZE),
+            None => MAX_HEADER_SIZE,
+        };
+        if raw.len() >= expected {
+            break;
+        }
+    }
+    let body_start = body_start.unwrap_or(raw.len());
 
-    let request_raw;
-    if let Ok(size) = stream.read(&mut buffer) {
-        request_raw = String::from_utf8_lossy(&buffer[..size])
-    } else {
-        println!("Error reading incoming stream");
-        return;
+    let request_raw = String::from_utf8_lossy(&raw[..body_start]);
+    if body_start < raw.len() {
+        request_struct.body = serde_json::from_slice(&raw[body_start..]).ok();
     }
 
     println!("Raw: {:?}", request_raw);
@@ -61,26 +99,33 @@ pub fn parse_tcp_stream(stream: &mut TcpStream, request_struct: &mut Request) {
         if ind == 0 {
             request_struct.method = Some(splitted[0].to_string());
             request_struct.path = Some(splitted[1].to_string());
-            if request_struct.path.is_some() && request_struct.method.is_some() {
-                let matched_path = path_parser(
-                    request_struct.path.as_ref().unwrap(),
-                    &mut request_struct.params,
-                    request_struct.method.as_ref().unwrap(),
-                );
+            if let (Some(path), Some(method)) = (&request_struct.path, &request_struct.method) {
+                let matched_path = path_parser(path, &mut request_struct.params, method);
                 request_struct.matched_path = matched_path;
-                parse_query(
-                    request_struct.path.as_ref().unwrap(),
-                    &mut request_struct.query,
-                )
+                parse_query(path, &mut request_struct.query)
             }
             request_struct.http_version = Some(splitted[2].to_string());
         }
+        if ind > 0 {
+            if let Some((name, value)) = line.unwrap().split_once(':') {
+                request_struct
+                    .headers
+                    .insert(name.trim().to_lowercase(), value.trim().to_string());
+            }
+        }
         if splitted[0] == "Host:" {
             request_struct.host = Some(splitted[1].to_string());
         }
         if splitted[0] == "Content-Type:" {
             request_struct.content_type = Some(splitted[1].to_string());
         }
+        if splitted[0].eq_ignore_ascii_case("Accept:") {
+            // unlike other headers, accept is a list, so we keep all of it
+            request_struct.accept = line
+                .unwrap()
+                .split_once(':')
+                .map(|h| h.1.trim().to_string());
+        }
         if splitted[0] == "User-Agent:" {
             request_struct.user_agent = Some(splitted[1].to_string());
         }
@@ -92,6 +137,15 @@ pub fn parse_tcp_stream(stream: &mut TcpStream, request_struct: &mut Request) {
     }
 }
 
+fn content_length(headers: &[u8]) -> usize {
+    String::from_utf8_lossy(headers)
+        .split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0)
+}
+
 fn parse_query(path: &str, query_map: &mut HashMap<String, String>) {
     let query_raw = path.split_once('?');
     if query_raw.is_none() {
@@ -102,9 +156,32 @@ fn parse_query(path: &str, query_map: &mut HashMap<String, String>) {
 
     for pair in pairs {
         let splitted = pair.split_once('=').unwrap_or(("", ""));
-        query_map.insert(splitted.0.to_string(), splitted.1.to_string());
+        query_map.insert(splitted.0.to_string(), decode_query_value(splitted.1));
     }
 }
+
+// Query values come percent-encoded from the browser (e.g. `q=joe%27s+cafe`)
+fn decode_query_value(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'+', _) => decoded.push(b' '),
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 2;
+            }
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
 fn construct_params<'b>(
     split_existing: &mut VecDeque<&'b str>,
     split_requested: &mut VecDeque<&'b str>,
@@ -156,6 +233,9 @@ fn path_parser<'a>(
 }
 
 fn compare_paths(requested: &VecDeque<&str>, existing: &VecDeque<&str>) -> bool {
+    if requested.len() != existing.len() {
+        return false;
+    }
     for (ind, item) in requested.iter().enumerate() {
         let current_requested = *item;
         let current_existing = existing[ind];
@@ -1,90 +1,126 @@
 use serde_json::{json, Value};
 
-const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n";
-const BAD_REQUEST: &str = "HTTP/1.1 400 BAD_REQUEST\r\nContent-Type: application/json\r\n";
-const UNAUTHORIZED: &str = "HTTP/1.1 401 UNAUTHORIZED\r\nContent-Type: application/json\r\n";
-const NOT_FOUND: &str = "HTTP/1.1 404 NOT FOUND\r\nContent-Type: application/json\r\n";
-const INTERNAL_SERVER_ERROR: &str =
-    "HTTP/1.1 500 INTERNAL SERVER ERROR\r\nContent-Type: application/json\r\n";
+const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\n";
+const BAD_REQUEST: &str = "HTTP/1.1 400 BAD_REQUEST\r\n";
+const UNAUTHORIZED: &str = "HTTP/1.1 401 UNAUTHORIZED\r\n";
+const NOT_FOUND: &str = "HTTP/1.1 404 NOT FOUND\r\n";
+const INTERNAL_SERVER_ERROR: &str = "HTTP/1.1 500 INTERNAL SERVER ERROR\r\n";
+const SERVICE_UNAVAILABLE: &str = "HTTP/1.1 503 SERVICE UNAVAILABLE\r\n";
 
 pub struct Response {
     pub status: u16,
     pub body: Value,
+    pub content_type: String,
+    pub headers: Vec<(String, String)>,
+    // When set, it is sent as is instead of the json body
+    pub raw_body: Option<Vec<u8>>,
 }
 
 #[allow(dead_code)]
 impl Response {
     pub fn default() -> Response {
-        Response {
-            status: 200,
-            body: json!({"message": "Request successfull!"}),
-        }
+        Response::success(json!({"message": "Request successfull!"}), None)
     }
     pub fn bad_request(message: Option<&str>) -> Response {
-        Response {
-            status: 400,
-            body: json!({"message": message.unwrap_or("Invalid request")}),
-        }
+        Response::with_status(
+            400,
+            json!({"message": message.unwrap_or("Invalid request")}),
+        )
     }
     pub fn unauthorized(message: Option<&str>) -> Response {
-        Response {
-            status: 401,
-            body: json!({"message": message.unwrap_or("Not authorized")}),
-        }
+        Response::with_status(401, json!({"message": message.unwrap_or("Not authorized")}))
     }
     pub fn success(data: Value, status: Option<u16>) -> Response {
-        Response {
-            status: status.unwrap_or(200),
-            body: data,
-        }
+        Response::with_status(status.unwrap_or(200), data)
+    }
+    pub fn binary(data: Vec<u8>, content_type: &str) -> Response {
+        let mut response = Response::with_status(200, Value::Null).content_type(content_type);
+        response.raw_body = Some(data);
+        response
     }
     pub fn internal(data: Option<Value>) -> Response {
-        Response {
-            status: 500,
-            body: data.unwrap_or(json!({"message": "Something went wrong!"})),
-        }
+        Response::with_status(
+            500,
+            data.unwrap_or(json!({"message": "Something went wrong!"})),
+        )
     }
     pub fn not_found(message: Option<&str>) -> Response {
+        Response::with_status(
+            404,
+            json!({"message": message.unwrap_or("Resource not found!")}),
+        )
+    }
+    pub fn unavailable(message: Option<&str>) -> Response {
+        Response::with_status(
+            503,
+            json!({"message": message.unwrap_or("Service unavailable")}),
+        )
+    }
+    pub fn content_type(mut self, content_type: &str) -> Response {
+        self.content_type = content_type.to_string();
+        self
+    }
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+    fn with_status(status: u16, body: Value) -> Response {
         Response {
-            status: 404,
-            body: json!({"message": message.unwrap_or("Resource not found!")}),
+            status,
+            body,
+            content_type: "application/json".to_string(),
+            headers: vec![],
+            raw_body: None,
         }
     }
-    pub fn to_response_string(&self) -> String {
+    pub fn to_response_bytes(&self) -> Vec<u8> {
         if self.status == 200 {
-            return self.construct_response_string(OK_RESPONSE);
+            return self.construct_response(OK_RESPONSE);
         }
         if self.status == 400 {
-            return self.construct_response_string(BAD_REQUEST);
+            return self.construct_response(BAD_REQUEST);
         }
         if self.status == 401 {
-            return self.construct_response_string(UNAUTHORIZED);
+            return self.construct_response(UNAUTHORIZED);
         }
         if self.status == 404 {
-            return self.construct_response_string(NOT_FOUND);
+            return self.construct_response(NOT_FOUND);
+        }
+        if self.status == 503 {
+            return self.construct_response(SERVICE_UNAVAILABLE);
         }
-        self.construct_response_string(INTERNAL_SERVER_ERROR)
+        self.construct_response(INTERNAL_SERVER_ERROR)
     }
-    fn construct_response_string(&self, response_type: &str) -> String {
+    fn construct_response(&self, response_type: &str) -> Vec<u8> {
+        let content_type = format!("Content-Type: {}\r\n", self.content_type);
         let allow_origin = "Access-Control-Allow-Origin: *\r\n".to_string();
         let allow_methods = "Access-Control-Allow-Methods: GET\r\n".to_string();
         let allow_headers = "Access-Control-Allow-Headers: DNT,User-Agent,X-Requested-With,If-Modified-Since,Cache-Control,Content-Type,Range\r\n".to_string();
+        let extra_headers: String = self
+            .headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+            .collect();
 
-        let content_length = format!(
-            "Content-Length: {}\r\n\r\n",
-            self.body.to_string().as_bytes().len()
-        );
+        let body = match &self.raw_body {
+            Some(raw) => raw.clone(),
+            None => self.body.to_string().into_bytes(),
+        };
+        let content_length = format!("Content-Length: {}\r\n\r\n", body.len());
         let server = format!("Server: {}\r\n", "Rust");
-        let response = format!(
-            "{}{}{}{}{}{}{}\r\n\r\n",
+        let mut response = format!(
+            "{}{}{}{}{}{}{}{}",
             response_type,
+            content_type,
             server,
             allow_headers,
             allow_methods,
             allow_origin,
+            extra_headers,
             content_length,
-            self.body
-        );
+        )
+        .into_bytes();
+        response.extend_from_slice(&body);
         response
     }
 }
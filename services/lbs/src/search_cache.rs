@@ -0,0 +1,137 @@
+use crate::dbs::{self, DBConnections, Result};
+use model::business::BusinessSummary;
+use model::geohash;
+use model::search_cache::{bucket_for, cell_index_key, entry_key};
+use redis::Pipeline;
+use serde_json::{json, Value};
+
+pub struct SearchCache {
+    pub ttl: u64,
+    pub stats: CacheStats,
+}
+
+#[derive(Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub bypassed: u64,
+}
+
+impl CacheStats {
+    pub fn to_json(&self) -> Value {
+        let lookups = self.hits + self.misses;
+        let hit_rate = if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        };
+        json!({
+            "hits": self.hits,
+            "misses": self.misses,
+            "bypassed": self.bypassed,
+            "hitRate": hit_rate
+        })
+    }
+}
+
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    Bypass,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Bypass => "BYPASS",
+        }
+    }
+}
+
+impl SearchCache {
+    pub fn new(ttl: u64) -> SearchCache {
+        SearchCache {
+            ttl,
+            stats: CacheStats::default(),
+        }
+    }
+}
+
+// Cached entries hold every business around the center of the query cell, far enough out to
+// cover a search of the bucket radius from any point of the cell. Exact radius and ordering are
+// applied per request, so a hit gives the same result as a direct search. Filters (e.g. openNow)
+// run after this, they don't need to be part of the key
+pub async fn find_businesses(
+    conns: &mut DBConnections,
+    lon: f64,
+    lat: f64,
+    radius: f64,
+    bypass: bool,
+) -> Result<(Vec<BusinessSummary>, CacheStatus)> {
+    let bucket = bucket_for(radius);
+    if bypass || conns.search_cache.ttl == 0 || bucket.is_none() {
+        conns.search_cache.stats.bypassed += 1;
+        let businesses = dbs::find_in_radius(conns, lon, lat, radius).await?;
+        return Ok((businesses, CacheStatus::Bypass));
+    }
+    let (bucket, precision) = bucket.unwrap();
+    let cell = geohash::encode(lon, lat, precision);
+    let key = entry_key(bucket, &cell);
+
+    let cached: Option<String> = conns
+        .redis_business
+        .read_cmd(redis::cmd("GET").arg(&key))
+        .await?;
+    let (candidates, status) = match cached.map(|c| serde_json::from_str(&c)) {
+        Some(Ok(candidates)) => {
+            conns.search_cache.stats.hits += 1;
+            (candidates, CacheStatus::Hit)
+        }
+        _ => {
+            conns.search_cache.stats.misses += 1;
+            let candidates = fill_entry(conns, &key, bucket, &cell, precision).await?;
+            (candidates, CacheStatus::Miss)
+        }
+    };
+
+    let mut found: Vec<(f64, BusinessSummary)> = candidates
+        .into_iter()
+        .map(|b: BusinessSummary| (geohash::distance(lon, lat, b.lon as f64, b.lat as f64), b))
+        .filter(|(distance, _)| *distance <= radius)
+        .collect();
+    found.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    Ok((found.into_iter().map(|(_, b)| b).collect(), status))
+}
+
+async fn fill_entry(
+    conns: &mut DBConnections,
+    key: &str,
+    bucket: u32,
+    cell: &str,
+    precision: usize,
+) -> Result<Vec<BusinessSummary>> {
+    let area = geohash::decode(cell).ok_or("Invalid geohash cell")?;
+    let (lon, lat) = area.center();
+    let reach = bucket as f64 + area.half_diagonal();
+    let candidates = dbs::find_in_radius(conns, lon, lat, reach).await?;
+
+    // register the entry under every cell it can hold businesses from, the api clears those
+    // sets when a business in the cell is written
+    let ttl = conns.search_cache.ttl;
+    let mut pipe = Pipeline::new();
+    pipe.set_ex(key, serde_json::to_string(&candidates)?, ttl)
+        .ignore();
+    for covered in geohash::covering_cells(lon, lat, reach, precision) {
+        let index = cell_index_key(bucket, &covered);
+        pipe.sadd(&index, key).ignore();
+        pipe.expire(&index, ttl as i64).ignore();
+    }
+    let _: () = pipe
+        .query_async(&mut conns.redis_business.primary())
+        .await?;
+
+    Ok(candidates)
+}
@@ -0,0 +1,156 @@
+use crate::dbs::Result;
+use crate::redis_db::RedisDB;
+use redis::Value;
+use serde::Serialize;
+
+// Suggestion documents live in redis-geo (redis-stack) as `suggest:<id>` hashes, written by the api
+// on create/update. RediSearch keeps the index below in sync with those hashes by itself
+pub const SUGGEST_INDEX: &str = "idx:suggest";
+pub const SUGGEST_PREFIX: &str = "suggest:";
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Suggestion {
+    pub id: u64,
+    pub name: String,
+    pub r#type: String,
+}
+
+pub async fn ensure_index(redis: &RedisDB) -> Result<()> {
+    let created: redis::RedisResult<()> = redis::cmd("FT.CREATE")
+        .arg(SUGGEST_INDEX)
+        .arg("ON")
+        .arg("HASH")
+        .arg("PREFIX")
+        .arg(1)
+        .arg(SUGGEST_PREFIX)
+        .arg("SCHEMA")
+        .arg("name")
+        .arg("TEXT")
+        .arg("WEIGHT")
+        .arg(5.0)
+        .arg("type")
+        .arg("TEXT")
+        .arg("location")
+        .arg("GEO")
+        .query_async(&mut redis.primary())
+        .await;
+
+    match created {
+        Ok(_) => Ok(()),
+        // index survives restarts of lbs, so this is the usual case
+        Err(e) if e.to_string().contains("Index already exists") => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn find_suggestions(
+    redis: &RedisDB,
+    text: &str,
+    lon: f64,
+    lat: f64,
+    radius: f64,
+    limit: usize,
+) -> Result<Vec<Suggestion>> {
+    let query = build_query(text, lon, lat, radius);
+    if query.is_none() {
+        return Ok(vec![]);
+    }
+
+    let raw: Value = redis
+        .read_cmd(
+            redis::cmd("FT.SEARCH")
+                .arg(SUGGEST_INDEX)
+                .arg(query.unwrap())
+                .arg("RETURN")
+                .arg(2)
+                .arg("name")
+                .arg("type")
+                .arg("LIMIT")
+                .arg(0)
+                .arg(limit),
+        )
+        .await?;
+
+    Ok(parse_search_reply(&raw))
+}
+
+// Every word of the input has to match. Words are matched as prefixes, since the user is still
+// typing, and fuzzily so that a typo or two does not empty the list. RediSearch takes the edit
+// distance from the number of `%` around the term
+fn build_query(text: &str, lon: f64, lat: f64, radius: f64) -> Option<String> {
+    let terms: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let word = word.to_lowercase();
+            match word.chars().count() {
+                0..=2 => format!("{}*", word),
+                3..=5 => format!("({}*|%{}%)", word, word),
+                _ => format!("({}*|%%{}%%)", word, word),
+            }
+        })
+        .collect();
+
+    if terms.is_empty() {
+        return None;
+    }
+
+    Some(format!(
+        "@name|type:({}) @location:[{} {} {} m]",
+        terms.join(" "),
+        lon,
+        lat,
+        radius
+    ))
+}
+
+fn parse_search_reply(raw: &Value) -> Vec<Suggestion> {
+    let mut result = vec![];
+    let items = match raw {
+        Value::Bulk(items) if !items.is_empty() => items,
+        _ => return result,
+    };
+
+    // reply is [total, key, [field, value, ...], key, [field, value, ...], ...]
+    for pair in items[1..].chunks(2) {
+        if pair.len() != 2 {
+            continue;
+        }
+        let id = match value_to_string(&pair[0])
+            .strip_prefix(SUGGEST_PREFIX)
+            .map(|id| id.parse::<u64>())
+        {
+            Some(Ok(id)) => id,
+            _ => continue,
+        };
+        let mut suggestion = Suggestion {
+            id,
+            name: "null".to_string(),
+            r#type: "null".to_string(),
+        };
+        if let Value::Bulk(fields) = &pair[1] {
+            for field in fields.chunks(2) {
+                if field.len() != 2 {
+                    continue;
+                }
+                match value_to_string(&field[0]).as_str() {
+                    "name" => suggestion.name = value_to_string(&field[1]),
+                    "type" => suggestion.r#type = value_to_string(&field[1]),
+                    _ => continue,
+                }
+            }
+        }
+        result.push(suggestion);
+    }
+
+    result
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::Data(bytes) => String::from_utf8_lossy(bytes).to_string(),
+        Value::Status(status) => status.to_owned(),
+        _ => "".to_string(),
+    }
+}
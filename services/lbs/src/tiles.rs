@@ -0,0 +1,127 @@
+use crate::cluster;
+use crate::dbs::{DBConnections, Result};
+use crate::geo_index::Viewport;
+use crate::mvt::{self, Layer, MvtValue};
+use std::f64::consts::PI;
+
+// Below this zoom a tile covers too many businesses to send one by one, so they are clustered
+// the same way `/clusters` does it
+pub const CLUSTER_BELOW_ZOOM: u8 = 13;
+pub const TILE_CACHE_CONTROL: &str = "public, max-age=300";
+
+pub struct TileId {
+    pub z: u8,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl TileId {
+    pub fn parse(z: &str, x: &str, y: &str) -> Option<TileId> {
+        let z = z.parse::<u8>().ok()?;
+        let x = x.parse::<u32>().ok()?;
+        let y = y.strip_suffix(".mvt")?.parse::<u32>().ok()?;
+        if z > cluster::MAX_ZOOM || x >= 1 << z || y >= 1 << z {
+            return None;
+        }
+        Some(TileId { z, x, y })
+    }
+
+    pub fn viewport(&self) -> Viewport {
+        Viewport {
+            min_lon: tile_lon(self.x, self.z),
+            min_lat: tile_lat(self.y + 1, self.z),
+            max_lon: tile_lon(self.x + 1, self.z),
+            max_lat: tile_lat(self.y, self.z),
+        }
+    }
+
+    // Web mercator position of the point inside of this tile, in tile extent units
+    fn project(&self, lon: f64, lat: f64) -> (i32, i32) {
+        let scale = (1u64 << self.z) as f64;
+        let lat = lat.to_radians();
+        let world_x = (lon + 180.0) / 360.0;
+        let world_y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0;
+        let x = (world_x * scale - self.x as f64) * mvt::EXTENT as f64;
+        let y = (world_y * scale - self.y as f64) * mvt::EXTENT as f64;
+        (x.round() as i32, y.round() as i32)
+    }
+}
+
+// Businesses are clustered below CLUSTER_BELOW_ZOOM, and above it too when the tile has more
+// than cluster::MAX_DETAILED_POINTS of them, so that a tile never reads every business of a
+// crowded area. Positions are read up to cluster::MAX_VIEWPORT_POINTS like `/clusters` does,
+// which keeps the low zoom tiles covering whole continents cheap
+pub async fn build_tile(conns: &mut DBConnections, tile: &TileId) -> Result<Vec<u8>> {
+    let (found, _) = conns
+        .geo_index
+        .within_box(&tile.viewport(), cluster::MAX_VIEWPORT_POINTS)
+        .await?;
+    let mut businesses = Layer::new("businesses");
+    let mut clusters = Layer::new("clusters");
+
+    if tile.z >= CLUSTER_BELOW_ZOOM && found.len() <= cluster::MAX_DETAILED_POINTS {
+        let points = cluster::read_details(conns, found).await?;
+        for point in points {
+            let (x, y) = tile.project(point.lon, point.lat);
+            businesses.add_point(
+                point.id,
+                x,
+                y,
+                vec![
+                    ("id", MvtValue::Uint(point.id)),
+                    ("name", MvtValue::String(point.name)),
+                    ("type", MvtValue::String(point.r#type)),
+                    ("stars", MvtValue::Uint(point.stars as u64)),
+                ],
+            );
+        }
+        return Ok(mvt::encode_tile(&[businesses]));
+    }
+
+    let precision = cluster::precision_for_zoom(tile.z);
+    for group in cluster::cluster_positions(conns, found, precision).await? {
+        let top = group.representative;
+        if group.count == 1 {
+            let (x, y) = tile.project(top.lon, top.lat);
+            businesses.add_point(
+                top.id,
+                x,
+                y,
+                vec![
+                    ("id", MvtValue::Uint(top.id)),
+                    ("name", MvtValue::String(top.name)),
+                    ("type", MvtValue::String(top.r#type)),
+                    ("stars", MvtValue::Uint(top.stars as u64)),
+                ],
+            );
+            continue;
+        }
+        let (x, y) = tile.project(group.lon, group.lat);
+        clusters.add_point(
+            top.id,
+            x,
+            y,
+            vec![
+                ("cell", MvtValue::String(group.cell)),
+                ("count", MvtValue::Uint(group.count as u64)),
+                ("lat", MvtValue::Double(group.lat)),
+                ("lon", MvtValue::Double(group.lon)),
+                ("id", MvtValue::Uint(top.id)),
+                ("name", MvtValue::String(top.name)),
+                ("type", MvtValue::String(top.r#type)),
+                ("stars", MvtValue::Uint(top.stars as u64)),
+            ],
+        );
+    }
+
+    Ok(mvt::encode_tile(&[businesses, clusters]))
+}
+
+fn tile_lon(x: u32, z: u8) -> f64 {
+    x as f64 / (1u64 << z) as f64 * 360.0 - 180.0
+}
+
+fn tile_lat(y: u32, z: u8) -> f64 {
+    let n = PI - 2.0 * PI * y as f64 / (1u64 << z) as f64;
+    n.sinh().atan().to_degrees()
+}
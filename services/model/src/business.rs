@@ -0,0 +1,435 @@
+use crate::hash_codec;
+use crate::hours::OpeningHours;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+
+// Version of the stored business record, kept in Mongo documents and Redis hashes. Records from
+// before the field existed have the shape of version 1. Raise it when a field changes meaning or
+// type, readers refuse records newer than they know
+pub const SCHEMA_VERSION: u32 = 1;
+
+// Hash fields of the summary view, what search results are made of
+pub const SUMMARY_FIELDS: [&str; 8] = [
+    "name", "type", "stars", "lat", "lon", "opensAt", "closesAt", "hours",
+];
+// Hash fields of the details view, the rest of the full record
+pub const DETAIL_FIELDS: [&str; 5] = ["zipCode", "averagePrice", "description", "email", "phone"];
+
+// Mongo field of a soft deleted business, the time it was deleted. Such a document is only kept
+// to be restored, every read skips it and the business is gone from Redis
+pub const DELETED_AT_FIELD: &str = "deletedAt";
+
+// The full record, as the api stores it in Mongo and in the business Redis. The hash is keyed by
+// the id, which is not repeated inside it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BusinessData {
+    #[serde(default = "first_version")]
+    pub schema_version: u32,
+    pub id: Option<u64>,
+    // the detail fields were not always written, records without them show them empty
+    #[serde(default)]
+    pub zip_code: String,
+    pub name: String,
+    pub stars: u8,
+    pub r#type: String,
+    pub lat: f32,
+    pub lon: f32,
+    pub opens_at: u8,
+    pub closes_at: u8,
+    #[serde(default)]
+    pub average_price: u8,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub email: String,
+    #[serde(default)]
+    pub phone: String,
+    #[serde(default)]
+    pub hours: Option<OpeningHours>,
+    // Hash fields this version doesn't know, e.g. written by a newer one with the same schema
+    // version. They are written back as they are, so that a rewrite doesn't lose them
+    #[serde(skip)]
+    pub extra: HashMap<String, String>,
+}
+
+// What a list of businesses needs. Field names match SUMMARY_FIELDS
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BusinessSummary {
+    pub id: Option<u64>,
+    pub name: String,
+    pub stars: u8,
+    pub r#type: String,
+    pub lat: f32,
+    pub lon: f32,
+    pub opens_at: u8,
+    pub closes_at: u8,
+    #[serde(default)]
+    pub hours: Option<OpeningHours>,
+}
+
+// The part of the full record that the summary leaves out. Field names match DETAIL_FIELDS
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct BusinessDetails {
+    pub zip_code: String,
+    pub average_price: u8,
+    pub description: String,
+    pub email: String,
+    pub phone: String,
+}
+
+// Every field of BusinessData is in one of the views, or is the version or the id
+fn is_record_field(name: &str) -> bool {
+    name == "schemaVersion"
+        || name == "id"
+        || SUMMARY_FIELDS.contains(&name)
+        || DETAIL_FIELDS.contains(&name)
+}
+
+// not SCHEMA_VERSION, records without a version are from before there was one
+fn first_version() -> u32 {
+    1
+}
+
+impl BusinessData {
+    pub fn opening_hours(&self) -> OpeningHours {
+        OpeningHours::resolve(
+            self.hours.as_ref(),
+            self.opens_at,
+            self.closes_at,
+            self.lon as f64,
+            self.lat as f64,
+        )
+    }
+    // Stored hours that the api built out of opensAt/closesAt, not ones a client set. Records
+    // written before derived hours were marked count when they are what those fields give
+    pub fn has_derived_hours(&self) -> bool {
+        match &self.hours {
+            None => true,
+            Some(hours) => {
+                hours.derived
+                    || (hours.exceptions.is_empty()
+                        && hours.weekly
+                            == OpeningHours::from_legacy(self.opens_at, self.closes_at).weekly)
+            }
+        }
+    }
+    // Same for the timezone of the hours, looked up from the position. Derived hours never had a
+    // timezone from a client either
+    pub fn has_derived_timezone(&self) -> bool {
+        match &self.hours {
+            Some(hours) if hours.timezone.is_some() => {
+                hours.derived_timezone || self.has_derived_hours()
+            }
+            _ => true,
+        }
+    }
+    pub fn summary(&self) -> BusinessSummary {
+        BusinessSummary {
+            id: self.id,
+            name: self.name.clone(),
+            stars: self.stars,
+            r#type: self.r#type.clone(),
+            lat: self.lat,
+            lon: self.lon,
+            opens_at: self.opens_at,
+            closes_at: self.closes_at,
+            hours: self.hours.clone(),
+        }
+    }
+    pub fn details(&self) -> BusinessDetails {
+        BusinessDetails {
+            zip_code: self.zip_code.clone(),
+            average_price: self.average_price,
+            description: self.description.clone(),
+            email: self.email.clone(),
+            phone: self.phone.clone(),
+        }
+    }
+    pub fn to_hash(&self) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+        let mut hash = hash_codec::to_hash(self)?;
+        hash.retain(|(name, _)| name != "id");
+        hash.extend(self.extra.clone());
+        Ok(hash)
+    }
+    pub fn from_hash(
+        id: u64,
+        hash: HashMap<String, String>,
+    ) -> Result<BusinessData, Box<dyn Error>> {
+        if hash.is_empty() {
+            return Err("Hash cannot be empty".into());
+        }
+        let (known, extra) = hash
+            .into_iter()
+            .partition(|(name, _)| is_record_field(name));
+        let mut data: BusinessData = hash_codec::from_hash(known)?;
+        data.extra = extra;
+        if data.schema_version > SCHEMA_VERSION {
+            return Err(format!(
+                "Business {} has schema version {}, newer than {}",
+                id, data.schema_version, SCHEMA_VERSION
+            )
+            .into());
+        }
+        data.id = Some(id);
+        Ok(data)
+    }
+}
+
+impl BusinessSummary {
+    pub fn opening_hours(&self) -> OpeningHours {
+        OpeningHours::resolve(
+            self.hours.as_ref(),
+            self.opens_at,
+            self.closes_at,
+            self.lon as f64,
+            self.lat as f64,
+        )
+    }
+    // From the SUMMARY_FIELDS of a hash, e.g. read with HMGET
+    pub fn from_hash(
+        id: u64,
+        hash: HashMap<String, String>,
+    ) -> Result<BusinessSummary, Box<dyn Error>> {
+        if hash.is_empty() {
+            return Err("Hash cannot be empty".into());
+        }
+        let mut summary: BusinessSummary = hash_codec::from_hash(hash)?;
+        summary.id = Some(id);
+        Ok(summary)
+    }
+}
+
+impl BusinessDetails {
+    // From the DETAIL_FIELDS of a hash. Missing fields stay empty
+    pub fn from_hash(hash: HashMap<String, String>) -> Result<BusinessDetails, Box<dyn Error>> {
+        Ok(hash_codec::from_hash(hash)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hours::{ClockTime, DateException, TimeRange, WeeklySchedule};
+    use serde_json::json;
+
+    fn business() -> BusinessData {
+        let range = TimeRange {
+            opens: ClockTime::new(9, 0).unwrap(),
+            closes: ClockTime::new(17, 30).unwrap(),
+        };
+        BusinessData {
+            schema_version: SCHEMA_VERSION,
+            id: Some(42),
+            zip_code: "10115".to_string(),
+            name: "Café \"Nord\", Berlin".to_string(),
+            stars: 4,
+            r#type: "cafe".to_string(),
+            lat: 52.53,
+            lon: 13.38,
+            opens_at: 9,
+            closes_at: 17,
+            average_price: 12,
+            description: "Coffee\nand cake".to_string(),
+            email: "nord@example.com".to_string(),
+            phone: "+49 30 1234567".to_string(),
+            hours: Some(OpeningHours {
+                timezone: Some("Europe/Berlin".to_string()),
+                weekly: WeeklySchedule::every_day(vec![range]),
+                exceptions: vec![DateException {
+                    date: "2024-12-25".parse().unwrap(),
+                    ranges: vec![],
+                }],
+                ..OpeningHours::default()
+            }),
+            extra: HashMap::new(),
+        }
+    }
+
+    fn read_back(data: &BusinessData) -> BusinessData {
+        let hash = data.to_hash().unwrap().into_iter().collect();
+        BusinessData::from_hash(data.id.unwrap(), hash).unwrap()
+    }
+
+    fn hash_of(fields: &[(&str, &str)]) -> HashMap<String, String> {
+        fields
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn business_round_trips_through_a_hash() {
+        let data = business();
+        let hash: HashMap<_, _> = data.to_hash().unwrap().into_iter().collect();
+        assert!(!hash.contains_key("id"));
+        let hours: serde_json::Value = serde_json::from_str(&hash["hours"]).unwrap();
+        assert_eq!(
+            hours["weekly"]["mon"],
+            json!([{"opens": "09:00", "closes": "17:30"}])
+        );
+        assert_eq!(hours["exceptions"][0]["date"], "2024-12-25");
+
+        let read = BusinessData::from_hash(42, hash).unwrap();
+        assert_eq!(
+            serde_json::to_value(&read).unwrap(),
+            serde_json::to_value(&data).unwrap()
+        );
+        assert_eq!(read.hours, data.hours);
+    }
+
+    #[test]
+    fn none_and_empty_fields_round_trip() {
+        let mut data = business();
+        data.hours = None;
+        data.description = String::new();
+        data.email = String::new();
+        let hash: HashMap<_, _> = data.to_hash().unwrap().into_iter().collect();
+        assert!(!hash.contains_key("hours"));
+        assert_eq!(hash["description"], "");
+
+        let read = read_back(&data);
+        assert_eq!(read.hours, None);
+        assert_eq!(read.description, "");
+        assert_eq!(read.email, "");
+        assert_eq!(read.id, Some(42));
+    }
+
+    #[test]
+    fn unknown_fields_are_kept() {
+        let mut hash: HashMap<_, _> = business().to_hash().unwrap().into_iter().collect();
+        hash.insert(
+            "website".to_string(),
+            "https://nord.example.com".to_string(),
+        );
+
+        let read = BusinessData::from_hash(42, hash).unwrap();
+        assert_eq!(read.extra["website"], "https://nord.example.com");
+        let written: HashMap<_, _> = read.to_hash().unwrap().into_iter().collect();
+        assert_eq!(written["website"], "https://nord.example.com");
+        assert_eq!(written["name"], "Café \"Nord\", Berlin");
+    }
+
+    #[test]
+    fn newer_schema_version_is_rejected() {
+        let mut hash: HashMap<_, _> = business().to_hash().unwrap().into_iter().collect();
+        hash.insert(
+            "schemaVersion".to_string(),
+            (SCHEMA_VERSION + 1).to_string(),
+        );
+        let error = BusinessData::from_hash(42, hash).unwrap_err();
+        assert!(error.to_string().contains("newer than"));
+    }
+
+    #[test]
+    fn version_0_hash_is_read() {
+        // written before schemaVersion, hours and the detail fields
+        let hash = hash_of(&[
+            ("name", "Old Shop"),
+            ("type", "shop"),
+            ("stars", "3"),
+            ("lat", "48.1"),
+            ("lon", "11.5"),
+            ("opensAt", "8"),
+            ("closesAt", "20"),
+        ]);
+        let read = BusinessData::from_hash(7, hash).unwrap();
+        assert_eq!(read.schema_version, 1);
+        assert_eq!(read.id, Some(7));
+        assert_eq!(read.name, "Old Shop");
+        assert_eq!(read.zip_code, "");
+        assert_eq!(read.average_price, 0);
+        assert_eq!(read.phone, "");
+        assert_eq!(read.hours, None);
+        assert!(read.extra.is_empty());
+
+        let document = json!({"id": 7, "name": "Old Shop", "type": "shop", "stars": 3,
+            "lat": 48.1, "lon": 11.5, "opensAt": 8, "closesAt": 20});
+        let read: BusinessData = serde_json::from_value(document).unwrap();
+        assert_eq!(read.description, "");
+    }
+
+    #[test]
+    fn derived_hours_are_told_apart() {
+        // hours a client set
+        let data = business();
+        assert!(!data.has_derived_hours());
+        assert!(!data.has_derived_timezone());
+
+        let mut data = business();
+        data.hours = None;
+        assert!(data.has_derived_hours());
+        data.hours = Some(data.opening_hours());
+        assert!(data.has_derived_hours());
+        assert!(data.has_derived_timezone());
+
+        // written before the markers, but the same as the old fields give
+        let mut unmarked = data.hours.clone().unwrap();
+        unmarked.derived = false;
+        unmarked.derived_timezone = false;
+        data.hours = Some(unmarked);
+        assert!(data.has_derived_hours());
+        assert!(data.has_derived_timezone());
+
+        // hours of a client without a timezone, which was looked up
+        let mut data = business();
+        data.hours.as_mut().unwrap().timezone = None;
+        data.hours = Some(data.opening_hours());
+        assert!(!data.has_derived_hours());
+        assert!(data.has_derived_timezone());
+    }
+
+    #[test]
+    fn empty_hash_is_rejected() {
+        assert!(BusinessData::from_hash(1, HashMap::new()).is_err());
+        assert!(BusinessSummary::from_hash(1, HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn summary_round_trips_through_a_hash() {
+        let data = business();
+        let summary = data.summary();
+        let hash: HashMap<_, _> = hash_codec::to_hash(&summary).unwrap().into_iter().collect();
+        let fields: HashMap<_, _> = hash
+            .into_iter()
+            .filter(|(name, _)| SUMMARY_FIELDS.contains(&name.as_str()))
+            .collect();
+        assert_eq!(fields.len(), SUMMARY_FIELDS.len());
+
+        let read = BusinessSummary::from_hash(42, fields).unwrap();
+        assert_eq!(read.id, Some(42));
+        assert_eq!(read.name, data.name);
+        assert_eq!(read.lat, data.lat);
+        assert_eq!(read.hours, data.hours);
+    }
+
+    #[test]
+    fn details_round_trip_and_default_when_missing() {
+        let data = business();
+        let hash: HashMap<_, _> = data
+            .to_hash()
+            .unwrap()
+            .into_iter()
+            .filter(|(name, _)| DETAIL_FIELDS.contains(&name.as_str()))
+            .collect();
+        let read = BusinessDetails::from_hash(hash).unwrap();
+        assert_eq!(
+            serde_json::to_value(&read).unwrap(),
+            serde_json::to_value(data.details()).unwrap()
+        );
+
+        let read = BusinessDetails::from_hash(hash_of(&[("email", "a@b.de")])).unwrap();
+        assert_eq!(read.email, "a@b.de");
+        assert_eq!(read.zip_code, "");
+        assert_eq!(read.average_price, 0);
+    }
+
+    #[test]
+    fn summary_without_a_field_is_rejected() {
+        let hash = hash_of(&[("name", "No Stars"), ("type", "shop")]);
+        assert!(BusinessSummary::from_hash(1, hash).is_err());
+    }
+}
@@ -0,0 +1,3 @@
+// Published on the business-info Redis by the api after a business is written. The message is
+// the business id, subscribers are expected to re-read whatever they hold for it
+pub const BUSINESS_CHANGED_CHANNEL: &str = "business:changed";
@@ -0,0 +1,265 @@
+use std::collections::HashSet;
+
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+pub struct GeoCell {
+    pub min_lon: f64,
+    pub min_lat: f64,
+    pub max_lon: f64,
+    pub max_lat: f64,
+}
+
+impl GeoCell {
+    pub fn center(&self) -> (f64, f64) {
+        (
+            (self.min_lon + self.max_lon) / 2.0,
+            (self.min_lat + self.max_lat) / 2.0,
+        )
+    }
+    // Distance from the center to a corner, in meters
+    pub fn half_diagonal(&self) -> f64 {
+        let (lon, lat) = self.center();
+        distance(lon, lat, self.max_lon, self.max_lat)
+    }
+}
+
+pub fn encode(lon: f64, lat: f64, precision: usize) -> String {
+    let mut lon_range = (-180.0, 180.0);
+    let mut lat_range = (-90.0, 90.0);
+    let mut hash = String::with_capacity(precision);
+    let mut is_lon = true;
+    let mut bits = 0;
+    let mut current = 0usize;
+
+    while hash.len() < precision {
+        let (range, value) = if is_lon {
+            (&mut lon_range, lon)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        current <<= 1;
+        if value >= mid {
+            current |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        is_lon = !is_lon;
+        bits += 1;
+
+        if bits == 5 {
+            hash.push(BASE32[current] as char);
+            bits = 0;
+            current = 0;
+        }
+    }
+
+    hash
+}
+
+pub fn decode(hash: &str) -> Option<GeoCell> {
+    let mut lon_range = (-180.0, 180.0);
+    let mut lat_range = (-90.0, 90.0);
+    let mut is_lon = true;
+
+    for c in hash.bytes() {
+        let index = BASE32.iter().position(|b| *b == c)?;
+        for shift in (0..5).rev() {
+            let range = if is_lon {
+                &mut lon_range
+            } else {
+                &mut lat_range
+            };
+            let mid = (range.0 + range.1) / 2.0;
+            if (index >> shift) & 1 == 1 {
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            is_lon = !is_lon;
+        }
+    }
+
+    Some(GeoCell {
+        min_lon: lon_range.0,
+        min_lat: lat_range.0,
+        max_lon: lon_range.1,
+        max_lat: lat_range.1,
+    })
+}
+
+pub const EARTH_RADIUS: f64 = 6_372_797.560856; // same radius Redis GEO uses
+
+// Great-circle distance in meters
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+// Size of a cell in degrees (lon, lat). Odd precisions spend one bit more on longitude
+pub fn cell_size(precision: usize) -> (f64, f64) {
+    let bits = precision * 5;
+    let lon_bits = bits.div_ceil(2);
+    let lat_bits = bits / 2;
+    (
+        360.0 / (1u64 << lon_bits) as f64,
+        180.0 / (1u64 << lat_bits) as f64,
+    )
+}
+
+// All cells that a circle touches. Walks the cell grid over the bounding box of the circle, which
+// can include a few corner cells that are just outside of it
+pub fn covering_cells(lon: f64, lat: f64, radius: f64, precision: usize) -> Vec<String> {
+    let d_lat = (radius / EARTH_RADIUS).to_degrees();
+    // a circle over a pole reaches every longitude
+    let d_lon = if (lat.abs() + d_lat) >= 90.0 {
+        180.0
+    } else {
+        d_lat / lat.to_radians().cos().max(0.01)
+    };
+    box_cells(
+        lon - d_lon,
+        lat - d_lat,
+        lon + d_lon,
+        lat + d_lat,
+        precision,
+    )
+}
+
+// All cells that a lon/lat box touches. Longitudes past ±180, e.g. of a circle around a point
+// next to the antimeridian, go on at the other side of it
+pub fn box_cells(
+    min_lon: f64,
+    min_lat: f64,
+    max_lon: f64,
+    max_lat: f64,
+    precision: usize,
+) -> Vec<String> {
+    let lon_ranges = if max_lon - min_lon >= 360.0 {
+        vec![(-180.0, 180.0)]
+    } else if min_lon < -180.0 {
+        vec![(min_lon + 360.0, 180.0), (-180.0, max_lon)]
+    } else if max_lon > 180.0 {
+        vec![(min_lon, 180.0), (-180.0, max_lon - 360.0)]
+    } else {
+        vec![(min_lon, max_lon)]
+    };
+    let (width, height) = cell_size(precision);
+    let min_lat = min_lat.max(-90.0);
+    let max_lat = max_lat.min(90.0);
+
+    // the walk can land in a cell twice where the steps add up to a little less than its size
+    let mut seen = HashSet::new();
+    let mut cells: Vec<String> = vec![];
+    for (min_lon, max_lon) in lon_ranges {
+        let mut cell_lat = ((min_lat + 90.0) / height).floor() * height - 90.0 + height / 2.0;
+        while cell_lat - height / 2.0 <= max_lat && cell_lat < 90.0 {
+            let mut cell_lon = ((min_lon + 180.0) / width).floor() * width - 180.0 + width / 2.0;
+            while cell_lon - width / 2.0 <= max_lon && cell_lon < 180.0 {
+                let cell = encode(cell_lon, cell_lat, precision);
+                if seen.insert(cell.clone()) {
+                    cells.push(cell);
+                }
+                cell_lon += width;
+            }
+            cell_lat += height;
+        }
+    }
+
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_known_hashes() {
+        // examples from geohash.org
+        assert_eq!(encode(-5.6, 42.6, 5), "ezs42");
+        assert_eq!(encode(10.40744, 57.64911, 11), "u4pruydqqvj");
+        assert_eq!(encode(13.4, 52.5, 0), "");
+        assert_eq!(encode(-180.0, -90.0, 3), "000");
+        assert_eq!(encode(180.0, 90.0, 3), "zzz");
+    }
+
+    #[test]
+    fn decode_gives_the_cell_of_the_point() {
+        for (lon, lat) in [(13.4, 52.5), (-122.42, 37.77), (151.21, -33.87), (0.0, 0.0)] {
+            for precision in 1..=9 {
+                let cell = decode(&encode(lon, lat, precision)).unwrap();
+                assert!(cell.min_lon <= lon && lon <= cell.max_lon);
+                assert!(cell.min_lat <= lat && lat <= cell.max_lat);
+                let (width, height) = cell_size(precision);
+                assert!((cell.max_lon - cell.min_lon - width).abs() < 1e-9);
+                assert!((cell.max_lat - cell.min_lat - height).abs() < 1e-9);
+                let (center_lon, center_lat) = cell.center();
+                assert_eq!(
+                    encode(center_lon, center_lat, precision),
+                    encode(lon, lat, precision)
+                );
+            }
+        }
+        assert!(decode("ab").is_none());
+        assert!(decode("u3").is_some());
+    }
+
+    #[test]
+    fn distances() {
+        assert_eq!(distance(13.4, 52.5, 13.4, 52.5), 0.0);
+        // a degree of latitude
+        let degree = distance(0.0, 0.0, 0.0, 1.0);
+        assert!((degree - 111_226.3).abs() < 1.0);
+        // Berlin to Paris, about 878 km
+        let km = distance(13.405, 52.52, 2.3522, 48.8566) / 1000.0;
+        assert!((km - 878.0).abs() < 5.0, "{}", km);
+    }
+
+    #[test]
+    fn box_cells_cover_the_box_once() {
+        let cells = box_cells(13.0, 52.0, 14.0, 53.0, 3);
+        let unique: HashSet<&String> = cells.iter().collect();
+        assert_eq!(unique.len(), cells.len());
+        for (lon, lat) in [(13.0, 52.0), (14.0, 53.0), (13.5, 52.5), (13.99, 52.01)] {
+            assert!(cells.contains(&encode(lon, lat, 3)), "{},{}", lon, lat);
+        }
+        assert_eq!(box_cells(-180.0, -90.0, 180.0, 90.0, 1).len(), 32);
+        assert_eq!(box_cells(-180.0, -90.0, 180.0, 90.0, 2).len(), 1024);
+    }
+
+    #[test]
+    fn box_cells_at_the_antimeridian() {
+        let cells = box_cells(179.5, -1.0, 180.0, 1.0, 2);
+        assert!(cells.contains(&encode(179.9, 0.5, 2)));
+        assert!(cells
+            .iter()
+            .all(|cell| decode(cell).unwrap().max_lon == 180.0));
+
+        // a circle around a point next to it reaches over to the west of -180
+        let cells = covering_cells(179.99, 0.0, 50_000.0, 2);
+        assert!(cells.contains(&encode(179.99, 0.0, 2)));
+        assert!(cells.contains(&encode(-179.99, 0.0, 2)));
+        let cells = covering_cells(-179.99, 0.0, 50_000.0, 2);
+        assert!(cells.contains(&encode(179.99, 0.0, 2)));
+    }
+
+    #[test]
+    fn box_cells_at_the_poles() {
+        let cells = box_cells(-10.0, 89.0, 10.0, 95.0, 2);
+        assert!(cells.contains(&encode(0.0, 90.0, 2)));
+        assert!(cells
+            .iter()
+            .all(|cell| decode(cell).unwrap().max_lat == 90.0));
+        let cells = box_cells(-10.0, -95.0, 10.0, -89.0, 2);
+        assert!(cells.contains(&encode(0.0, -90.0, 2)));
+
+        // near the pole a circle spans every longitude
+        let cells = covering_cells(0.0, 89.99, 10_000.0, 1);
+        assert!(cells.contains(&encode(-179.0, 89.99, 1)));
+        assert!(cells.contains(&encode(179.0, 89.99, 1)));
+    }
+}
@@ -0,0 +1,131 @@
+use serde::de::value::{Error, MapDeserializer, StringDeserializer};
+use serde::de::{self, IntoDeserializer, Visitor};
+use serde::{forward_to_deserialize_any, Deserializer, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+// Structs are stored in Redis as a hash with one field per struct field: strings as they are,
+// numbers and booleans as text, nested structures (e.g. opening hours) as json. Both directions
+// go through serde, so a field added to a struct is written and read back without touching this.
+// None fields are left out of the hash
+pub fn to_hash<T: Serialize>(value: &T) -> Result<Vec<(String, String)>, serde_json::Error> {
+    let fields = match serde_json::to_value(value)? {
+        Value::Object(fields) => fields,
+        _ => {
+            return Err(serde::ser::Error::custom(
+                "Only structs can be stored as a hash",
+            ))
+        }
+    };
+    Ok(fields
+        .into_iter()
+        .filter_map(|(name, value)| match value {
+            Value::Null => None,
+            Value::String(value) => Some((name, value)),
+            other => Some((name, other.to_string())),
+        })
+        .collect())
+}
+
+pub fn from_hash<T: de::DeserializeOwned>(hash: HashMap<String, String>) -> Result<T, Error> {
+    T::deserialize(MapDeserializer::new(
+        hash.into_iter()
+            .map(|(name, value)| (name, HashValue(value))),
+    ))
+}
+
+// A single hash field. It is text whatever the type of the struct field, so numbers are parsed
+// here and nested structures are handed to serde_json
+struct HashValue(String);
+
+impl<'de> IntoDeserializer<'de, Error> for HashValue {
+    type Deserializer = HashValue;
+    fn into_deserializer(self) -> HashValue {
+        self
+    }
+}
+
+macro_rules! parse_value {
+    ($($method:ident => $visit:ident),*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                match self.0.parse() {
+                    Ok(value) => visitor.$visit(value),
+                    Err(_) => Err(de::Error::custom(format!("Unexpected value: {}", self.0))),
+                }
+            }
+        )*
+    };
+}
+
+impl HashValue {
+    fn json<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let json: Value = serde_json::from_str(&self.0).map_err(de::Error::custom)?;
+        json.deserialize_any(visitor).map_err(de::Error::custom)
+    }
+}
+
+impl<'de> Deserializer<'de> for HashValue {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_string(self.0)
+    }
+
+    parse_value! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // unit variants are stored by their name, like strings
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        StringDeserializer::<Error>::new(self.0).deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.json(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.json(visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.json(visitor)
+    }
+
+    forward_to_deserialize_any! {
+        char str string bytes byte_buf unit unit_struct tuple tuple_struct identifier ignored_any
+    }
+}
@@ -0,0 +1,430 @@
+use crate::timezone;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+const MINUTES_IN_DAY: u16 = 24 * 60;
+
+// Time of the day in minutes since midnight. Serialized as "HH:MM", "24:00" is allowed so that a
+// range can run until the very end of the day
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ClockTime(u16);
+
+impl ClockTime {
+    pub fn new(hours: u16, minutes: u16) -> Option<ClockTime> {
+        let total = hours.checked_mul(60)?.checked_add(minutes)?;
+        if minutes >= 60 || total > MINUTES_IN_DAY {
+            return None;
+        }
+        Some(ClockTime(total))
+    }
+    pub fn minutes(&self) -> u16 {
+        self.0
+    }
+}
+
+impl Serialize for ClockTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:02}:{:02}", self.0 / 60, self.0 % 60))
+    }
+}
+
+impl<'de> Deserialize<'de> for ClockTime {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.split_once(':')
+            .and_then(|(h, m)| ClockTime::new(h.parse().ok()?, m.parse().ok()?))
+            .ok_or_else(|| de::Error::custom(format!("invalid time of day: {}", raw)))
+    }
+}
+
+// A range whose closing time is not after its opening time runs past midnight, e.g.
+// 22:00-02:00 closes at two in the morning of the next day
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimeRange {
+    pub opens: ClockTime,
+    pub closes: ClockTime,
+}
+
+impl TimeRange {
+    pub fn is_overnight(&self) -> bool {
+        self.closes <= self.opens
+    }
+    fn contains_same_day(&self, minute: u16) -> bool {
+        if self.is_overnight() {
+            return minute >= self.opens.0;
+        }
+        minute >= self.opens.0 && minute < self.closes.0
+    }
+    fn contains_next_day(&self, minute: u16) -> bool {
+        self.is_overnight() && minute < self.closes.0
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WeeklySchedule {
+    pub mon: Vec<TimeRange>,
+    pub tue: Vec<TimeRange>,
+    pub wed: Vec<TimeRange>,
+    pub thu: Vec<TimeRange>,
+    pub fri: Vec<TimeRange>,
+    pub sat: Vec<TimeRange>,
+    pub sun: Vec<TimeRange>,
+}
+
+impl WeeklySchedule {
+    pub fn every_day(ranges: Vec<TimeRange>) -> WeeklySchedule {
+        WeeklySchedule {
+            mon: ranges.clone(),
+            tue: ranges.clone(),
+            wed: ranges.clone(),
+            thu: ranges.clone(),
+            fri: ranges.clone(),
+            sat: ranges.clone(),
+            sun: ranges,
+        }
+    }
+    pub fn day(&self, day: Weekday) -> &[TimeRange] {
+        match day {
+            Weekday::Mon => &self.mon,
+            Weekday::Tue => &self.tue,
+            Weekday::Wed => &self.wed,
+            Weekday::Thu => &self.thu,
+            Weekday::Fri => &self.fri,
+            Weekday::Sat => &self.sat,
+            Weekday::Sun => &self.sun,
+        }
+    }
+}
+
+// Replaces the weekly schedule for a single date. No ranges means closed the whole day (holidays)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DateException {
+    pub date: NaiveDate,
+    #[serde(default)]
+    pub ranges: Vec<TimeRange>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpeningHours {
+    // IANA name, e.g. "Europe/Berlin". Derived from coordinates when not given
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub weekly: WeeklySchedule,
+    #[serde(default)]
+    pub exceptions: Vec<DateException>,
+    // Set on hours built out of opensAt/closesAt, and on a timezone looked up from the position,
+    // rather than given by a client. Only those are derived again when the fields they come
+    // from change
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub derived: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub derived_timezone: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl OpeningHours {
+    // Builds the schedule out of the old whole-hour `opensAt`/`closesAt` pair. 0-0 was used as
+    // "unknown", so it gives an empty schedule
+    pub fn from_legacy(opens_at: u8, closes_at: u8) -> OpeningHours {
+        let range = ClockTime::new(opens_at as u16, 0)
+            .zip(ClockTime::new(closes_at as u16, 0))
+            .map(|(opens, closes)| TimeRange { opens, closes });
+        let weekly = match range {
+            Some(range) if opens_at != 0 || closes_at != 0 => {
+                WeeklySchedule::every_day(vec![range])
+            }
+            _ => WeeklySchedule::default(),
+        };
+        OpeningHours {
+            timezone: None,
+            weekly,
+            exceptions: vec![],
+            derived: true,
+            derived_timezone: false,
+        }
+    }
+
+    // Returns stored hours or the ones derived from legacy fields, with the timezone filled in
+    pub fn resolve(
+        hours: Option<&OpeningHours>,
+        opens_at: u8,
+        closes_at: u8,
+        lon: f64,
+        lat: f64,
+    ) -> OpeningHours {
+        let mut resolved = match hours {
+            Some(hours) => hours.clone(),
+            None => OpeningHours::from_legacy(opens_at, closes_at),
+        };
+        if resolved.timezone.is_none() {
+            resolved.timezone = timezone::find_timezone(lon, lat);
+            resolved.derived_timezone = resolved.timezone.is_some();
+        }
+        resolved
+    }
+
+    pub fn tz(&self) -> Tz {
+        self.timezone
+            .as_ref()
+            .and_then(|name| name.parse::<Tz>().ok())
+            .unwrap_or(Tz::UTC)
+    }
+
+    pub fn is_open_now(&self) -> bool {
+        self.is_open_at(Utc::now())
+    }
+
+    pub fn is_open_at(&self, at: DateTime<Utc>) -> bool {
+        self.is_open_local(at.with_timezone(&self.tz()).naive_local())
+    }
+
+    pub fn is_open_local(&self, local: NaiveDateTime) -> bool {
+        let date = local.date();
+        let minute = (local.hour() * 60 + local.minute()) as u16;
+
+        if self
+            .ranges_for(date)
+            .iter()
+            .any(|range| range.contains_same_day(minute))
+        {
+            return true;
+        }
+        // overnight ranges of the previous day still count after midnight
+        match date.checked_sub_signed(Duration::days(1)) {
+            Some(previous) => self
+                .ranges_for(previous)
+                .iter()
+                .any(|range| range.contains_next_day(minute)),
+            None => false,
+        }
+    }
+
+    fn ranges_for(&self, date: NaiveDate) -> &[TimeRange] {
+        match self.exceptions.iter().find(|e| e.date == date) {
+            Some(exception) => &exception.ranges,
+            None => self.weekly.day(date.weekday()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn range(opens: u16, closes: u16) -> TimeRange {
+        TimeRange {
+            opens: ClockTime::new(opens, 0).unwrap(),
+            closes: ClockTime::new(closes, 0).unwrap(),
+        }
+    }
+
+    // 2024-06-03 is a Monday
+    fn local(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 6, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn hours(weekly: WeeklySchedule, exceptions: Vec<DateException>) -> OpeningHours {
+        OpeningHours {
+            weekly,
+            exceptions,
+            ..OpeningHours::default()
+        }
+    }
+
+    #[test]
+    fn clock_times() {
+        assert_eq!(ClockTime::new(24, 0).unwrap().minutes(), 1440);
+        assert!(ClockTime::new(24, 1).is_none());
+        assert!(ClockTime::new(9, 60).is_none());
+        let time: ClockTime = serde_json::from_str("\"07:05\"").unwrap();
+        assert_eq!(serde_json::to_string(&time).unwrap(), "\"07:05\"");
+        assert!(serde_json::from_str::<ClockTime>("\"7\"").is_err());
+        assert!(serde_json::from_str::<ClockTime>("\"25:00\"").is_err());
+    }
+
+    #[test]
+    fn day_range_closes_at_its_closing_time() {
+        let hours = hours(WeeklySchedule::every_day(vec![range(9, 17)]), vec![]);
+        assert!(!hours.is_open_local(local(3, 8, 59)));
+        assert!(hours.is_open_local(local(3, 9, 0)));
+        assert!(hours.is_open_local(local(3, 16, 59)));
+        assert!(!hours.is_open_local(local(3, 17, 0)));
+    }
+
+    #[test]
+    fn overnight_range_runs_into_the_next_day() {
+        // Fridays only, 22:00 to 02:00
+        let weekly = WeeklySchedule {
+            fri: vec![range(22, 2)],
+            ..WeeklySchedule::default()
+        };
+        let hours = hours(weekly, vec![]);
+        assert!(range(22, 2).is_overnight());
+        assert!(!hours.is_open_local(local(7, 21, 59)));
+        assert!(hours.is_open_local(local(7, 23, 30)));
+        // Saturday after midnight belongs to Friday's range
+        assert!(hours.is_open_local(local(8, 1, 59)));
+        assert!(!hours.is_open_local(local(8, 2, 0)));
+        // nothing on Saturday evening, and Thursday's night has no range to carry over
+        assert!(!hours.is_open_local(local(8, 23, 0)));
+        assert!(!hours.is_open_local(local(7, 1, 0)));
+    }
+
+    #[test]
+    fn exception_replaces_the_weekly_schedule() {
+        let weekly = WeeklySchedule::every_day(vec![range(9, 17)]);
+        let exceptions = vec![
+            DateException {
+                date: NaiveDate::from_ymd_opt(2024, 6, 4).unwrap(),
+                ranges: vec![range(12, 14)],
+            },
+            // closed the whole day
+            DateException {
+                date: NaiveDate::from_ymd_opt(2024, 6, 5).unwrap(),
+                ranges: vec![],
+            },
+        ];
+        let hours = hours(weekly, exceptions);
+        assert!(hours.is_open_local(local(3, 10, 0)));
+        assert!(!hours.is_open_local(local(4, 10, 0)));
+        assert!(hours.is_open_local(local(4, 13, 0)));
+        assert!(!hours.is_open_local(local(5, 10, 0)));
+        assert!(!hours.is_open_local(local(5, 16, 0)));
+        assert!(hours.is_open_local(local(6, 10, 0)));
+    }
+
+    #[test]
+    fn exception_closes_the_night_after_an_overnight_day() {
+        let weekly = WeeklySchedule::every_day(vec![range(20, 4)]);
+        let exceptions = vec![DateException {
+            date: NaiveDate::from_ymd_opt(2024, 6, 4).unwrap(),
+            ranges: vec![],
+        }];
+        let hours = hours(weekly, exceptions);
+        // the night into the closed day is the previous day's range
+        assert!(hours.is_open_local(local(4, 3, 0)));
+        assert!(!hours.is_open_local(local(4, 21, 0)));
+        // and the closed day has no night to carry into the next one
+        assert!(!hours.is_open_local(local(5, 3, 0)));
+    }
+
+    #[test]
+    fn legacy_hours() {
+        let hours = OpeningHours::from_legacy(9, 17);
+        assert!(hours.derived);
+        assert_eq!(hours.weekly.day(Weekday::Sun), [range(9, 17)]);
+        assert!(hours.exceptions.is_empty());
+
+        // 0-0 meant unknown
+        let unknown = OpeningHours::from_legacy(0, 0);
+        assert_eq!(unknown.weekly, WeeklySchedule::default());
+        assert!(!unknown.is_open_local(local(3, 12, 0)));
+
+        // the same hour twice is an overnight range around the clock
+        let around_the_clock = OpeningHours::from_legacy(9, 9);
+        assert!(around_the_clock.weekly.mon[0].is_overnight());
+        for hour in [0, 8, 9, 12, 23] {
+            assert!(
+                around_the_clock.is_open_local(local(3, hour, 0)),
+                "{}",
+                hour
+            );
+        }
+
+        let late = OpeningHours::from_legacy(18, 2);
+        assert!(late.is_open_local(local(4, 1, 0)));
+        assert!(!late.is_open_local(local(4, 3, 0)));
+
+        // out of range hours give no schedule rather than a wrong one
+        assert_eq!(
+            OpeningHours::from_legacy(9, 25).weekly,
+            WeeklySchedule::default()
+        );
+    }
+
+    #[test]
+    fn resolve_keeps_stored_hours() {
+        let stored = OpeningHours {
+            timezone: Some("Asia/Tokyo".to_string()),
+            ..hours(WeeklySchedule::every_day(vec![range(10, 11)]), vec![])
+        };
+        let resolved = OpeningHours::resolve(Some(&stored), 9, 17, 13.4, 52.5);
+        assert_eq!(resolved, stored);
+        assert!(!resolved.derived && !resolved.derived_timezone);
+    }
+
+    #[test]
+    fn resolve_marks_what_it_derives() {
+        let resolved = OpeningHours::resolve(None, 9, 17, 13.4, 52.5);
+        assert!(resolved.derived);
+        assert_eq!(resolved.timezone.as_deref(), Some("Europe/Berlin"));
+        assert!(resolved.derived_timezone);
+        let json = serde_json::to_value(&resolved).unwrap();
+        assert_eq!(json["derived"], true);
+        assert_eq!(json["derivedTimezone"], true);
+
+        // client hours without a timezone only get the timezone derived
+        let custom = hours(WeeklySchedule::every_day(vec![range(7, 12)]), vec![]);
+        let resolved = OpeningHours::resolve(Some(&custom), 9, 17, -74.0, 40.7);
+        assert!(!resolved.derived);
+        assert!(resolved.derived_timezone);
+        assert_eq!(resolved.timezone.as_deref(), Some("America/New_York"));
+        // and the markers are left out of client hours
+        let json = serde_json::to_value(&custom).unwrap();
+        assert!(json.get("derived").is_none());
+    }
+
+    #[test]
+    fn open_in_a_timezone_ahead_of_utc() {
+        let hours = OpeningHours {
+            timezone: Some("Europe/Berlin".to_string()),
+            ..hours(WeeklySchedule::every_day(vec![range(9, 17)]), vec![])
+        };
+        // summer time, UTC+2
+        let at = Utc.with_ymd_and_hms(2024, 6, 3, 7, 30, 0).unwrap();
+        assert!(hours.is_open_at(at));
+        assert!(hours.is_open_local(local(3, 9, 30)));
+        let at = Utc.with_ymd_and_hms(2024, 6, 3, 15, 30, 0).unwrap();
+        assert!(!hours.is_open_at(at));
+        assert!(!hours.is_open_local(local(3, 17, 30)));
+    }
+
+    #[test]
+    fn open_in_a_timezone_behind_utc() {
+        let weekly = WeeklySchedule {
+            mon: vec![range(20, 23)],
+            ..WeeklySchedule::default()
+        };
+        let hours = OpeningHours {
+            timezone: Some("America/New_York".to_string()),
+            ..hours(weekly, vec![])
+        };
+        // Tuesday 01:00 UTC is Monday 21:00 in New York, UTC-4
+        let at = Utc.with_ymd_and_hms(2024, 6, 4, 1, 0, 0).unwrap();
+        assert!(hours.is_open_at(at));
+        assert!(hours.is_open_local(local(3, 21, 0)));
+        // Monday 21:00 UTC is Monday 17:00 there
+        let at = Utc.with_ymd_and_hms(2024, 6, 3, 21, 0, 0).unwrap();
+        assert!(!hours.is_open_at(at));
+    }
+
+    #[test]
+    fn unknown_timezone_is_utc() {
+        let hours = OpeningHours {
+            timezone: Some("Mars/Olympus".to_string()),
+            ..OpeningHours::default()
+        };
+        assert_eq!(hours.tz(), Tz::UTC);
+    }
+}
@@ -0,0 +1,10 @@
+pub mod business;
+pub mod events;
+pub mod geohash;
+pub mod hash_codec;
+pub mod hours;
+pub mod redis_connection;
+pub mod search_cache;
+pub mod shards;
+pub mod timezone;
+pub mod validation;
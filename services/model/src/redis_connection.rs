@@ -0,0 +1,209 @@
+use futures_util::future::try_join_all;
+use redis::aio::{ConnectionLike, MultiplexedConnection};
+use redis::cluster::ClusterClient;
+use redis::cluster_async::ClusterConnection;
+use redis::cluster_routing::get_slot;
+use redis::{Arg, Cmd, Pipeline, RedisFuture, RedisResult, Value};
+use std::collections::HashMap;
+
+// Connection to a single Redis node or to a Redis Cluster, used the same way by both. A cluster
+// only runs a pipeline when all of its keys are in one hash slot, so pipelines over arbitrary
+// keys (e.g. hydrating a page of businesses) are split by slot here, the parts are sent
+// concurrently and the replies are put back in the order of the commands
+#[derive(Clone)]
+pub enum RedisConnection {
+    Single(MultiplexedConnection),
+    Cluster(ClusterConnection),
+}
+
+impl RedisConnection {
+    pub async fn connect(uri: &str) -> RedisResult<RedisConnection> {
+        let client = redis::Client::open(uri)?;
+        Ok(RedisConnection::Single(
+            client.get_multiplexed_async_connection().await?,
+        ))
+    }
+
+    // Any reachable nodes of the cluster will do, the rest is discovered from them
+    pub async fn connect_cluster(seeds: &[String]) -> RedisResult<RedisConnection> {
+        let client = ClusterClient::new(seeds.to_vec())?;
+        Ok(RedisConnection::Cluster(
+            client.get_async_connection().await?,
+        ))
+    }
+
+    pub fn is_cluster(&self) -> bool {
+        matches!(self, RedisConnection::Cluster(_))
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            RedisConnection::Single(connection) => connection.req_packed_command(cmd),
+            RedisConnection::Cluster(connection) => connection.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipe: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            RedisConnection::Single(connection) => {
+                connection.req_packed_commands(pipe, offset, count)
+            }
+            // an offset means MULTI/EXEC, which can't be split and has to stay in one slot
+            RedisConnection::Cluster(connection) => {
+                if offset > 0 || !spans_slots(pipe) {
+                    connection.req_packed_commands(pipe, offset, count)
+                } else {
+                    Box::pin(query_by_slot(connection.clone(), pipe))
+                }
+            }
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConnection::Single(connection) => connection.get_db(),
+            RedisConnection::Cluster(connection) => connection.get_db(),
+        }
+    }
+}
+
+async fn query_by_slot(connection: ClusterConnection, pipe: &Pipeline) -> RedisResult<Vec<Value>> {
+    let queries = split_by_slot(pipe).into_iter().map(|(group, positions)| {
+        let mut connection = connection.clone();
+        async move {
+            let replies: Vec<Value> = group.query_async(&mut connection).await?;
+            Ok::<_, redis::RedisError>((positions, replies))
+        }
+    });
+    let parts = try_join_all(queries).await?;
+    Ok(merge_replies(pipe.cmd_iter().count(), parts))
+}
+
+// One pipeline per slot, with the position of each of its commands in the original pipeline
+fn split_by_slot(pipe: &Pipeline) -> Vec<(Pipeline, Vec<usize>)> {
+    let slots = slots_of(pipe);
+    let mut groups: HashMap<u16, (Pipeline, Vec<usize>)> = HashMap::default();
+    for (i, (cmd, slot)) in pipe.cmd_iter().zip(&slots).enumerate() {
+        let (group, positions) = groups
+            .entry(*slot)
+            .or_insert_with(|| (Pipeline::new(), vec![]));
+        group.add_command(cmd.clone());
+        positions.push(i);
+    }
+    groups.into_values().collect()
+}
+
+fn merge_replies(count: usize, parts: Vec<(Vec<usize>, Vec<Value>)>) -> Vec<Value> {
+    let mut values = vec![Value::Nil; count];
+    for (positions, replies) in parts {
+        for (i, reply) in positions.into_iter().zip(replies) {
+            values[i] = reply;
+        }
+    }
+    values
+}
+
+fn spans_slots(pipe: &Pipeline) -> bool {
+    slots_of(pipe).windows(2).any(|pair| pair[0] != pair[1])
+}
+
+// Slot of every command, taken from its first key. Commands without a key go with slot 0
+fn slots_of(pipe: &Pipeline) -> Vec<u16> {
+    pipe.cmd_iter()
+        .map(|cmd| match cmd.args_iter().nth(1) {
+            Some(Arg::Simple(key)) => get_slot(key),
+            _ => 0,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_of(cmd: &Cmd) -> Vec<u8> {
+        match cmd.args_iter().nth(1) {
+            Some(Arg::Simple(key)) => key.to_vec(),
+            _ => vec![],
+        }
+    }
+
+    fn pipeline(keys: &[&str]) -> Pipeline {
+        let mut pipe = Pipeline::new();
+        for key in keys {
+            pipe.hget(*key, "name");
+        }
+        pipe
+    }
+
+    #[test]
+    fn slots_come_from_the_first_key() {
+        // from the cluster spec
+        assert_eq!(get_slot(b"foo"), 12182);
+        let pipe = pipeline(&["foo", "{user1000}.following", "{user1000}.followers"]);
+        let slots = slots_of(&pipe);
+        assert_eq!(slots[0], 12182);
+        assert_eq!(slots[1], slots[2]);
+        let mut ping = Pipeline::new();
+        ping.cmd("PING");
+        assert_eq!(slots_of(&ping), [0]);
+    }
+
+    #[test]
+    fn pipelines_in_one_slot_are_not_split() {
+        assert!(!spans_slots(&pipeline(&["{a}1", "{a}2", "{a}3"])));
+        assert!(!spans_slots(&pipeline(&["1"])));
+        assert!(!spans_slots(&Pipeline::new()));
+        assert!(spans_slots(&pipeline(&["{a}1", "{b}2"])));
+    }
+
+    #[test]
+    fn pipeline_is_split_by_slot() {
+        let keys = ["{a}1", "{b}2", "{a}3", "{c}4", "{b}5"];
+        let pipe = pipeline(&keys);
+        let mut parts = split_by_slot(&pipe);
+        parts.sort_by_key(|(_, positions)| positions[0]);
+
+        let positions: Vec<Vec<usize>> = parts.iter().map(|(_, p)| p.clone()).collect();
+        assert_eq!(positions, [vec![0, 2], vec![1, 4], vec![3]]);
+        for (group, positions) in &parts {
+            let slots = slots_of(group);
+            assert!(slots.iter().all(|slot| *slot == slots[0]));
+            let group_keys: Vec<Vec<u8>> = group.cmd_iter().map(key_of).collect();
+            let expected: Vec<Vec<u8>> = positions.iter().map(|i| keys[*i].into()).collect();
+            assert_eq!(group_keys, expected);
+        }
+    }
+
+    #[test]
+    fn replies_come_back_in_command_order() {
+        let keys = ["{a}1", "{b}2", "{a}3", "{c}4", "{b}5"];
+        let pipe = pipeline(&keys);
+        // each part answers with its own keys, in whatever order the parts finish
+        let mut parts: Vec<(Vec<usize>, Vec<Value>)> = split_by_slot(&pipe)
+            .into_iter()
+            .map(|(group, positions)| {
+                let replies = group
+                    .cmd_iter()
+                    .map(|cmd| Value::Data(key_of(cmd)))
+                    .collect();
+                (positions, replies)
+            })
+            .collect();
+        parts.reverse();
+
+        let values = merge_replies(keys.len(), parts);
+        let expected: Vec<Value> = keys
+            .iter()
+            .map(|key| Value::Data(key.as_bytes().to_vec()))
+            .collect();
+        assert_eq!(values, expected);
+    }
+}
@@ -0,0 +1,39 @@
+use crate::geohash;
+
+// Search results are cached by lbs per (radius bucket, geohash cell of the query point). The api
+// drops entries through the per-cell index sets whenever a business in that cell changes.
+// Cells get coarser as the radius grows, so one entry covers an area about the size of the search
+pub const RADIUS_BUCKETS: [(u32, usize); 7] = [
+    (500, 6),
+    (1000, 6),
+    (2000, 5),
+    (5000, 5),
+    (10000, 4),
+    (20000, 4),
+    (50000, 3),
+];
+
+// Smallest bucket that fits the radius with the geohash precision used for it. Bigger searches
+// are not cached
+pub fn bucket_for(radius: f64) -> Option<(u32, usize)> {
+    RADIUS_BUCKETS
+        .iter()
+        .find(|(bucket, _)| radius <= *bucket as f64)
+        .copied()
+}
+
+pub fn entry_key(bucket: u32, cell: &str) -> String {
+    format!("search:{}:{}", bucket, cell)
+}
+
+pub fn cell_index_key(bucket: u32, cell: &str) -> String {
+    format!("search:idx:{}:{}", bucket, cell)
+}
+
+// Index sets that may reference cached results containing a business at this point
+pub fn index_keys_for_point(lon: f64, lat: f64) -> Vec<String> {
+    RADIUS_BUCKETS
+        .iter()
+        .map(|(bucket, precision)| cell_index_key(*bucket, &geohash::encode(lon, lat, *precision)))
+        .collect()
+}
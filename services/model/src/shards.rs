@@ -0,0 +1,158 @@
+use crate::geohash;
+
+// Business positions are split into one GEO set per region, the geohash prefix of the position
+// with `precision` characters, and the sets are spread over the geo Redis nodes. Precision 0 keeps
+// everything in the single "world" set. Precision 1 gives 32 regions, 2 gives 1024
+pub const UNSHARDED_KEY: &str = "world";
+pub const SHARD_PREFIX: &str = "geo:";
+// Set of the shard keys that hold positions, one per node, for queries that need every shard
+pub const SHARD_LIST_KEY: &str = "geo:shards";
+pub const MAX_PRECISION: usize = 3;
+
+#[derive(Clone, Debug)]
+pub struct ShardMap {
+    pub precision: usize,
+    pub nodes: usize,
+}
+
+impl ShardMap {
+    pub fn new(precision: usize, nodes: usize) -> ShardMap {
+        ShardMap {
+            precision: precision.min(MAX_PRECISION),
+            nodes: nodes.max(1),
+        }
+    }
+
+    pub fn is_sharded(&self) -> bool {
+        self.precision > 0
+    }
+
+    pub fn key_for(&self, lon: f64, lat: f64) -> String {
+        if !self.is_sharded() {
+            return UNSHARDED_KEY.to_string();
+        }
+        format!(
+            "{}{}",
+            SHARD_PREFIX,
+            geohash::encode(lon, lat, self.precision)
+        )
+    }
+
+    // Node that holds the shard. Keys are hashed rather than assigned in ranges so that
+    // neighbouring regions, which busy searches hit together, usually land on different nodes
+    pub fn node_for(&self, key: &str) -> usize {
+        (fnv1a(key.as_bytes()) % self.nodes as u64) as usize
+    }
+
+    // Shards a radius search has to ask, a search near a region border spans several of them
+    pub fn keys_for_radius(&self, lon: f64, lat: f64, radius: f64) -> Vec<String> {
+        if !self.is_sharded() {
+            return vec![UNSHARDED_KEY.to_string()];
+        }
+        geohash::covering_cells(lon, lat, radius, self.precision)
+            .into_iter()
+            .map(|cell| format!("{}{}", SHARD_PREFIX, cell))
+            .collect()
+    }
+
+    pub fn keys_for_box(
+        &self,
+        min_lon: f64,
+        min_lat: f64,
+        max_lon: f64,
+        max_lat: f64,
+    ) -> Vec<String> {
+        if !self.is_sharded() {
+            return vec![UNSHARDED_KEY.to_string()];
+        }
+        geohash::box_cells(min_lon, min_lat, max_lon, max_lat, self.precision)
+            .into_iter()
+            .map(|cell| format!("{}{}", SHARD_PREFIX, cell))
+            .collect()
+    }
+}
+
+// A node in GEO_SHARD_NODES is `<primary uri>` or `<primary uri>|<replica uri>|...`. Only lbs
+// reads from the replicas, writers just need the primary
+pub fn split_node_spec(spec: &str) -> (String, Vec<String>) {
+    let mut uris = spec.split('|').map(|uri| uri.trim().to_string());
+    let primary = uris.next().unwrap_or_default();
+    (primary, uris.filter(|uri| !uri.is_empty()).collect())
+}
+
+// Stable across builds and platforms, unlike the std hasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsharded_map_has_one_key() {
+        let map = ShardMap::new(0, 3);
+        assert!(!map.is_sharded());
+        assert_eq!(map.key_for(13.4, 52.5), UNSHARDED_KEY);
+        assert_eq!(map.keys_for_radius(13.4, 52.5, 1000.0), [UNSHARDED_KEY]);
+        assert_eq!(
+            map.keys_for_box(-180.0, -90.0, 180.0, 90.0),
+            [UNSHARDED_KEY]
+        );
+    }
+
+    #[test]
+    fn keys_are_geohash_prefixes() {
+        let map = ShardMap::new(2, 1);
+        assert_eq!(map.key_for(13.4, 52.5), "geo:u3");
+        assert_eq!(ShardMap::new(9, 1).precision, MAX_PRECISION);
+        assert_eq!(ShardMap::new(1, 0).nodes, 1);
+    }
+
+    #[test]
+    fn searches_near_a_border_ask_both_shards() {
+        let map = ShardMap::new(1, 1);
+        // 0° longitude splits "u" from "g"
+        let keys = map.keys_for_radius(0.001, 50.0, 1000.0);
+        assert!(keys.contains(&"geo:u".to_string()));
+        assert!(keys.contains(&"geo:g".to_string()));
+        assert_eq!(map.keys_for_radius(13.4, 52.5, 1000.0), ["geo:u"]);
+    }
+
+    #[test]
+    fn node_for_a_key_is_stable_and_spread() {
+        let map = ShardMap::new(2, 4);
+        // pinned, a different hash would move every shard to another node
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        let key = map.key_for(13.4, 52.5);
+        assert_eq!(map.node_for(&key), map.node_for(&key));
+        assert_eq!(map.node_for(&key), (fnv1a(key.as_bytes()) % 4) as usize);
+
+        let mut used = [0; 4];
+        for cell in geohash::box_cells(-180.0, -90.0, 180.0, 90.0, 2) {
+            used[map.node_for(&format!("{}{}", SHARD_PREFIX, cell))] += 1;
+        }
+        assert!(used.iter().all(|count| *count > 150), "{:?}", used);
+    }
+
+    #[test]
+    fn node_specs() {
+        assert_eq!(
+            split_node_spec("redis://a:6379"),
+            ("redis://a:6379".to_string(), vec![])
+        );
+        assert_eq!(
+            split_node_spec("redis://a | redis://b ||redis://c"),
+            (
+                "redis://a".to_string(),
+                vec!["redis://b".to_string(), "redis://c".to_string()]
+            )
+        );
+    }
+}
@@ -0,0 +1,15 @@
+use std::sync::OnceLock;
+use tzf_rs::DefaultFinder;
+
+// Timezone polygons are bundled into the binary by tzf-rs, so no lookups go over the network.
+// Building the finder takes a moment, it's done once on first use
+static FINDER: OnceLock<DefaultFinder> = OnceLock::new();
+
+pub fn find_timezone(lon: f64, lat: f64) -> Option<String> {
+    let finder = FINDER.get_or_init(DefaultFinder::new);
+    let name = finder.get_tz_name(lon, lat);
+    if name.is_empty() {
+        return None;
+    }
+    Some(name.to_string())
+}
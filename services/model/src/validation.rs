@@ -0,0 +1,483 @@
+use crate::business::BusinessData;
+use crate::hours::OpeningHours;
+use chrono_tz::Tz;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+// Redis GEO only takes latitudes in the range of the web mercator projection
+pub const MIN_LAT: f64 = -85.05112878;
+pub const MAX_LAT: f64 = 85.05112878;
+pub const MAX_STARS: u64 = 5;
+
+// Lengths in characters, of the value with surrounding whitespace trimmed
+const NAME_LENGTH: (usize, usize) = (1, 100);
+const TYPE_LENGTH: (usize, usize) = (1, 50);
+const ZIP_CODE_LENGTH: (usize, usize) = (1, 12);
+const DESCRIPTION_LENGTH: (usize, usize) = (0, 2000);
+const EMAIL_LENGTH: (usize, usize) = (3, 254);
+const PHONE_LENGTH: (usize, usize) = (1, 30);
+const MIN_PHONE_DIGITS: usize = 7;
+// Fields a business didn't always have
+const DETAIL_FIELDS: [&str; 4] = ["zipCode", "description", "email", "phone"];
+
+// One problem with one field of a business, `field` is the json name
+#[derive(Clone, Debug, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: impl Into<String>) -> FieldError {
+        FieldError {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+// A business as a client writes it, checked field by field. Every problem is reported, not only
+// the first, so that a form can mark all of them at once. `id` and `schemaVersion` are set by the
+// api, only their type is checked. Unknown fields are ignored
+pub fn parse_business(value: &Value) -> Result<BusinessData, Vec<FieldError>> {
+    parse(value, &[])
+}
+
+// A PATCH merged into the stored business. Records written before the detail fields were
+// required can have them empty, that is only an error when the PATCH sets them
+pub fn parse_business_patch(
+    merged: &Value,
+    changes: &Map<String, Value>,
+) -> Result<BusinessData, Vec<FieldError>> {
+    let unchanged: Vec<&str> = DETAIL_FIELDS
+        .into_iter()
+        .filter(|field| !changes.contains_key(*field))
+        .collect();
+    parse(merged, &unchanged)
+}
+
+// `may_be_empty` are text fields that are left out of the checks when empty
+fn parse(value: &Value, may_be_empty: &[&str]) -> Result<BusinessData, Vec<FieldError>> {
+    let Some(record) = value.as_object() else {
+        return Err(vec![FieldError::new("", "business must be a json object")]);
+    };
+    let mut errors = vec![];
+
+    text(record, "name", NAME_LENGTH, may_be_empty, &mut errors);
+    text(record, "type", TYPE_LENGTH, may_be_empty, &mut errors);
+    if let Some(zip_code) = text(
+        record,
+        "zipCode",
+        ZIP_CODE_LENGTH,
+        may_be_empty,
+        &mut errors,
+    ) {
+        let valid = zip_code
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-');
+        if !valid {
+            errors.push(FieldError::new(
+                "zipCode",
+                "may only contain letters, digits, spaces and dashes",
+            ));
+        }
+    }
+    text(
+        record,
+        "description",
+        DESCRIPTION_LENGTH,
+        may_be_empty,
+        &mut errors,
+    );
+    if let Some(email) = text(record, "email", EMAIL_LENGTH, may_be_empty, &mut errors) {
+        if !is_email(email) {
+            errors.push(FieldError::new("email", "is not a valid email address"));
+        }
+    }
+    if let Some(phone) = text(record, "phone", PHONE_LENGTH, may_be_empty, &mut errors) {
+        let allowed = phone
+            .chars()
+            .all(|c| c.is_ascii_digit() || " +-().x".contains(c));
+        let digits = phone.chars().filter(|c| c.is_ascii_digit()).count();
+        if !allowed || digits < MIN_PHONE_DIGITS {
+            errors.push(FieldError::new(
+                "phone",
+                format!(
+                    "must have at least {} digits and only spaces, +, -, (, ), . or x besides them",
+                    MIN_PHONE_DIGITS
+                ),
+            ));
+        }
+    }
+
+    whole_number(record, "stars", 0, MAX_STARS, true, &mut errors);
+    whole_number(record, "averagePrice", 0, u8::MAX as u64, true, &mut errors);
+    // whole hours, 24 closes at midnight
+    whole_number(record, "opensAt", 0, 24, true, &mut errors);
+    whole_number(record, "closesAt", 0, 24, true, &mut errors);
+    whole_number(record, "id", 0, u64::MAX, false, &mut errors);
+    whole_number(
+        record,
+        "schemaVersion",
+        0,
+        u32::MAX as u64,
+        false,
+        &mut errors,
+    );
+    coordinate(record, "lat", MIN_LAT, MAX_LAT, &mut errors);
+    coordinate(record, "lon", -180.0, 180.0, &mut errors);
+    hours(record, &mut errors);
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    // the lengths were checked on the trimmed values, and that is what gets stored
+    let mut record = record.clone();
+    for field in ["name", "type", "zipCode", "description", "email", "phone"] {
+        if let Some(Value::String(value)) = record.get_mut(field) {
+            *value = value.trim().to_string();
+        }
+    }
+    serde_json::from_value(Value::Object(record)).map_err(|e| {
+        vec![FieldError::new(
+            "",
+            format!("is not a valid business: {}", e),
+        )]
+    })
+}
+
+fn text<'a>(
+    record: &'a Map<String, Value>,
+    field: &str,
+    (min, max): (usize, usize),
+    may_be_empty: &[&str],
+    errors: &mut Vec<FieldError>,
+) -> Option<&'a str> {
+    let value = match record.get(field) {
+        None | Some(Value::Null) if may_be_empty.contains(&field) => return None,
+        Some(Value::String(value)) if value.trim().is_empty() && may_be_empty.contains(&field) => {
+            return None
+        }
+        None | Some(Value::Null) => {
+            errors.push(FieldError::new(field, "is required"));
+            return None;
+        }
+        Some(Value::String(value)) => value.trim(),
+        Some(_) => {
+            errors.push(FieldError::new(field, "must be a string"));
+            return None;
+        }
+    };
+    let length = value.chars().count();
+    if length < min || length > max {
+        let message = if min == 0 {
+            format!("must be at most {} characters long", max)
+        } else {
+            format!("must be {} to {} characters long", min, max)
+        };
+        errors.push(FieldError::new(field, message));
+        return None;
+    }
+    Some(value)
+}
+
+fn whole_number(
+    record: &Map<String, Value>,
+    field: &str,
+    min: u64,
+    max: u64,
+    required: bool,
+    errors: &mut Vec<FieldError>,
+) {
+    match record.get(field) {
+        None | Some(Value::Null) if required => errors.push(FieldError::new(field, "is required")),
+        None | Some(Value::Null) => {}
+        Some(value) => match value.as_u64() {
+            Some(n) if n >= min && n <= max => {}
+            _ => errors.push(FieldError::new(
+                field,
+                format!("must be a whole number from {} to {}", min, max),
+            )),
+        },
+    }
+}
+
+fn coordinate(
+    record: &Map<String, Value>,
+    field: &str,
+    min: f64,
+    max: f64,
+    errors: &mut Vec<FieldError>,
+) {
+    match record.get(field).and_then(|value| value.as_f64()) {
+        Some(n) if (min..=max).contains(&n) => {}
+        Some(_) => errors.push(FieldError::new(
+            field,
+            format!("must be between {} and {}", min, max),
+        )),
+        None => errors.push(FieldError::new(field, "must be a number")),
+    }
+}
+
+// Optional, derived from opensAt/closesAt when missing
+fn hours(record: &Map<String, Value>, errors: &mut Vec<FieldError>) {
+    let value = match record.get("hours") {
+        None | Some(Value::Null) => return,
+        Some(value) => value,
+    };
+    let hours = match serde_json::from_value::<OpeningHours>(value.clone()) {
+        Ok(hours) => hours,
+        Err(e) => {
+            errors.push(FieldError::new("hours", e.to_string()));
+            return;
+        }
+    };
+    if let Some(timezone) = &hours.timezone {
+        if timezone.parse::<Tz>().is_err() {
+            errors.push(FieldError::new(
+                "hours.timezone",
+                "must be an IANA time zone, e.g. Europe/Berlin",
+            ));
+        }
+    }
+    let mut dates = hours.exceptions.iter().map(|e| e.date).collect::<Vec<_>>();
+    dates.sort();
+    if dates.windows(2).any(|pair| pair[0] == pair[1]) {
+        errors.push(FieldError::new(
+            "hours.exceptions",
+            "may have only one exception per date",
+        ));
+    }
+}
+
+// Only the shape: something before the @, and a domain with a dot that doesn't start or end it
+fn is_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && !domain.contains('@')
+        && !email.chars().any(char::is_whitespace)
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn business() -> Value {
+        json!({
+            "name": "Café Nord",
+            "type": "cafe",
+            "zipCode": "10115",
+            "description": "Coffee and cake",
+            "email": "nord@example.com",
+            "phone": "+49 (30) 123-4567",
+            "stars": 4,
+            "averagePrice": 12,
+            "opensAt": 9,
+            "closesAt": 17,
+            "lat": 52.53,
+            "lon": 13.38
+        })
+    }
+
+    fn with(field: &str, value: Value) -> Value {
+        let mut business = business();
+        business[field] = value;
+        business
+    }
+
+    fn without(field: &str) -> Value {
+        let mut business = business();
+        business.as_object_mut().unwrap().remove(field);
+        business
+    }
+
+    // names of the fields that failed
+    fn failed(value: &Value) -> Vec<String> {
+        match parse_business(value) {
+            Ok(_) => vec![],
+            Err(errors) => errors.into_iter().map(|e| e.field).collect(),
+        }
+    }
+
+    #[test]
+    fn valid_business_is_parsed() {
+        let data = parse_business(&business()).unwrap();
+        assert_eq!(data.name, "Café Nord");
+        assert_eq!(data.stars, 4);
+        assert_eq!(data.hours, None);
+    }
+
+    #[test]
+    fn text_is_stored_trimmed() {
+        let mut business = business();
+        business["name"] = json!("  Café Nord \n");
+        business["email"] = json!(" nord@example.com ");
+        business["description"] = json!("   ");
+        let data = parse_business(&business).unwrap();
+        assert_eq!(data.name, "Café Nord");
+        assert_eq!(data.email, "nord@example.com");
+        assert_eq!(data.description, "");
+    }
+
+    #[test]
+    fn stars_from_0_to_5() {
+        for stars in [0, 5] {
+            assert!(failed(&with("stars", json!(stars))).is_empty());
+        }
+        for stars in [json!(6), json!(-1), json!(2.5), json!("4")] {
+            assert_eq!(failed(&with("stars", stars)), ["stars"]);
+        }
+        assert_eq!(failed(&without("stars")), ["stars"]);
+    }
+
+    #[test]
+    fn hours_from_0_to_24() {
+        for field in ["opensAt", "closesAt"] {
+            assert!(failed(&with(field, json!(0))).is_empty());
+            assert!(failed(&with(field, json!(24))).is_empty());
+            assert_eq!(failed(&with(field, json!(25))), [field]);
+            assert_eq!(failed(&with(field, json!(-1))), [field]);
+        }
+    }
+
+    #[test]
+    fn coordinates_in_range() {
+        assert!(failed(&with("lat", json!(MAX_LAT))).is_empty());
+        assert!(failed(&with("lon", json!(-180))).is_empty());
+        for lat in [json!(85.1), json!(-90), json!("52.5"), Value::Null] {
+            assert_eq!(failed(&with("lat", lat)), ["lat"]);
+        }
+        for lon in [json!(180.5), json!(-181), json!(true)] {
+            assert_eq!(failed(&with("lon", lon)), ["lon"]);
+        }
+    }
+
+    #[test]
+    fn email_format() {
+        for email in ["a@b.de", "first.last+tag@mail.example.com"] {
+            assert!(failed(&with("email", json!(email))).is_empty(), "{}", email);
+        }
+        for email in [
+            "nord",
+            "@example.com",
+            "a@b",
+            "a@@b.de",
+            "a@.de",
+            "a@b.",
+            "a b@c.de",
+        ] {
+            assert_eq!(failed(&with("email", json!(email))), ["email"], "{}", email);
+        }
+    }
+
+    #[test]
+    fn phone_format() {
+        for phone in ["0301234567", "+49 30 123 45 67", "(030) 123-4567 x12"] {
+            assert!(failed(&with("phone", json!(phone))).is_empty(), "{}", phone);
+        }
+        for phone in ["123456", "+49 30 CALL NOW", "030/1234567"] {
+            assert_eq!(failed(&with("phone", json!(phone))), ["phone"], "{}", phone);
+        }
+    }
+
+    #[test]
+    fn text_lengths() {
+        assert_eq!(failed(&with("name", json!(""))), ["name"]);
+        assert_eq!(failed(&with("name", json!("  "))), ["name"]);
+        assert!(failed(&with("name", json!("n".repeat(100)))).is_empty());
+        assert_eq!(failed(&with("name", json!("n".repeat(101)))), ["name"]);
+        // characters, not bytes
+        assert!(failed(&with("type", json!("é".repeat(50)))).is_empty());
+        assert_eq!(failed(&with("type", json!("t".repeat(51)))), ["type"]);
+        assert!(failed(&with("description", json!(""))).is_empty());
+        assert_eq!(
+            failed(&with("description", json!("d".repeat(2001)))),
+            ["description"]
+        );
+        assert_eq!(failed(&with("zipCode", json!("10115!"))), ["zipCode"]);
+        assert_eq!(failed(&with("name", json!(42))), ["name"]);
+    }
+
+    #[test]
+    fn opening_hours() {
+        let hours = json!({"timezone": "Europe/Berlin", "weekly": {"mon": [{"opens": "09:00", "closes": "17:00"}]}});
+        assert!(failed(&with("hours", hours)).is_empty());
+        let hours = json!({"timezone": "Mars/Olympus"});
+        assert_eq!(failed(&with("hours", hours)), ["hours.timezone"]);
+        let hours = json!({"weekly": {"mon": [{"opens": "25:00", "closes": "26:00"}]}});
+        assert_eq!(failed(&with("hours", hours)), ["hours"]);
+        let hours = json!({"exceptions": [{"date": "2024-12-25"}, {"date": "2024-12-25"}]});
+        assert_eq!(failed(&with("hours", hours)), ["hours.exceptions"]);
+    }
+
+    #[test]
+    fn patch_leaves_missing_details_alone() {
+        // stored before the details were required
+        let mut stored = business();
+        for field in ["zipCode", "email", "phone"] {
+            stored.as_object_mut().unwrap().remove(field);
+        }
+        stored["description"] = json!("");
+        let mut merged = stored.clone();
+        merged["stars"] = json!(5);
+        let changes = json!({"stars": 5});
+        let data = parse_business_patch(&merged, changes.as_object().unwrap()).unwrap();
+        assert_eq!(data.stars, 5);
+        assert_eq!(data.email, "");
+        assert_eq!(failed(&merged), ["zipCode", "email", "phone"]);
+
+        // unless the PATCH sets them
+        let changes = json!({"email": "", "phone": "123"});
+        let mut merged = stored.clone();
+        merged["email"] = json!("");
+        merged["phone"] = json!("123");
+        let errors = parse_business_patch(&merged, changes.as_object().unwrap()).unwrap_err();
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, ["email", "phone"]);
+
+        // stored details that are there are checked as always
+        let merged = with("zipCode", json!("10115!"));
+        let changes = json!({"stars": 4});
+        assert!(parse_business_patch(&merged, changes.as_object().unwrap()).is_err());
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let mut business = business();
+        business["name"] = json!("");
+        business["stars"] = json!(9);
+        business["lat"] = json!(100);
+        business["email"] = json!("nord");
+        business.as_object_mut().unwrap().remove("phone");
+        let errors = parse_business(&business).unwrap_err();
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, ["name", "email", "phone", "stars", "lat"]);
+        assert_eq!(errors[2].message, "is required");
+
+        let body = serde_json::to_value(&errors[0]).unwrap();
+        assert_eq!(
+            body,
+            json!({"field": "name", "message": "must be 1 to 100 characters long"})
+        );
+    }
+
+    #[test]
+    fn only_objects_are_businesses() {
+        for value in [json!([]), json!("business"), Value::Null] {
+            assert_eq!(failed(&value), [""]);
+        }
+    }
+
+    #[test]
+    fn id_and_version_are_only_type_checked() {
+        assert!(failed(&with("id", json!(123456789))).is_empty());
+        assert_eq!(failed(&with("id", json!("abc"))), ["id"]);
+        assert_eq!(failed(&with("schemaVersion", json!(-1))), ["schemaVersion"]);
+    }
+}